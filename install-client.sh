@@ -24,10 +24,7 @@ install_dirs()
 
 install_conf()
 {
-    if [ -e /opt/letmein/etc/letmein.conf ]; then
-        do_chown root:root /opt/letmein/etc/letmein.conf
-        do_chmod 0644 /opt/letmein/etc/letmein.conf
-    else
+    if ! [ -e /opt/letmein/etc/letmein.conf ]; then
         do_install \
             -o root -g root -m 0644 \
             "$basedir/letmein/letmein.conf" \
@@ -43,6 +43,16 @@ install_conf()
     fi
 }
 
+install_fwkey()
+{
+    if ! [ -e /opt/letmein/etc/letmeinfwd.key ]; then
+        ( umask 077 && od -An -tx1 -N32 /dev/urandom | tr -d ' \n' > /opt/letmein/etc/letmeinfwd.key ) ||
+            die "Failed to generate /opt/letmein/etc/letmeinfwd.key"
+    fi
+    do_chown letmeind:root /opt/letmein/etc/letmeinfwd.key
+    do_chmod 0400 /opt/letmein/etc/letmeinfwd.key
+}
+
 install_letmeinfwd()
 {
     do_install \
@@ -106,6 +116,7 @@ entry_checks
 stop_services
 install_dirs
 install_conf
+install_fwkey
 install_letmeinfwd
 install_letmeind
 start_services
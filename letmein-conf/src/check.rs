@@ -0,0 +1,350 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Consistency checks of a loaded server configuration.
+
+use crate::{Config, ConfigVariant, Ini, IniPos, Resource};
+use anyhow::{self as ah, Context as _};
+use letmein_proto::{ResourceId, UserId};
+use std::{net::IpAddr, path::Path, time::SystemTime};
+
+/// Severity of a [ConfigProblem].
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ProblemSeverity {
+    /// The configuration works, but probably not as intended.
+    Warning,
+    /// The configuration is broken.
+    Error,
+}
+
+impl std::fmt::Display for ProblemSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Warning => write!(f, "WARNING"),
+            Self::Error => write!(f, "ERROR"),
+        }
+    }
+}
+
+/// A problem found by [Config::check].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ConfigProblem {
+    pub severity: ProblemSeverity,
+    /// The section that contains the problem.
+    pub section: String,
+    /// The position of the problem, if known.
+    pub pos: Option<IniPos>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if let Some(pos) = &self.pos {
+            write!(f, "{pos}: ")?;
+        }
+        write!(f, "{}: [{}] {}", self.severity, self.section, self.message)
+    }
+}
+
+/// Find the position of the `[RESOURCES]` option of resource `id`.
+fn resource_pos(ini: &Ini, id: ResourceId) -> Option<IniPos> {
+    ini.options_iter("RESOURCES")?
+        .find(|(name, _)| name.parse::<ResourceId>().ok() == Some(id))
+        .and_then(|(name, _)| ini.option_pos("RESOURCES", name))
+        .cloned()
+}
+
+/// Find the position of the `[KEYS]` option that defines the key `name`.
+fn key_pos(ini: &Ini, name: &str) -> Option<IniPos> {
+    let canonical = |option: &str| -> Option<String> {
+        let (id, label) = match option.split_once('.') {
+            Some((id, label)) => (id, Some(label.trim())),
+            None => (option, None),
+        };
+        let id: UserId = id.parse().ok()?;
+        Some(match label {
+            Some(label) => format!("{id}.{label}"),
+            None => id.to_string(),
+        })
+    };
+    ini.options_iter("KEYS")?
+        .find(|(option, _)| canonical(option).as_deref() == Some(name))
+        .and_then(|(option, _)| ini.option_pos("KEYS", option))
+        .cloned()
+}
+
+/// Find the position of an option in `section`, falling back to the section position.
+fn option_pos(ini: &Ini, section: &str, option: &str) -> Option<IniPos> {
+    ini.option_pos(section, option)
+        .or_else(|| ini.section_pos(section))
+        .cloned()
+}
+
+impl Config {
+    /// Load the server configuration from `path` and run [Config::check] on it.
+    pub fn check_file(path: &Path) -> ah::Result<Vec<ConfigProblem>> {
+        let ini = Ini::new_from_file(path)
+            .with_context(|| format!("Failed to load configuration {path:?}"))?;
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_file_ini(&ini)?;
+        conf.path = Some(path.to_path_buf());
+        Ok(conf.check(&ini))
+    }
+
+    /// Run [Config::check_file] and print all problems.
+    ///
+    /// This implements the `--check-config` option of the server daemons.
+    /// Returns the exit code of the process:
+    /// 0, if no errors were found,
+    /// 1, if errors were found
+    /// and 2, if the file could not be checked.
+    pub fn check_file_print(path: &Path) -> i32 {
+        let problems = match Self::check_file(path) {
+            Ok(problems) => problems,
+            Err(e) => {
+                eprintln!("Configuration check failed: {e:#}");
+                return 2;
+            }
+        };
+        for problem in &problems {
+            println!("{problem}");
+        }
+        if problems
+            .iter()
+            .any(|problem| problem.severity == ProblemSeverity::Error)
+        {
+            return 1;
+        }
+        println!("{}: Configuration OK.", path.display());
+        0
+    }
+
+    /// Run consistency checks across the options of this server configuration.
+    ///
+    /// `ini` is the parsed file this configuration has been loaded from.
+    /// It is used to find the positions of the problems.
+    ///
+    /// The result also contains the [Config::warnings] of loading the configuration.
+    /// The problems are sorted by file and line number.
+    pub fn check(&self, ini: &Ini) -> Vec<ConfigProblem> {
+        self.check_at(ini, SystemTime::now())
+    }
+
+    fn check_at(&self, ini: &Ini, now: SystemTime) -> Vec<ConfigProblem> {
+        let mut problems = self.warnings.clone();
+        let mut problem = |severity, section: &str, pos, message| {
+            problems.push(ConfigProblem {
+                severity,
+                section: section.to_string(),
+                pos,
+                message,
+            });
+        };
+
+        for (_, keys) in self.keys.iter() {
+            for key in keys {
+                let expired = key.validity().not_after.map(|t| t < now).unwrap_or(false);
+                if expired && !key.validity().disabled {
+                    problem(
+                        ProblemSeverity::Warning,
+                        "KEYS",
+                        key_pos(ini, key.name()),
+                        format!("Key {} has expired.", key.name()),
+                    );
+                }
+            }
+        }
+
+        let mut resources: Vec<(&ResourceId, &Resource)> = self.resources.iter().collect();
+        resources.sort_by_key(|(id, _)| u32::from(**id));
+
+        for (id, res) in &resources {
+            let Resource::Port { port, users, .. } = res;
+            let pos = resource_pos(ini, **id);
+
+            // The control port must not be managed by a resource.
+            if self.is_control_port(*port) {
+                problem(
+                    ProblemSeverity::Error,
+                    "RESOURCES",
+                    pos.clone(),
+                    format!("Resource {id} uses the letmein control port {port}."),
+                );
+            }
+
+            // All users of the resource must be able to authenticate.
+            let mut users: Vec<UserId> = users.clone();
+            users.sort_by_key(|user| u32::from(*user));
+            for user in users {
+                if !self.keys.contains_key(&user) {
+                    problem(
+                        ProblemSeverity::Error,
+                        "RESOURCES",
+                        pos.clone(),
+                        format!(
+                            "Resource {id} references user {user}, which has no key in [KEYS]."
+                        ),
+                    );
+                } else if self.valid_keys(user, now).is_empty() {
+                    problem(
+                        ProblemSeverity::Warning,
+                        "RESOURCES",
+                        pos.clone(),
+                        format!(
+                            "Resource {id} references user {user}, \
+                             which has no currently valid key in [KEYS]."
+                        ),
+                    );
+                }
+            }
+        }
+
+        // The unspecified IPv6 address accepts knocks from IPv4 and IPv6 peers.
+        let listens_v4 = self
+            .listeners()
+            .iter()
+            .any(|l| match l.addr.to_canonical() {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(addr) => addr.is_unspecified(),
+            });
+        let listens_v6 = self
+            .listeners()
+            .iter()
+            .any(|l| l.addr.to_canonical().is_ipv6());
+        match self.nft_family() {
+            "ip" if listens_v6 => problem(
+                ProblemSeverity::Warning,
+                "NFTABLES",
+                option_pos(ini, "NFTABLES", "family"),
+                "The nftables family 'ip' can only open ports for IPv4. \
+                 Knocks from IPv6 addresses will fail."
+                    .to_string(),
+            ),
+            "ip6" if listens_v4 => problem(
+                ProblemSeverity::Warning,
+                "NFTABLES",
+                option_pos(ini, "NFTABLES", "family"),
+                "The nftables family 'ip6' can only open ports for IPv6. \
+                 Knocks from IPv4 addresses will fail."
+                    .to_string(),
+            ),
+            _ => (),
+        }
+
+        if self.ban_firewall() && self.ban_threshold() == 0 {
+            problem(
+                ProblemSeverity::Warning,
+                "GENERAL",
+                option_pos(ini, "GENERAL", "ban-firewall"),
+                "'ban-firewall' has no effect, because 'ban-threshold' is 0.".to_string(),
+            );
+        }
+
+        problems.sort_by(|a, b| a.pos.cmp(&b.pos));
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(problem: &ConfigProblem) -> Option<usize> {
+        problem.pos.as_ref().map(|pos| pos.line())
+    }
+
+    fn check(content: &str) -> Vec<ConfigProblem> {
+        let mut ini = Ini::new();
+        ini.parse_str(content).unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        conf.check(&ini)
+    }
+
+    #[test]
+    fn test_check_ok() {
+        let problems = check(
+            "[GENERAL]\nport = 5800\n\
+             [KEYS]\n00000001 = 0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF\n\
+             [RESOURCES]\n00000001 = port: 22 / users: 00000001\n",
+        );
+        assert!(problems.is_empty());
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[KEYS]\n\
+             00000001 = 0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF \
+                 / not-after: 2025-01-01\n\
+             00000002 = 0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF \
+                 / disabled\n\
+             [RESOURCES]\n00000001 = port: 22 / users: 00000001, 00000002\n",
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        let now = crate::parse::parse_datetime("2025-06-01").unwrap();
+        let problems = conf.check_at(&ini, now);
+        assert_eq!(problems.len(), 3);
+        assert_eq!(line(&problems[0]), Some(2));
+        assert!(problems[0].message.contains("Key 00000001 has expired"));
+        assert_eq!(line(&problems[1]), Some(5));
+        assert!(problems[1]
+            .message
+            .contains("user 00000001, which has no currently valid"));
+        assert!(problems[2]
+            .message
+            .contains("user 00000002, which has no currently valid"));
+    }
+
+    #[test]
+    fn test_check_problems() {
+        let problems = check(
+            "[GENERAL]\nport = 5800\n\
+             [NFTABLES]\nfamily = ip\n\
+             [RESOURCES]\n\
+             00000001 = port: 5800\n\
+             00000002 = port: 22\n\
+             00000004 = port: 80 / users: 00000009\n",
+        );
+        assert_eq!(problems.len(), 3);
+        assert_eq!(problems[0].severity, ProblemSeverity::Warning);
+        assert_eq!(problems[0].section, "NFTABLES");
+        assert_eq!(line(&problems[0]), Some(4));
+        assert_eq!(problems[1].severity, ProblemSeverity::Error);
+        assert_eq!(line(&problems[1]), Some(6));
+        assert!(problems[1].message.contains("control port"));
+        assert_eq!(line(&problems[2]), Some(8));
+        assert_eq!(
+            problems[2].to_string(),
+            "line 8: ERROR: [RESOURCES] Resource 00000004 references user 00000009, \
+             which has no key in [KEYS]."
+        );
+    }
+
+    #[test]
+    fn test_check_family() {
+        let family = |listen: &str, family: &str| {
+            check(&format!(
+                "[GENERAL]\nlisten = {listen}\n[NFTABLES]\nfamily = {family}\n"
+            ))
+            .len()
+        };
+        assert_eq!(family("0.0.0.0:5800", "ip"), 0);
+        assert_eq!(family("[::1]:5800", "ip6"), 0);
+        assert_eq!(family("*:5800", "inet"), 0);
+        assert_eq!(family("*:5800", "ip"), 1);
+        assert_eq!(family("*:5800", "ip6"), 1);
+        assert_eq!(family("0.0.0.0:5800, [::1]:5800", "ip"), 1);
+        assert_eq!(family("[::ffff:127.0.0.1]:5800", "ip6"), 1);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
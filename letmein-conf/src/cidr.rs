@@ -0,0 +1,183 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Source address allow-lists.
+
+use anyhow::{self as ah, format_err as err, Context as _};
+use std::{net::IpAddr, str::FromStr};
+
+/// An IP network in CIDR notation, e.g. `192.168.1.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Build the network mask of a `prefix_len` bits long prefix.
+fn mask_v4(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len.min(32)))
+        .unwrap_or(0)
+}
+
+/// Build the network mask of a `prefix_len` bits long prefix.
+fn mask_v6(prefix_len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - u32::from(prefix_len.min(128)))
+        .unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = ah::Error;
+
+    /// Parse `ADDR/PREFIXLEN` or a single address `ADDR`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.trim(), Some(prefix_len.trim())),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Invalid IP address '{addr}'"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| err!("Invalid prefix length '{prefix_len}' in '{s}'"))?,
+            None => max_len,
+        };
+        let host_bits = match addr {
+            IpAddr::V4(v4) => u32::from(v4) & !mask_v4(prefix_len) != 0,
+            IpAddr::V6(v6) => u128::from(v6) & !mask_v6(prefix_len) != 0,
+        };
+        if host_bits {
+            return Err(err!("The network '{s}' has host bits set"));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Cidr {
+    /// Check whether `addr` is inside of this network.
+    ///
+    /// IPv4-mapped IPv6 addresses are compared as IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => addr,
+            },
+            IpAddr::V4(_) => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = mask_v4(self.prefix_len);
+                u32::from(addr) & mask == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = mask_v6(self.prefix_len);
+                u128::from(addr) & mask == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse a non-empty list of networks.
+pub fn parse_cidr_list<'a>(items: impl IntoIterator<Item = &'a str>) -> ah::Result<Vec<Cidr>> {
+    let list = items
+        .into_iter()
+        .map(|s| s.parse())
+        .collect::<ah::Result<Vec<Cidr>>>()?;
+    if list.is_empty() {
+        return Err(err!("Empty address list"));
+    }
+    Ok(list)
+}
+
+/// Check whether `addr` is allowed by the allow-list `list`.
+/// An empty list allows all addresses.
+pub fn list_contains(list: &[Cidr], addr: IpAddr) -> bool {
+    list.is_empty() || list.iter().any(|net| net.contains(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(net.addr, ip("10.0.0.0"));
+        assert_eq!(net.prefix_len, 8);
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        let net: Cidr = " 192.168.1.5 ".parse().unwrap();
+        assert_eq!(net.prefix_len, 32);
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert_eq!(net.addr, ip("2001:db8::"));
+        assert_eq!(net.prefix_len, 32);
+        let net: Cidr = "::1".parse().unwrap();
+        assert_eq!(net.prefix_len, 128);
+        let net: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(net.prefix_len, 0);
+
+        assert!("10.0.0.1/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+
+        assert_eq!(
+            parse_cidr_list(["10.0.0.0/8", "fd00::/8"]).unwrap().len(),
+            2
+        );
+        assert!(parse_cidr_list([]).is_err());
+        assert!(parse_cidr_list(["10.0.0.0/8", ""]).is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.0.0")));
+        assert!(net.contains(ip("10.1.255.255")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.1.2.3")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("192.0.2.1")));
+        assert!(!any.contains(ip("2001:db8::1")));
+
+        let list = parse_cidr_list(["192.0.2.1", "2001:db8::/32"]).unwrap();
+        assert!(list_contains(&list, ip("192.0.2.1")));
+        assert!(!list_contains(&list, ip("192.0.2.2")));
+        assert!(list_contains(&list, ip("2001:db8::2")));
+        assert!(list_contains(&[], ip("192.0.2.2")));
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -8,23 +8,79 @@
 
 use anyhow::{self as ah, format_err as err, Context as _};
 use std::{
-    collections::{hash_map, HashMap},
+    collections::{hash_map, HashMap, HashSet},
     io::Read as _,
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
+/// The name of the directive that includes other files.
+const INCLUDE: &str = "include";
+
+/// Maximum nesting depth of included files.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The files found for the wildcard and directory `include` directives.
+///
+/// The key is the include path with the directory of the including file prepended.
+pub type ResolvedIncludes = HashMap<PathBuf, Vec<PathBuf>>;
+
+/// State of the `include` directives while parsing.
+struct Includes<'a> {
+    /// If set, then wildcards and directories are not resolved again,
+    /// but the files from here are used.
+    pinned: Option<&'a ResolvedIncludes>,
+    /// The files found while parsing.
+    resolved: ResolvedIncludes,
+}
+
 /// An iterator over all option name-value tuples from a section.
 pub type IniSectionIter<'a> = hash_map::Iter<'a, String, String>;
 
+/// Source position of a section or an option.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct IniPos {
+    file: Option<Arc<Path>>,
+    line: usize,
+}
+
+impl IniPos {
+    /// Get the file the item was read from.
+    /// This is `None` for items parsed from a string.
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// Get the line number (starting at 1).
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl std::fmt::Display for IniPos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file.display(), self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
 /// All options from a `.ini` file section.
 struct IniSection {
     options: HashMap<String, String>,
+    /// Position of the section header.
+    pos: IniPos,
+    /// Positions of the options.
+    option_pos: HashMap<String, IniPos>,
 }
 
 impl IniSection {
-    fn new() -> Self {
+    fn new(pos: IniPos) -> Self {
         Self {
             options: HashMap::new(),
+            pos,
+            option_pos: HashMap::new(),
         }
     }
 
@@ -36,7 +92,7 @@ impl IniSection {
         &mut self.options
     }
 
-    fn iter(&self) -> IniSectionIter {
+    fn iter(&self) -> IniSectionIter<'_> {
         self.options.iter()
     }
 }
@@ -44,6 +100,7 @@ impl IniSection {
 /// Simple `.ini`-style file parser.
 pub struct Ini {
     sections: HashMap<String, IniSection>,
+    includes: ResolvedIncludes,
 }
 
 impl Ini {
@@ -51,6 +108,7 @@ impl Ini {
     pub fn new() -> Self {
         Self {
             sections: HashMap::new(),
+            includes: HashMap::new(),
         }
     }
 
@@ -66,14 +124,57 @@ impl Ini {
     /// Note that the parser state will be cleared before adding new items
     /// from the file.
     pub fn read_file(&mut self, path: &Path) -> ah::Result<()> {
+        self.read_file_includes(path, None)
+    }
+
+    /// Read the specified `.ini`-style file into an existing parser.
+    ///
+    /// Wildcard and directory includes are not resolved again.
+    /// They include the files from `pinned`, which were found by a previous
+    /// parser with [Ini::resolved_includes].
+    /// Therefore, no directory is read.
+    /// New wildcard and directory includes are an error.
+    pub fn read_file_pinned(&mut self, path: &Path, pinned: &ResolvedIncludes) -> ah::Result<()> {
+        self.read_file_includes(path, Some(pinned))
+    }
+
+    fn read_file_includes(
+        &mut self,
+        path: &Path,
+        pinned: Option<&ResolvedIncludes>,
+    ) -> ah::Result<()> {
+        let mut sections = HashMap::new();
+        let mut includes = Includes {
+            pinned,
+            resolved: HashMap::new(),
+        };
+        Self::parse_file(&mut sections, &mut includes, path, 0)?;
+        self.sections = sections;
+        self.includes = includes.resolved;
+        Ok(())
+    }
+
+    /// Get the files found for the wildcard and directory includes.
+    pub fn resolved_includes(&self) -> &ResolvedIncludes {
+        &self.includes
+    }
+
+    fn parse_file(
+        sections: &mut HashMap<String, IniSection>,
+        includes: &mut Includes<'_>,
+        path: &Path,
+        depth: usize,
+    ) -> ah::Result<()> {
         let mut file = std::fs::OpenOptions::new()
             .read(true)
             .open(path)
-            .context("Open configuration file")?;
+            .with_context(|| format!("{}: Open configuration file", path.display()))?;
         let mut buf = vec![];
         file.read_to_end(&mut buf)
-            .context("Read configuration file")?;
-        self.parse_bytes(&buf)
+            .with_context(|| format!("{}: Read configuration file", path.display()))?;
+        let content = std::str::from_utf8(&buf)
+            .with_context(|| format!("{}: UTF-8 conversion", path.display()))?;
+        Self::parse_into(sections, includes, content, Some(path.into()), depth)
     }
 
     /// Read the `.ini`-style formatted byte stream into an existing parser.
@@ -92,9 +193,40 @@ impl Ini {
     /// Note that the parser state will be cleared before adding new items
     /// from the string.
     pub fn parse_str(&mut self, content: &str) -> ah::Result<()> {
+        self.parse(content, None)
+    }
+
+    fn parse(&mut self, content: &str, file: Option<Arc<Path>>) -> ah::Result<()> {
         let mut sections = HashMap::new();
+        let mut includes = Includes {
+            pinned: None,
+            resolved: HashMap::new(),
+        };
+        Self::parse_into(&mut sections, &mut includes, content, file, 0)?;
+        self.sections = sections;
+        self.includes = includes.resolved;
+        Ok(())
+    }
+
+    /// Parse `content` and merge it into `sections`.
+    ///
+    /// A section may be split over several files,
+    /// but an option must only be defined in one of them.
+    fn parse_into(
+        sections: &mut HashMap<String, IniSection>,
+        includes: &mut Includes<'_>,
+        content: &str,
+        file: Option<Arc<Path>>,
+        depth: usize,
+    ) -> ah::Result<()> {
+        let mut file_sections = HashSet::new();
+        let mut include_lines = vec![];
         let mut in_section = None;
-        for line in content.lines() {
+        for (lineno, line) in content.lines().enumerate() {
+            let pos = IniPos {
+                file: file.clone(),
+                line: lineno + 1,
+            };
             let line = line.trim_start();
             if line.is_empty() {
                 continue; // This is an empty line.
@@ -110,16 +242,25 @@ impl Ini {
                     let end_chlen = ']'.len_utf8();
                     let sname = &line[begin_chlen..line.len() - end_chlen];
                     if sname.is_empty() {
-                        return Err(err!("Section name is empty: '{line}'"));
+                        return Err(err!("{pos}: Section name is empty: '{line}'"));
                     }
-                    if sections.contains_key(sname) {
-                        return Err(err!("Duplicate section name: '{line}'"));
+                    if !file_sections.insert(sname.to_string()) {
+                        return Err(err!("{pos}: Duplicate section name: '{line}'"));
                     }
-                    sections.insert(sname.to_string(), IniSection::new());
+                    sections
+                        .entry(sname.to_string())
+                        .or_insert_with(|| IniSection::new(pos));
                     in_section = Some(sname.to_string());
                     continue;
                 } else {
-                    return Err(err!("Invalid section name: '{line}'"));
+                    return Err(err!("{pos}: Invalid section name: '{line}'"));
+                }
+            }
+            // Include directive?
+            if let Some((name, pattern)) = line.split_once('=') {
+                if name.trim_end() == INCLUDE {
+                    include_lines.push((pos, pattern.trim().to_string()));
+                    continue;
                 }
             }
             // Are we inside of a section?
@@ -130,22 +271,40 @@ impl Ini {
                         // We have an option
                         let opt_name = line[..=(idx - chlen)].trim_end().to_string();
                         let opt_value = line[idx + chlen..].to_string();
-                        sections
-                            .get_mut(section)
-                            .unwrap()
-                            .options_mut()
-                            .insert(opt_name, opt_value);
+                        let sect = sections.get_mut(section).unwrap();
+                        if let Some(prev) = sect.option_pos.get(&opt_name) {
+                            if prev.file != pos.file {
+                                return Err(err!(
+                                    "{pos}: Duplicate option '{opt_name}' in section [{section}]. \
+                                     It is already defined at {prev}."
+                                ));
+                            }
+                        }
+                        sect.option_pos.insert(opt_name.clone(), pos);
+                        sect.options_mut().insert(opt_name, opt_value);
                     } else {
-                        return Err(err!("Option has no name before equal sign '=': '{line}'"));
+                        return Err(err!(
+                            "{pos}: Option has no name before equal sign '=': '{line}'"
+                        ));
                     }
                 } else {
-                    return Err(err!("Option has no equal sign '=': '{line}'"));
+                    return Err(err!("{pos}: Option has no equal sign '=': '{line}'"));
                 }
             } else {
-                return Err(err!("Option is not inside of a section: '{line}'"));
+                return Err(err!("{pos}: Option is not inside of a section: '{line}'"));
+            }
+        }
+        for (pos, pattern) in include_lines {
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(err!("{pos}: Includes are nested too deeply."));
+            }
+            let paths = include_paths(file.as_deref(), &pattern, includes)
+                .with_context(|| format!("{pos}: {INCLUDE} = '{pattern}'"))?;
+            for path in paths {
+                Self::parse_file(sections, includes, &path, depth + 1)
+                    .with_context(|| format!("{pos}: {INCLUDE} = '{pattern}'"))?;
             }
         }
-        self.sections = sections;
         Ok(())
     }
 
@@ -159,16 +318,240 @@ impl Ini {
         None
     }
 
+    /// Get the position of a section header.
+    pub fn section_pos(&self, section: &str) -> Option<&IniPos> {
+        self.sections.get(section).map(|s| &s.pos)
+    }
+
+    /// Get the position of an option from the given section.
+    pub fn option_pos(&self, section: &str, option: &str) -> Option<&IniPos> {
+        self.sections
+            .get(section)
+            .and_then(|s| s.option_pos.get(option))
+    }
+
+    /// Describe an option for error messages.
+    ///
+    /// The description contains the position and the option text.
+    /// The values of the `[KEYS]` section are secret and are not included.
+    pub fn describe(&self, section: &str, option: &str) -> String {
+        let mut desc = String::new();
+        if let Some(pos) = self.option_pos(section, option) {
+            desc.push_str(&format!("{pos}: "));
+        }
+        desc.push_str(&format!("[{section}] {option}"));
+        if section != "KEYS" {
+            if let Some(value) = self.get(section, option) {
+                desc.push_str(&format!(" = '{}'", value.trim()));
+            }
+        }
+        desc
+    }
+
+    /// Get an iterator over all section names.
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.keys().map(|s| s.as_str())
+    }
+
     /// Get an iterator over all option name-value tuples from a section.
-    pub fn options_iter(&self, section: &str) -> Option<IniSectionIter> {
+    pub fn options_iter(&self, section: &str) -> Option<IniSectionIter<'_>> {
         self.sections.get(section).map(|s| s.iter())
     }
 }
 
+/// Check whether `name` matches the shell-style `pattern` with `*` and `?` wildcards.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Get the sorted list of files in `dir` that match the file name `pattern`.
+///
+/// Hidden files are only matched, if the pattern starts with a dot.
+fn match_dir(dir: &Path, pattern: &str) -> ah::Result<Vec<PathBuf>> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir).context("Read include directory")? {
+        let entry = entry.context("Read include directory")?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if name.starts_with('.') && pattern.first() != Some(&'.') {
+            continue;
+        }
+        let name: Vec<char> = name.chars().collect();
+        if wildcard_match(&pattern, &name) && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Get the files to be included by an `include` directive.
+///
+/// Relative paths are relative to the directory of the including `file`.
+/// A directory includes all `*.conf` files in it.
+/// The file name may contain `*` and `?` wildcards.
+fn include_paths(
+    file: Option<&Path>,
+    pattern: &str,
+    includes: &mut Includes<'_>,
+) -> ah::Result<Vec<PathBuf>> {
+    if pattern.is_empty() {
+        return Err(err!("The include path is empty."));
+    }
+    let mut path = PathBuf::from(pattern);
+    if path.is_relative() {
+        if let Some(dir) = file.and_then(|f| f.parent()) {
+            path = dir.join(path);
+        }
+    }
+    let is_dir = path.is_dir();
+    let name = if is_dir {
+        "*.conf"
+    } else {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return Err(err!("Invalid include path."));
+        };
+        if !name.contains(['*', '?']) {
+            return Ok(vec![path]);
+        }
+        name
+    };
+    if let Some(pinned) = includes.pinned {
+        let Some(paths) = pinned.get(&path) else {
+            return Err(err!(
+                "New wildcard or directory includes require a restart."
+            ));
+        };
+        includes.resolved.insert(path, paths.clone());
+        return Ok(paths.clone());
+    }
+    let dir = if is_dir {
+        path.as_path()
+    } else {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if dir.to_string_lossy().contains(['*', '?']) {
+            return Err(err!("Wildcards are only supported in the file name."));
+        }
+        dir
+    };
+    let paths = match_dir(dir, name)?;
+    includes.resolved.insert(path, paths.clone());
+    Ok(paths)
+}
+
 impl Default for Ini {
     fn default() -> Self {
         Self::new()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pattern, &name)
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(matches("*.conf", "a.conf"));
+        assert!(matches("*.conf", ".conf"));
+        assert!(matches("user-?.conf", "user-1.conf"));
+        assert!(matches("a*b*c", "abc"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(!matches("*.conf", "a.conf~"));
+        assert!(!matches("user-?.conf", "user-12.conf"));
+        assert!(!matches("a*b*c", "acb"));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("letmein-ini-test-{}", std::process::id()));
+        let confd = dir.join("conf.d");
+        std::fs::create_dir_all(&confd).unwrap();
+        std::fs::write(
+            dir.join("main.conf"),
+            "include = conf.d\n[GENERAL]\ndebug = true\n[KEYS]\n1 = a\n",
+        )
+        .unwrap();
+        std::fs::write(confd.join("b.conf"), "[KEYS]\n2 = b\n[RESOURCES]\n3 = c\n").unwrap();
+        std::fs::write(confd.join("a.conf"), "[KEYS]\n4 = d\n").unwrap();
+        std::fs::write(confd.join("c.txt"), "garbage").unwrap();
+
+        let ini = Ini::new_from_file(&dir.join("main.conf")).unwrap();
+        assert_eq!(ini.get("GENERAL", "debug"), Some(" true"));
+        assert_eq!(ini.options_iter("KEYS").unwrap().count(), 3);
+        assert_eq!(ini.get("KEYS", "2"), Some(" b"));
+        assert_eq!(ini.get("RESOURCES", "3"), Some(" c"));
+        let pos = ini.option_pos("KEYS", "4").unwrap();
+        assert_eq!(pos.file(), Some(confd.join("a.conf").as_path()));
+        assert_eq!(pos.line(), 2);
+
+        // Pinned includes do not pick up new files.
+        assert_eq!(
+            ini.resolved_includes()[&confd],
+            [confd.join("a.conf"), confd.join("b.conf")]
+        );
+        std::fs::write(confd.join("e.conf"), "[KEYS]\n5 = f\n").unwrap();
+        let mut pinned = Ini::new();
+        pinned
+            .read_file_pinned(&dir.join("main.conf"), ini.resolved_includes())
+            .unwrap();
+        assert_eq!(pinned.options_iter("KEYS").unwrap().count(), 3);
+        assert_eq!(pinned.resolved_includes(), ini.resolved_includes());
+        std::fs::remove_file(confd.join("e.conf")).unwrap();
+
+        // New wildcard includes require a full load.
+        std::fs::write(
+            dir.join("main.conf"),
+            "include = conf.d\ninclude = conf.d/*.conf\n[GENERAL]\ndebug = true\n",
+        )
+        .unwrap();
+        let Err(e) = pinned.read_file_pinned(&dir.join("main.conf"), ini.resolved_includes())
+        else {
+            panic!("Loading must fail");
+        };
+        assert!(format!("{e:#}").contains("require a restart"));
+        std::fs::write(
+            dir.join("main.conf"),
+            "include = conf.d\n[GENERAL]\ndebug = true\n[KEYS]\n1 = a\n",
+        )
+        .unwrap();
+
+        // An option must only be defined once.
+        std::fs::write(confd.join("d.conf"), "[KEYS]\n1 = e\n").unwrap();
+        let Err(e) = Ini::new_from_file(&dir.join("main.conf")) else {
+            panic!("Loading must fail");
+        };
+        assert!(format!("{e:#}").contains("Duplicate option '1' in section [KEYS]"));
+
+        // Include loops are detected.
+        std::fs::write(confd.join("d.conf"), "include = d.conf\n").unwrap();
+        let Err(e) = Ini::new_from_file(&dir.join("main.conf")) else {
+            panic!("Loading must fail");
+        };
+        assert!(format!("{e:#}").contains("nested too deeply"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
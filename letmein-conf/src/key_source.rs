@@ -0,0 +1,536 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Key references in the `[KEYS]` section.
+
+use crate::{
+    cidr::{list_contains, parse_cidr_list, Cidr},
+    parse::{parse_datetime, parse_hex},
+    parse_items::{rfind_unquoted, split_unquoted, unquote},
+    ConfigVariant, Schedule,
+};
+use anyhow::{self as ah, format_err as err, Context as _};
+use letmein_proto::{Key, UserId};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+/// Overwrite secret bytes with zeros.
+pub fn zeroize(bytes: &mut [u8]) {
+    bytes.fill(0);
+    // Prevent the compiler from optimizing the write away.
+    std::hint::black_box(bytes);
+}
+
+/// Where the value of a `[KEYS]` option comes from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum KeySource<'a> {
+    /// The key is given as hex string.
+    Literal(&'a str),
+    /// `file:/path`: Read the hex string from a file.
+    File(PathBuf),
+    /// `env:VAR`: Read the hex string from an environment variable.
+    Env(&'a str),
+    /// `cmd:/usr/bin/pass show letmein`: Read the hex string from the output of a command.
+    Cmd(&'a str),
+    /// `credential:NAME`: Read the hex string from a systemd credential.
+    Credential(&'a str),
+}
+
+impl<'a> KeySource<'a> {
+    /// Parse a `[KEYS]` option value.
+    ///
+    /// Relative `file:` paths are relative to the directory `base`.
+    pub fn parse(value: &'a str, base: Option<&Path>) -> ah::Result<Self> {
+        let value = value.trim();
+        let Some((kind, reference)) = value.split_once(':') else {
+            return Ok(Self::Literal(value));
+        };
+        let reference = reference.trim();
+        if reference.is_empty() {
+            return Err(err!("The '{kind}:' key reference is empty."));
+        }
+        match kind.trim() {
+            "file" => {
+                let mut path = PathBuf::from(reference);
+                if path.is_relative() {
+                    if let Some(base) = base {
+                        path = base.join(path);
+                    }
+                }
+                Ok(Self::File(path))
+            }
+            "env" => Ok(Self::Env(reference)),
+            "cmd" => Ok(Self::Cmd(reference)),
+            "credential" => {
+                if reference.contains('/') {
+                    return Err(err!("The credential name must not contain '/'."));
+                }
+                Ok(Self::Credential(reference))
+            }
+            kind => Err(err!("Unknown key reference type '{kind}:'.")),
+        }
+    }
+
+    /// Get the hex string of the key.
+    fn read(&self, variant: ConfigVariant) -> ah::Result<String> {
+        match self {
+            Self::Literal(hex) => Ok(hex.to_string()),
+            Self::File(path) => {
+                std::fs::read_to_string(path).with_context(|| format!("Read key file {path:?}"))
+            }
+            Self::Env(var) => {
+                std::env::var(var).with_context(|| format!("Read environment variable {var}"))
+            }
+            Self::Cmd(cmd) => {
+                if variant != ConfigVariant::Client {
+                    return Err(err!("'cmd:' keys are only supported by the client."));
+                }
+                let mut args = cmd.split_whitespace();
+                let exe = args.next().context("The command is empty.")?;
+                let output = Command::new(exe)
+                    .args(args)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .with_context(|| format!("Run key command '{cmd}'"))?;
+                let mut stdout = output.stdout;
+                if !output.status.success() {
+                    zeroize(&mut stdout);
+                    return Err(err!("Key command '{cmd}' failed: {}", output.status));
+                }
+                match String::from_utf8(stdout) {
+                    Ok(hex) => Ok(hex),
+                    Err(e) => {
+                        zeroize(&mut e.into_bytes());
+                        Err(err!("Key command '{cmd}' printed invalid UTF-8."))
+                    }
+                }
+            }
+            Self::Credential(name) => {
+                let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+                    .context("CREDENTIALS_DIRECTORY is not set. Not running as systemd service?")?;
+                let path = Path::new(&dir).join(name);
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Read systemd credential {path:?}"))
+            }
+        }
+    }
+
+    /// Get the key.
+    pub fn resolve(&self, variant: ConfigVariant) -> ah::Result<Key> {
+        let hex = self.read(variant)?;
+        let key = parse_hex(&hex).context("Invalid key");
+        zeroize(&mut hex.into_bytes());
+        key
+    }
+}
+
+/// Validity options of a `[KEYS]` entry.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct KeyValidity {
+    /// The key is not valid before this time.
+    pub not_before: Option<SystemTime>,
+    /// The key is not valid after this time.
+    pub not_after: Option<SystemTime>,
+    /// The key is never valid.
+    pub disabled: bool,
+    /// The key can only be used to knock during these times.
+    ///
+    /// The schedule is not part of [KeyValidity::is_valid_at].
+    /// It is checked by the server after the authentication,
+    /// so that the client can be told why the knock was rejected.
+    pub schedule: Option<Schedule>,
+    /// The key can only be used from these source addresses.
+    /// Empty, if all addresses are allowed.
+    pub from: Vec<Cidr>,
+}
+
+impl KeyValidity {
+    /// Check whether the key is valid at the time `now`.
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        !self.disabled
+            && self.not_before.map(|t| now >= t).unwrap_or(true)
+            && self.not_after.map(|t| now <= t).unwrap_or(true)
+    }
+
+    /// Check whether the key can be used from the source address `addr`.
+    pub fn allows_addr(&self, addr: IpAddr) -> bool {
+        list_contains(&self.from, addr)
+    }
+}
+
+/// Split a `[KEYS]` option value into the key and its validity options.
+///
+/// The validity options are appended to the key with `/`:
+/// `KEY / not-before: 2025-01-01 / not-after: 2025-12-31 / schedule: Mon-Fri 08:00-18:00 / from: "10.0.0.0/8" / disabled`
+///
+/// The options are taken from the end of the value,
+/// so that `/` in `file:` paths and `cmd:` commands is kept.
+pub fn split_key_value(value: &str) -> ah::Result<(&str, KeyValidity)> {
+    let mut validity = KeyValidity::default();
+    let mut key = value;
+    while let Some(slash) = rfind_unquoted(key, '/') {
+        let (head, item) = (&key[..slash], &key[slash + 1..]);
+        // `disabled` must be separated by whitespace,
+        // so that it is not mistaken for the end of a path, e.g. `file:/etc/keys/disabled`.
+        let separated =
+            head.ends_with(char::is_whitespace) && item.starts_with(char::is_whitespace);
+        let item = item.trim();
+        if item == "disabled" && separated {
+            validity.disabled = true;
+        } else if let Some((name, value)) = item.split_once(':') {
+            let time = || parse_datetime(value).with_context(|| format!("Invalid '{name}' time"));
+            match name.trim() {
+                "schedule" if validity.schedule.is_none() => {
+                    validity.schedule = Some(value.parse().context("Invalid 'schedule'")?);
+                }
+                "from" if validity.from.is_empty() => {
+                    let from = split_unquoted(value, ',').context("Invalid 'from'")?;
+                    validity.from =
+                        parse_cidr_list(from.into_iter().map(unquote)).context("Invalid 'from'")?;
+                }
+                "not-before" if validity.not_before.is_none() => {
+                    validity.not_before = Some(time()?);
+                }
+                "not-after" if validity.not_after.is_none() => {
+                    validity.not_after = Some(time()?);
+                }
+                "not-before" | "not-after" | "schedule" | "from" => {
+                    return Err(err!("multiple '{}' values", name.trim()));
+                }
+                _ => break,
+            }
+        } else {
+            break;
+        }
+        key = &key[..slash];
+    }
+    if let (Some(not_before), Some(not_after)) = (validity.not_before, validity.not_after) {
+        if not_before > not_after {
+            return Err(err!("'not-before' is after 'not-after'"));
+        }
+    }
+    Ok((key.trim(), validity))
+}
+
+/// One key of a user.
+#[derive(Clone)]
+pub struct UserKey {
+    name: String,
+    key: Key,
+    validity: KeyValidity,
+}
+
+impl UserKey {
+    pub fn new(name: String, key: Key, validity: KeyValidity) -> Self {
+        Self {
+            name,
+            key,
+            validity,
+        }
+    }
+
+    /// Get the name of the `[KEYS]` option this key was defined by.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the key bytes.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Get the validity options.
+    pub fn validity(&self) -> &KeyValidity {
+        &self.validity
+    }
+
+    /// Check whether the key is valid at the time `now`.
+    pub fn is_valid_at(&self, now: SystemTime) -> bool {
+        self.validity.is_valid_at(now)
+    }
+
+    /// Check whether the key can be used from the source address `addr`.
+    pub fn allows_addr(&self, addr: IpAddr) -> bool {
+        self.validity.allows_addr(addr)
+    }
+}
+
+impl std::fmt::Debug for UserKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        // Do not print the secret key bytes.
+        f.debug_struct("UserKey")
+            .field("name", &self.name)
+            .field("validity", &self.validity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for UserKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.key);
+    }
+}
+
+/// A secret key outside of the `[KEYS]` section.
+///
+/// The key bytes are overwritten with zeros when this is dropped.
+#[derive(Clone)]
+pub struct SecretKey(Key);
+
+impl SecretKey {
+    pub fn new(key: Key) -> Self {
+        Self(key)
+    }
+
+    /// Get the key bytes.
+    pub fn key(&self) -> &Key {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        // Do not print the secret key bytes.
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// The keys from the `[KEYS]` section.
+///
+/// A user can have several keys, e.g. during a key rotation.
+/// The key bytes are overwritten with zeros when this is dropped.
+#[derive(Clone, Default)]
+pub struct Keys(HashMap<UserId, Vec<UserKey>>);
+
+impl Keys {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Get all keys of a user, including the invalid ones.
+    pub fn get(&self, id: &UserId) -> &[UserKey] {
+        self.0.get(id).map(|keys| &keys[..]).unwrap_or(&[])
+    }
+
+    pub fn contains_key(&self, id: &UserId) -> bool {
+        self.0.contains_key(id)
+    }
+
+    pub fn insert(&mut self, id: UserId, key: UserKey) {
+        self.0.entry(id).or_default().push(key);
+    }
+
+    /// Get an iterator over all users and their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&UserId, &[UserKey])> {
+        self.0.iter().map(|(id, keys)| (id, &keys[..]))
+    }
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        // Do not print the secret key bytes.
+        f.debug_set()
+            .entries(self.0.values().flatten().map(|key| key.name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF";
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            KeySource::parse(KEY, None).unwrap(),
+            KeySource::Literal(KEY)
+        );
+        assert_eq!(
+            KeySource::parse(" file: keys/a.key", Some(Path::new("/etc/letmein"))).unwrap(),
+            KeySource::File("/etc/letmein/keys/a.key".into())
+        );
+        assert_eq!(
+            KeySource::parse("file:/a.key", Some(Path::new("/etc"))).unwrap(),
+            KeySource::File("/a.key".into())
+        );
+        assert_eq!(
+            KeySource::parse("env:LETMEIN_KEY", None).unwrap(),
+            KeySource::Env("LETMEIN_KEY")
+        );
+        assert_eq!(
+            KeySource::parse("cmd:/usr/bin/pass show letmein", None).unwrap(),
+            KeySource::Cmd("/usr/bin/pass show letmein")
+        );
+        assert_eq!(
+            KeySource::parse("credential:letmein-key", None).unwrap(),
+            KeySource::Credential("letmein-key")
+        );
+        assert!(KeySource::parse("file:", None).is_err());
+        assert!(KeySource::parse("foo:bar", None).is_err());
+        assert!(KeySource::parse("credential:../key", None).is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let key = KeySource::Literal(KEY)
+            .resolve(ConfigVariant::Server)
+            .unwrap();
+        assert_eq!(key[0], 0x01);
+        assert_eq!(key[31], 0xEF);
+
+        let key = KeySource::Cmd(&format!("/bin/echo {KEY}"))
+            .resolve(ConfigVariant::Client)
+            .unwrap();
+        assert_eq!(key[0], 0x01);
+        assert!(KeySource::Cmd("/bin/echo 00")
+            .resolve(ConfigVariant::Server)
+            .is_err());
+        assert!(KeySource::Cmd("/bin/false")
+            .resolve(ConfigVariant::Client)
+            .is_err());
+
+        assert!(KeySource::Env("LETMEIN_TEST_KEY_DOES_NOT_EXIST")
+            .resolve(ConfigVariant::Server)
+            .is_err());
+    }
+
+    #[test]
+    fn test_split_key_value() {
+        let (key, validity) = split_key_value(KEY).unwrap();
+        assert_eq!(key, KEY);
+        assert_eq!(validity, KeyValidity::default());
+
+        let (key, validity) =
+            split_key_value("file:/etc/a/b.key / not-before: 2025-01-01 / disabled").unwrap();
+        assert_eq!(key, "file:/etc/a/b.key");
+        assert!(validity.disabled);
+        assert_eq!(
+            validity.not_before,
+            Some(parse_datetime("2025-01-01").unwrap())
+        );
+        assert_eq!(validity.not_after, None);
+
+        let (key, _) = split_key_value("file:/etc/a/b.key").unwrap();
+        assert_eq!(key, "file:/etc/a/b.key");
+
+        let (key, validity) = split_key_value("file:/etc/keys/disabled").unwrap();
+        assert_eq!(key, "file:/etc/keys/disabled");
+        assert!(!validity.disabled);
+        let (key, validity) = split_key_value("file:/etc/keys/disabled / disabled").unwrap();
+        assert_eq!(key, "file:/etc/keys/disabled");
+        assert!(validity.disabled);
+        let (key, validity) = split_key_value("X /disabled").unwrap();
+        assert_eq!(key, "X /disabled");
+        assert!(!validity.disabled);
+
+        assert!(split_key_value("X / not-before: 2025-02-30").is_err());
+        assert!(split_key_value("X / not-before: 2026-01-01 / not-after: 2025-01-01").is_err());
+        assert!(split_key_value("X / not-after: 2025-01-01 / not-after: 2026-01-01").is_err());
+
+        let (key, validity) =
+            split_key_value("X / schedule: Mon-Fri 08:00-18:00, Sat 10:00-12:00").unwrap();
+        assert_eq!(key, "X");
+        assert_eq!(
+            validity.schedule,
+            Some("Mon-Fri 08:00-18:00, Sat 10:00-12:00".parse().unwrap())
+        );
+        assert!(split_key_value("X / schedule: Mon-Fri").is_err());
+        assert!(split_key_value("X / schedule: 08:00-09:00 / schedule: 10:00-11:00").is_err());
+
+        let (key, validity) =
+            split_key_value(r#"file:/etc/2/k / from: "10.0.0.0/8", "2001:db8::/32" / disabled"#)
+                .unwrap();
+        assert_eq!(key, "file:/etc/2/k");
+        assert!(validity.disabled);
+        assert_eq!(
+            validity.from,
+            [
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap()
+            ]
+        );
+        assert!(validity.allows_addr("10.1.2.3".parse().unwrap()));
+        assert!(!validity.allows_addr("192.168.1.1".parse().unwrap()));
+        let (key, _) = split_key_value("file:/etc/keys/2025").unwrap();
+        assert_eq!(key, "file:/etc/keys/2025");
+        assert!(split_key_value(r#"X / from: "10.0.0.1/8""#).is_err());
+        assert!(split_key_value(r#"X / from: "10.0.0.0/8" / from: ::1"#).is_err());
+        // Without quotes the prefix length is a separate item.
+        let (key, validity) = split_key_value("X / from: 10.0.0.0/8").unwrap();
+        assert_eq!(key, "X / from: 10.0.0.0/8");
+        assert!(validity.from.is_empty());
+    }
+
+    #[test]
+    fn test_key_validity() {
+        let t = |s| parse_datetime(s).unwrap();
+        let validity = KeyValidity {
+            not_before: Some(t("2025-01-01")),
+            not_after: Some(t("2025-12-31T23:59:59")),
+            ..Default::default()
+        };
+        assert!(!validity.is_valid_at(t("2024-12-31T23:59:59")));
+        assert!(validity.is_valid_at(t("2025-01-01")));
+        assert!(validity.is_valid_at(t("2025-12-31T23:59:59")));
+        assert!(!validity.is_valid_at(t("2026-01-01")));
+        let validity = KeyValidity {
+            disabled: true,
+            ..Default::default()
+        };
+        assert!(!validity.is_valid_at(t("2025-01-01")));
+        assert!(KeyValidity::default().is_valid_at(t("2025-01-01")));
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        let secs = |s| {
+            parse_datetime(s)
+                .unwrap()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert_eq!(secs("1970-01-01"), 0);
+        assert_eq!(secs("2000-03-01"), 951868800);
+        assert_eq!(secs("2024-02-29T12:30"), 1709209800);
+        assert_eq!(secs("2024-02-29T12:30:15Z"), 1709209815);
+        assert_eq!(secs("2024-02-29 12:30:15"), 1709209815);
+        assert!(parse_datetime("2023-02-29").is_err());
+        assert!(parse_datetime("1969-12-31").is_err());
+        assert!(parse_datetime("2024-01-01T24:00").is_err());
+        assert!(parse_datetime("2024-01").is_err());
+    }
+
+    #[test]
+    fn test_keys_debug() {
+        let mut keys = Keys::new();
+        keys.insert(
+            1.into(),
+            UserKey::new("00000001".to_string(), [0xAB; 32], Default::default()),
+        );
+        let debug = format!("{keys:?}");
+        assert!(!debug.contains("171"));
+        assert!(!debug.to_lowercase().contains("ab"));
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -14,23 +14,40 @@
 
 #![forbid(unsafe_code)]
 
+mod check;
+mod cidr;
 mod ini;
+mod key_source;
 mod parse;
 mod parse_items;
+mod perm;
+mod schedule;
+mod timezone;
 
 use crate::{
-    parse::{is_number, parse_bool, parse_duration, parse_hex, parse_u16},
+    cidr::{list_contains, parse_cidr_list},
+    key_source::{split_key_value, KeySource, Keys, SecretKey},
+    parse::{is_number, parse_bool, parse_duration, parse_f64, parse_u16, parse_u32},
     parse_items::{Map, MapItem},
 };
 use anyhow::{self as ah, format_err as err, Context as _};
 use letmein_proto::{Key, ResourceId, UserId, PORT};
 use std::{
     collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
-pub use crate::ini::{Ini, IniSectionIter};
+pub use crate::{
+    check::{ConfigProblem, ProblemSeverity},
+    cidr::Cidr,
+    ini::{Ini, IniPos, IniSectionIter, ResolvedIncludes},
+    key_source::{KeyValidity, UserKey},
+    schedule::Schedule,
+    timezone::TimeZone,
+};
 
 /// The default server configuration path, relative to the install prefix.
 #[cfg(not(target_os = "windows"))]
@@ -46,6 +63,45 @@ const CLIENT_CONF_PATH: &str = "letmein.conf";
 
 const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_millis(5_000);
 const DEFAULT_NFT_TIMEOUT: Duration = Duration::from_millis(600_000);
+const DEFAULT_BAN_WINDOW: Duration = Duration::from_millis(60_000);
+const DEFAULT_BAN_DURATION: Duration = Duration::from_millis(3_600_000);
+const DEFAULT_CONN_PREFIX_V4: u8 = 24;
+const DEFAULT_CONN_PREFIX_V6: u8 = 64;
+const DEFAULT_CONN_BURST: u32 = 10;
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_millis(10_000);
+
+/// Configured per-source connection limits of the control port.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnLimits {
+    /// Maximum number of simultaneous connections per source address.
+    /// 0 means unlimited.
+    pub per_addr: u32,
+    /// Maximum number of simultaneous connections per source prefix.
+    /// 0 means unlimited.
+    pub per_prefix: u32,
+    /// IPv4 prefix length for the per-prefix limit.
+    pub prefix_v4: u8,
+    /// IPv6 prefix length for the per-prefix limit.
+    pub prefix_v6: u8,
+    /// Token bucket refill rate, in connections per second per source address.
+    /// 0 means that rate limiting is disabled.
+    pub rate: f64,
+    /// Token bucket size.
+    pub burst: u32,
+}
+
+impl Default for ConnLimits {
+    fn default() -> Self {
+        Self {
+            per_addr: 0,
+            per_prefix: 0,
+            prefix_v4: DEFAULT_CONN_PREFIX_V4,
+            prefix_v6: DEFAULT_CONN_PREFIX_V6,
+            rate: 0.0,
+            burst: DEFAULT_CONN_BURST,
+        }
+    }
+}
 
 /// Configured control port.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -81,6 +137,104 @@ impl std::fmt::Display for ControlPort {
     }
 }
 
+/// Configured letmeind listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Listener {
+    /// The local address to bind to.
+    /// The unspecified IPv6 address `::` binds to all IPv4 and IPv6 addresses.
+    pub addr: IpAddr,
+    pub port: ControlPort,
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{} port {}", self.addr, self.port)
+    }
+}
+
+/// Time unit of a [RateLimit].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+}
+
+impl std::fmt::Display for LimitUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Second => write!(f, "second"),
+            Self::Minute => write!(f, "minute"),
+            Self::Hour => write!(f, "hour"),
+            Self::Day => write!(f, "day"),
+            Self::Week => write!(f, "week"),
+        }
+    }
+}
+
+impl std::str::FromStr for LimitUnit {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "second" => Ok(Self::Second),
+            "minute" => Ok(Self::Minute),
+            "hour" => Ok(Self::Hour),
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            other => Err(err!(
+                "Rate limit unit '{other}' is not valid. \
+                Valid values are: second, minute, hour, day, week."
+            )),
+        }
+    }
+}
+
+/// Packet rate limit of the firewall rules of a resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Number of packets per `per`.
+    pub rate: u32,
+    /// Time unit of the `rate`.
+    pub per: LimitUnit,
+    /// Number of packets that may exceed the rate.
+    pub burst: Option<u32>,
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = ah::Error;
+
+    /// Parse a rate limit in the form `RATE per UNIT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let (Some(rate), Some("per"), Some(per), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(err!(
+                "Rate limit '{}' is not valid. Expected: RATE per UNIT",
+                s.trim()
+            ));
+        };
+        let rate = parse_u32(rate).context("Rate limit")?;
+        if rate == 0 {
+            return Err(err!("Rate limit must not be zero."));
+        }
+        Ok(Self {
+            rate,
+            per: per.parse()?,
+            burst: None,
+        })
+    }
+}
+
+/// Default nftables log prefix of a resource with logging enabled.
+pub const DEFAULT_LOG_PREFIX: &str = "letmein: ";
+
+/// Maximum length of an nftables log prefix.
+const MAX_LOG_PREFIX_LEN: usize = 127;
+
 /// Configured resource.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Resource {
@@ -90,18 +244,22 @@ pub enum Resource {
         tcp: bool,
         udp: bool,
         users: Vec<UserId>,
+        /// nftables `log prefix` of the accept rules, if logging is enabled.
+        log: Option<String>,
+        /// nftables `limit rate` of the accept rules, if any.
+        limit: Option<RateLimit>,
+        /// The times at which the resource can be knocked open, if restricted.
+        schedule: Option<Schedule>,
+        /// The source addresses the resource can be knocked open from.
+        /// Empty, if all addresses are allowed.
+        from: Vec<Cidr>,
     },
 }
 
 impl Resource {
     pub fn contains_user(&self, id: UserId) -> bool {
         match self {
-            Self::Port {
-                port: _,
-                tcp: _,
-                udp: _,
-                users,
-            } => {
+            Self::Port { users, .. } => {
                 if users.is_empty() {
                     // This resource is unrestricted.
                     return true;
@@ -110,6 +268,21 @@ impl Resource {
             }
         }
     }
+
+    /// Get the times at which the resource can be knocked open.
+    /// `None`, if the resource is not restricted in time.
+    pub fn schedule(&self) -> Option<&Schedule> {
+        match self {
+            Self::Port { schedule, .. } => schedule.as_ref(),
+        }
+    }
+
+    /// Check whether the resource can be knocked open from the source address `addr`.
+    pub fn allows_addr(&self, addr: IpAddr) -> bool {
+        match self {
+            Self::Port { from, .. } => list_contains(from, addr),
+        }
+    }
 }
 
 /// Error reporting policy.
@@ -152,6 +325,41 @@ impl std::str::FromStr for ErrorPolicy {
     }
 }
 
+/// What to do, if a new lease would exceed a lease quota.
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum LeaseQuotaPolicy {
+    /// Reject the new lease (default).
+    #[default]
+    Reject,
+
+    /// Remove the oldest leases to make room for the new lease.
+    EvictOldest,
+}
+
+impl std::fmt::Display for LeaseQuotaPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Reject => write!(f, "reject"),
+            Self::EvictOldest => write!(f, "evict-oldest"),
+        }
+    }
+}
+
+impl std::str::FromStr for LeaseQuotaPolicy {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "reject" => Ok(LeaseQuotaPolicy::Reject),
+            "evict-oldest" => Ok(LeaseQuotaPolicy::EvictOldest),
+            other => Err(err!(
+                "Config option 'lease-quota-policy = {other}' is not valid. \
+                Valid values are: reject, evict-oldest."
+            )),
+        }
+    }
+}
+
 /// Seccomp setting.
 #[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
 pub enum Seccomp {
@@ -181,6 +389,77 @@ impl std::fmt::Display for Seccomp {
     }
 }
 
+/// Listening endpoint of a metrics server.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub enum MetricsListen {
+    /// The metrics server is disabled (default).
+    #[default]
+    Off,
+
+    /// Listen on a loopback TCP address.
+    Tcp(SocketAddr),
+
+    /// Listen on a Unix socket.
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for MetricsListen {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.to_lowercase() == "off" {
+            return Ok(Self::Off);
+        }
+        if s.starts_with('/') {
+            return Ok(Self::Unix(s.into()));
+        }
+        let Ok(addr) = s.parse::<SocketAddr>() else {
+            return Err(err!(
+                "Metrics listen address '{s}' is not valid. \
+                Valid values are: off, a loopback address with port or an absolute socket path."
+            ));
+        };
+        if !addr.ip().is_loopback() {
+            return Err(err!(
+                "Metrics listen address '{s}' is not a loopback address."
+            ));
+        }
+        Ok(Self::Tcp(addr))
+    }
+}
+
+/// Audit log destination.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub enum AuditLog {
+    /// Audit logging is disabled (default).
+    #[default]
+    Off,
+
+    /// Audit events are sent to the systemd journal.
+    Journal,
+
+    /// Audit events are appended to a file.
+    File(PathBuf),
+}
+
+impl std::str::FromStr for AuditLog {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match &s.to_lowercase()[..] {
+            "off" => Ok(Self::Off),
+            "journal" => Ok(Self::Journal),
+            _ if s.starts_with('/') => Ok(Self::File(s.into())),
+            other => Err(err!(
+                "Config option 'audit-log = {other}' is not valid. \
+                Valid values are: off, journal or an absolute file path."
+            )),
+        }
+    }
+}
+
 impl std::str::FromStr for Seccomp {
     type Err = ah::Error;
 
@@ -199,203 +478,611 @@ impl std::str::FromStr for Seccomp {
 
 fn get_debug(ini: &Ini) -> ah::Result<bool> {
     if let Some(debug) = ini.get("GENERAL", "debug") {
-        return parse_bool(debug);
+        return parse_bool(debug).with_context(|| ini.describe("GENERAL", "debug"));
     }
     Ok(false)
 }
 
 fn get_port(ini: &Ini) -> ah::Result<ControlPort> {
     if let Some(port) = ini.get("GENERAL", "port") {
-        let mut control_port = ControlPort {
-            port: PORT,
-            tcp: false,
-            udp: false,
-        };
-        let map = port.parse::<Map>().context("[GENERAL] port")?;
-        for item in map.items() {
-            match item {
-                MapItem::KeyValue(k, _) | MapItem::KeyValues(k, _) => {
-                    return Err(err!("[GENERAL] port: Unknown option: {k}"));
-                }
-                MapItem::Values(vs) => {
-                    if vs.len() == 1 && is_number(&vs[0]) {
-                        control_port.port = parse_u16(&vs[0])?;
-                    } else {
-                        for v in vs {
-                            match &v.to_lowercase()[..] {
-                                "tcp" => control_port.tcp = true,
-                                "udp" => control_port.udp = true,
-                                v => {
-                                    return Err(err!("[GENERAL] port: Unknown option: {v}"));
-                                }
+        return parse_control_port(port).with_context(|| ini.describe("GENERAL", "port"));
+    }
+    Ok(Default::default())
+}
+
+fn parse_control_port(port: &str) -> ah::Result<ControlPort> {
+    let mut control_port = ControlPort {
+        port: PORT,
+        tcp: false,
+        udp: false,
+    };
+    let map = port.parse::<Map>()?;
+    for item in map.items() {
+        match item {
+            MapItem::KeyValue(k, _) | MapItem::KeyValues(k, _) => {
+                return Err(err!("Unknown option: {k}"));
+            }
+            MapItem::Values(vs) => {
+                if vs.len() == 1 && is_number(&vs[0]) {
+                    control_port.port = parse_u16(&vs[0])?;
+                } else {
+                    for v in vs {
+                        match &v.to_lowercase()[..] {
+                            "tcp" => control_port.tcp = true,
+                            "udp" => control_port.udp = true,
+                            v => {
+                                return Err(err!("Unknown option: {v}"));
                             }
                         }
                     }
                 }
             }
         }
-        if !control_port.tcp && !control_port.udp {
-            // Default, if no tcp/udp option is given.
-            control_port.tcp = true;
+    }
+    if !control_port.tcp && !control_port.udp {
+        // Default, if no tcp/udp option is given.
+        control_port.tcp = true;
+    }
+    Ok(control_port)
+}
+
+fn get_listen(ini: &Ini, port: ControlPort) -> ah::Result<Vec<Listener>> {
+    if let Some(listen) = ini.get("GENERAL", "listen") {
+        return parse_listen(listen).with_context(|| ini.describe("GENERAL", "listen"));
+    }
+    Ok(vec![Listener {
+        addr: Ipv6Addr::UNSPECIFIED.into(),
+        port,
+    }])
+}
+
+/// Parse a comma separated list of `ADDR:PORT[/tcp|/udp]` listeners.
+///
+/// Entries with the same address and port are merged into one [Listener].
+fn parse_listen(listen: &str) -> ah::Result<Vec<Listener>> {
+    let mut listeners: Vec<Listener> = vec![];
+    for entry in listen.split(',') {
+        let entry = entry.trim();
+        let (addr, proto) = match entry.rsplit_once('/') {
+            Some((addr, proto)) => (addr.trim(), Some(proto.trim().to_lowercase())),
+            None => (entry, None),
+        };
+        let addr: SocketAddr = match addr.strip_prefix("*:") {
+            Some(port) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), parse_u16(port)?),
+            None => addr
+                .parse()
+                .with_context(|| format!("Invalid listen address '{addr}'. Expected ADDR:PORT"))?,
+        };
+        let (tcp, udp) = match proto.as_deref() {
+            None | Some("tcp") => (true, false),
+            Some("udp") => (false, true),
+            Some(proto) => return Err(err!("Unknown protocol: {proto}")),
+        };
+        match listeners
+            .iter_mut()
+            .find(|l| l.addr == addr.ip() && l.port.port == addr.port())
+        {
+            Some(listener) => {
+                listener.port.tcp |= tcp;
+                listener.port.udp |= udp;
+            }
+            None => listeners.push(Listener {
+                addr: addr.ip(),
+                port: ControlPort {
+                    port: addr.port(),
+                    tcp,
+                    udp,
+                },
+            }),
         }
-        return Ok(control_port);
     }
-    Ok(Default::default())
+    Ok(listeners)
+}
+
+fn get_proxy_protocol(ini: &Ini) -> ah::Result<Vec<Cidr>> {
+    if let Some(proxies) = ini.get("GENERAL", "proxy-protocol") {
+        return parse_cidr_list(proxies.split(','))
+            .with_context(|| ini.describe("GENERAL", "proxy-protocol"));
+    }
+    Ok(vec![])
 }
 
 fn get_control_timeout(ini: &Ini) -> ah::Result<Duration> {
     if let Some(timeout) = ini.get("GENERAL", "control-timeout") {
-        return parse_duration(timeout);
+        return parse_duration(timeout).with_context(|| ini.describe("GENERAL", "control-timeout"));
     }
     Ok(DEFAULT_CONTROL_TIMEOUT)
 }
 
+fn get_ban_threshold(ini: &Ini) -> ah::Result<u32> {
+    if let Some(threshold) = ini.get("GENERAL", "ban-threshold") {
+        return parse_u32(threshold).with_context(|| ini.describe("GENERAL", "ban-threshold"));
+    }
+    Ok(0)
+}
+
+fn get_ban_window(ini: &Ini) -> ah::Result<Duration> {
+    if let Some(window) = ini.get("GENERAL", "ban-window") {
+        return parse_duration(window).with_context(|| ini.describe("GENERAL", "ban-window"));
+    }
+    Ok(DEFAULT_BAN_WINDOW)
+}
+
+fn get_ban_duration(ini: &Ini) -> ah::Result<Duration> {
+    if let Some(duration) = ini.get("GENERAL", "ban-duration") {
+        return parse_duration(duration).with_context(|| ini.describe("GENERAL", "ban-duration"));
+    }
+    Ok(DEFAULT_BAN_DURATION)
+}
+
+fn get_ban_firewall(ini: &Ini, listen: &[Listener]) -> ah::Result<bool> {
+    if let Some(firewall) = ini.get("GENERAL", "ban-firewall") {
+        let firewall =
+            parse_bool(firewall).with_context(|| ini.describe("GENERAL", "ban-firewall"))?;
+        // Failed UDP knocks with a spoofed source address could ban any address.
+        if firewall && listen.iter().any(|l| l.port.udp) {
+            return Err(err!(
+                "'ban-firewall' can't be used with a UDP control port, \
+                 because the source address of UDP knocks is not verified."
+            ))
+            .with_context(|| ini.describe("GENERAL", "ban-firewall"));
+        }
+        return Ok(firewall);
+    }
+    Ok(false)
+}
+
+fn get_conn_limits(ini: &Ini) -> ah::Result<ConnLimits> {
+    let mut limits = ConnLimits::default();
+    if let Some(per_addr) = ini.get("GENERAL", "conn-limit-addr") {
+        limits.per_addr =
+            parse_u32(per_addr).with_context(|| ini.describe("GENERAL", "conn-limit-addr"))?;
+    }
+    if let Some(per_prefix) = ini.get("GENERAL", "conn-limit-prefix") {
+        limits.per_prefix =
+            parse_u32(per_prefix).with_context(|| ini.describe("GENERAL", "conn-limit-prefix"))?;
+    }
+    if let Some(prefix_v4) = ini.get("GENERAL", "conn-prefix-v4") {
+        limits.prefix_v4 = parse_u32(prefix_v4)
+            .and_then(|len| {
+                len.try_into()
+                    .ok()
+                    .filter(|&len| len <= 32)
+                    .ok_or_else(|| err!("Invalid prefix length"))
+            })
+            .with_context(|| ini.describe("GENERAL", "conn-prefix-v4"))?;
+    }
+    if let Some(prefix_v6) = ini.get("GENERAL", "conn-prefix-v6") {
+        limits.prefix_v6 = parse_u32(prefix_v6)
+            .and_then(|len| {
+                len.try_into()
+                    .ok()
+                    .filter(|&len| len <= 128)
+                    .ok_or_else(|| err!("Invalid prefix length"))
+            })
+            .with_context(|| ini.describe("GENERAL", "conn-prefix-v6"))?;
+    }
+    if let Some(rate) = ini.get("GENERAL", "conn-rate") {
+        limits.rate = parse_f64(rate)
+            .and_then(|rate| {
+                if rate < 0.0 {
+                    Err(err!("Negative rate"))
+                } else {
+                    Ok(rate)
+                }
+            })
+            .with_context(|| ini.describe("GENERAL", "conn-rate"))?;
+    }
+    if let Some(burst) = ini.get("GENERAL", "conn-burst") {
+        limits.burst = parse_u32(burst)
+            .and_then(|burst| {
+                if burst == 0 {
+                    Err(err!("Must be at least 1"))
+                } else {
+                    Ok(burst)
+                }
+            })
+            .with_context(|| ini.describe("GENERAL", "conn-burst"))?;
+    }
+    Ok(limits)
+}
+
+fn get_insecure_key_permissions(ini: &Ini) -> ah::Result<bool> {
+    if let Some(insecure) = ini.get("GENERAL", "insecure-key-permissions") {
+        return parse_bool(insecure)
+            .with_context(|| ini.describe("GENERAL", "insecure-key-permissions"));
+    }
+    Ok(false)
+}
+
+fn get_timezone(ini: &Ini) -> ah::Result<TimeZone> {
+    let timezone = ini.get("GENERAL", "timezone").unwrap_or("local");
+    TimeZone::parse(timezone).with_context(|| ini.describe("GENERAL", "timezone"))
+}
+
+fn get_schedule_cap_lease(ini: &Ini) -> ah::Result<bool> {
+    if let Some(cap) = ini.get("GENERAL", "schedule-cap-lease") {
+        return parse_bool(cap).with_context(|| ini.describe("GENERAL", "schedule-cap-lease"));
+    }
+    Ok(false)
+}
+
+fn get_max_leases(ini: &Ini, opt: &str) -> ah::Result<u32> {
+    if let Some(max) = ini.get("GENERAL", opt) {
+        return parse_u32(max).with_context(|| ini.describe("GENERAL", opt));
+    }
+    Ok(0)
+}
+
+fn get_lease_quota_policy(ini: &Ini) -> ah::Result<LeaseQuotaPolicy> {
+    if let Some(policy) = ini.get("GENERAL", "lease-quota-policy") {
+        return policy
+            .parse()
+            .with_context(|| ini.describe("GENERAL", "lease-quota-policy"));
+    }
+    Ok(Default::default())
+}
+
+fn get_firewall_key(ini: &Ini, variant: ConfigVariant) -> ah::Result<Option<SecretKey>> {
+    let Some(value) = ini.get("GENERAL", "firewall-key") else {
+        return Ok(None);
+    };
+    let key = KeySource::parse(value, key_file_base(ini, "GENERAL", "firewall-key"))
+        .and_then(|source| source.resolve(variant))
+        .and_then(|key| {
+            if key == [0; std::mem::size_of::<Key>()] || key == [0xFF; std::mem::size_of::<Key>()] {
+                return Err(err!("Invalid key: Key is all zeros (00) or all ones (FF)"));
+            }
+            Ok(key)
+        })
+        .with_context(|| ini.describe("GENERAL", "firewall-key"))?;
+    Ok(Some(SecretKey::new(key)))
+}
+
 fn get_control_error_policy(ini: &Ini) -> ah::Result<ErrorPolicy> {
     if let Some(policy) = ini.get("GENERAL", "control-error-policy") {
-        return policy.parse();
+        return policy
+            .parse()
+            .with_context(|| ini.describe("GENERAL", "control-error-policy"));
     }
     Ok(Default::default())
 }
 
 fn get_seccomp(ini: &Ini) -> ah::Result<Seccomp> {
     if let Some(seccomp) = ini.get("GENERAL", "seccomp") {
-        return seccomp.parse();
+        return seccomp
+            .parse()
+            .with_context(|| ini.describe("GENERAL", "seccomp"));
+    }
+    Ok(Default::default())
+}
+
+fn get_audit_log(ini: &Ini) -> ah::Result<AuditLog> {
+    if let Some(audit_log) = ini.get("GENERAL", "audit-log") {
+        return audit_log
+            .parse()
+            .with_context(|| ini.describe("GENERAL", "audit-log"));
     }
     Ok(Default::default())
 }
 
-fn get_keys(ini: &Ini) -> ah::Result<HashMap<UserId, Key>> {
-    let mut keys = HashMap::new();
+fn get_keys(ini: &Ini, variant: ConfigVariant) -> ah::Result<Keys> {
+    let mut keys = Keys::new();
     if let Some(options) = ini.options_iter("KEYS") {
         for (id, key) in options {
-            let id = id.parse().context("[KEYS]")?;
-            let key = parse_hex(key).context("[KEYS]")?;
-            if key == [0; std::mem::size_of::<Key>()] {
-                return Err(err!("Invalid key {id}: Key is all zeros (00)"));
-            }
-            if key == [0xFF; std::mem::size_of::<Key>()] {
-                return Err(err!("Invalid key {id}: Key is all ones (FF)"));
-            }
-            if keys.contains_key(&id) {
-                return Err(err!("[KEYS] Multiple definitions of key '{id}'"));
-            }
+            let (id, key) = parse_key(ini, id, key, variant, &keys)
+                .with_context(|| ini.describe("KEYS", id))?;
             keys.insert(id, key);
         }
     }
     Ok(keys)
 }
 
+/// Parse one option of the `[KEYS]` section.
+///
+/// The option name is the user identifier,
+/// optionally followed by a `.label` to define more than one key per user.
+///
+/// `keys` are the already parsed keys. They are used to detect duplicates.
+fn parse_key(
+    ini: &Ini,
+    name: &str,
+    value: &str,
+    variant: ConfigVariant,
+    keys: &Keys,
+) -> ah::Result<(UserId, UserKey)> {
+    let base = key_file_base(ini, "KEYS", name);
+    let (id, label) = match name.split_once('.') {
+        Some((id, label)) => (id, Some(label.trim())),
+        None => (name, None),
+    };
+    let id: UserId = id.parse()?;
+    let name = match label {
+        Some("") => return Err(err!("Empty key label")),
+        Some(label) => format!("{id}.{label}"),
+        None => id.to_string(),
+    };
+    let (value, validity) = split_key_value(value)?;
+    let key = KeySource::parse(value, base)?.resolve(variant)?;
+    if key == [0; std::mem::size_of::<Key>()] {
+        return Err(err!("Invalid key {name}: Key is all zeros (00)"));
+    }
+    if key == [0xFF; std::mem::size_of::<Key>()] {
+        return Err(err!("Invalid key {name}: Key is all ones (FF)"));
+    }
+    if keys.get(&id).iter().any(|k| k.name() == name) {
+        return Err(err!("Multiple definitions of key '{name}'"));
+    }
+    Ok((id, UserKey::new(name, key, validity)))
+}
+
+/// Get the directory that relative `file:` key references of `option` in `section` are relative to.
+fn key_file_base<'a>(ini: &'a Ini, section: &str, option: &str) -> Option<&'a Path> {
+    ini.option_pos(section, option)
+        .and_then(|pos| pos.file())
+        .and_then(|file| file.parent())
+}
+
+fn get_groups(ini: &Ini) -> ah::Result<HashMap<String, Vec<UserId>>> {
+    let mut groups = HashMap::new();
+    if let Some(options) = ini.options_iter("GROUPS") {
+        for (name, users) in options {
+            let users = parse_group(name, users).with_context(|| ini.describe("GROUPS", name))?;
+            groups.insert(name.trim().to_string(), users);
+        }
+    }
+    Ok(groups)
+}
+
+/// Parse one option of the `[GROUPS]` section.
+fn parse_group(name: &str, users: &str) -> ah::Result<Vec<UserId>> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(err!(
+            "Invalid group name. Only letters, digits, '-' and '_' are allowed"
+        ));
+    }
+    let mut group = vec![];
+    for user in users.split(',') {
+        let user: UserId = user
+            .parse()
+            .map_err(|_| err!("'user' id '{}' is invalid", user.trim()))?;
+        if !group.contains(&user) {
+            group.push(user);
+        }
+    }
+    Ok(group)
+}
+
 fn get_resources(ini: &Ini) -> ah::Result<HashMap<ResourceId, Resource>> {
+    let groups = get_groups(ini)?;
     let mut resources = HashMap::new();
     if let Some(options) = ini.options_iter("RESOURCES") {
         for (id, resource) in options {
-            let id = id.parse().context("[RESOURCES]")?;
-            let map = resource.parse::<Map>().context("[RESOURCES]")?;
-
-            let mut port: Option<u16> = None;
-            let mut users: Vec<String> = vec![];
-            let mut tcp = false;
-            let mut udp = false;
-
-            for item in map.items() {
-                match item {
-                    MapItem::KeyValue(k, v) => {
-                        if k == "port" {
-                            if port.is_some() {
-                                return Err(err!("[RESOURCE] multiple 'port' values"));
-                            }
-                            port = Some(parse_u16(v).context("[RESOURCES] port")?);
-                        } else if k == "users" {
-                            if !users.is_empty() {
-                                return Err(err!("[RESOURCE] multiple 'users' values"));
-                            }
-                            users.push(v.clone());
-                        } else {
-                            return Err(err!("[RESOURCE] unknown option: {k}"));
-                        }
+            let (id, res) = parse_resource(id, resource, &groups, &resources)
+                .with_context(|| ini.describe("RESOURCES", id))?;
+            resources.insert(id, res);
+        }
+    }
+    Ok(resources)
+}
+
+/// Parse one option of the `[RESOURCES]` section.
+///
+/// `@name` entries in `users:` are expanded with the `groups`.
+/// `resources` are the already parsed resources. They are used to detect duplicates.
+fn parse_resource(
+    id: &str,
+    resource: &str,
+    groups: &HashMap<String, Vec<UserId>>,
+    resources: &HashMap<ResourceId, Resource>,
+) -> ah::Result<(ResourceId, Resource)> {
+    let id: ResourceId = id.parse()?;
+    let map = resource.parse::<Map>()?;
+
+    let mut port: Option<u16> = None;
+    let mut users: Vec<String> = vec![];
+    let mut tcp = false;
+    let mut udp = false;
+    let mut log: Option<String> = None;
+    let mut limit: Option<RateLimit> = None;
+    let mut burst: Option<u32> = None;
+    let mut schedule: Option<Schedule> = None;
+    let mut from = vec![];
+
+    for item in map.items() {
+        match item {
+            MapItem::KeyValue(k, v) => {
+                if k == "port" {
+                    if port.is_some() {
+                        return Err(err!("multiple 'port' values"));
                     }
-                    MapItem::KeyValues(k, vs) => {
-                        if k == "port" {
-                            return Err(err!("[RESOURCE] invalid 'port' option"));
-                        } else if k == "users" {
-                            if !users.is_empty() {
-                                return Err(err!("[RESOURCE] multiple 'users' values"));
-                            }
-                            users = vs.clone();
-                        } else {
-                            return Err(err!("[RESOURCE] unknown option: {k}"));
-                        }
+                    port = Some(parse_u16(v).context("port")?);
+                } else if k == "users" {
+                    if !users.is_empty() {
+                        return Err(err!("multiple 'users' values"));
                     }
-                    MapItem::Values(vs) => {
-                        for v in vs {
-                            match &v.to_lowercase()[..] {
-                                "tcp" => {
-                                    tcp = true;
-                                }
-                                "udp" => {
-                                    udp = true;
-                                }
-                                v => {
-                                    return Err(err!("[RESOURCE] unknown option: {v}"));
-                                }
-                            }
-                        }
+                    users.push(v.clone());
+                } else if k == "log" {
+                    if log.is_some() {
+                        return Err(err!("multiple 'log' values"));
+                    }
+                    if v.is_empty() || v.len() > MAX_LOG_PREFIX_LEN {
+                        return Err(err!(
+                            "'log' prefix must be 1 to \
+                            {MAX_LOG_PREFIX_LEN} characters long"
+                        ));
+                    }
+                    if v.contains('"') || v.chars().any(|c| c.is_control()) {
+                        return Err(err!("'log' prefix contains invalid characters"));
+                    }
+                    log = Some(v.clone());
+                } else if k == "limit" {
+                    if limit.is_some() {
+                        return Err(err!("multiple 'limit' values"));
+                    }
+                    limit = Some(v.parse().context("limit")?);
+                } else if k == "burst" {
+                    if burst.is_some() {
+                        return Err(err!("multiple 'burst' values"));
+                    }
+                    burst = Some(parse_u32(v).context("burst")?);
+                } else if k == "schedule" {
+                    if schedule.is_some() {
+                        return Err(err!("multiple 'schedule' values"));
+                    }
+                    schedule = Some(v.parse().context("schedule")?);
+                } else if k == "from" {
+                    if !from.is_empty() {
+                        return Err(err!("multiple 'from' values"));
                     }
+                    from = parse_cidr_list([&v[..]]).context("from")?;
+                } else {
+                    return Err(err!("unknown option: {k}"));
                 }
             }
-            if !tcp && !udp {
-                // Default, if no tcp/udp option is given.
-                tcp = true;
-            }
-            let Some(port) = port else {
-                return Err(err!("[RESOURCE] '{id}': No 'port' value present"));
-            };
-
-            let mut res_users = vec![];
-            for user in users {
-                if let Ok(user) = user.parse() {
-                    res_users.push(user);
+            MapItem::KeyValues(k, vs) => {
+                if k == "port" || k == "log" || k == "limit" || k == "burst" {
+                    return Err(err!("invalid '{k}' option"));
+                } else if k == "users" {
+                    if !users.is_empty() {
+                        return Err(err!("multiple 'users' values"));
+                    }
+                    users = vs.clone();
+                } else if k == "schedule" {
+                    if schedule.is_some() {
+                        return Err(err!("multiple 'schedule' values"));
+                    }
+                    schedule = Some(vs.join(",").parse().context("schedule")?);
+                } else if k == "from" {
+                    if !from.is_empty() {
+                        return Err(err!("multiple 'from' values"));
+                    }
+                    from = parse_cidr_list(vs.iter().map(|v| &v[..])).context("from")?;
                 } else {
-                    return Err(err!("[RESOURCE] '{id}': 'user' id is invalid"));
+                    return Err(err!("unknown option: {k}"));
                 }
             }
-
-            for (res_id, res) in &resources {
-                let Resource::Port { port: res_port, .. } = res;
-                if *res_id == id {
-                    return Err(err!(
-                        "[RESOURCE] Multiple definitions of resource ID '{id}'"
-                    ));
-                }
-                if *res_port == port {
-                    return Err(err!(
-                        "[RESOURCE] Multiple definitions of resource port '{port}'"
-                    ));
+            MapItem::Values(vs) => {
+                for v in vs {
+                    match &v.to_lowercase()[..] {
+                        "tcp" => {
+                            tcp = true;
+                        }
+                        "udp" => {
+                            udp = true;
+                        }
+                        "log" => {
+                            if log.is_some() {
+                                return Err(err!("multiple 'log' values"));
+                            }
+                            log = Some(DEFAULT_LOG_PREFIX.to_string());
+                        }
+                        v => {
+                            return Err(err!("unknown option: {v}"));
+                        }
+                    }
                 }
             }
+        }
+    }
+    if !tcp && !udp {
+        // Default, if no tcp/udp option is given.
+        tcp = true;
+    }
+    let Some(port) = port else {
+        return Err(err!("No 'port' value present"));
+    };
+    if let Some(burst) = burst {
+        let Some(limit) = limit.as_mut() else {
+            return Err(err!("'burst' requires a 'limit' value"));
+        };
+        limit.burst = Some(burst);
+    }
 
-            let res = Resource::Port {
-                port,
-                tcp,
-                udp,
-                users: res_users,
+    let mut res_users = vec![];
+    for user in users {
+        let expanded = if let Some(group) = user.strip_prefix('@') {
+            let Some(group) = groups.get(group) else {
+                return Err(err!("Undefined group '@{group}'"));
             };
-            resources.insert(id, res);
+            group.clone()
+        } else if let Ok(user) = user.parse() {
+            vec![user]
+        } else {
+            return Err(err!("'user' id is invalid"));
+        };
+        for user in expanded {
+            if !res_users.contains(&user) {
+                res_users.push(user);
+            }
         }
     }
-    Ok(resources)
+
+    for (res_id, res) in resources {
+        let Resource::Port { port: res_port, .. } = res;
+        if *res_id == id {
+            return Err(err!("Multiple definitions of resource ID '{id}'"));
+        }
+        if *res_port == port {
+            return Err(err!("Multiple definitions of resource port '{port}'"));
+        }
+    }
+
+    let res = Resource::Port {
+        port,
+        tcp,
+        udp,
+        users: res_users,
+        log,
+        limit,
+        schedule,
+        from,
+    };
+    Ok((id, res))
 }
 
 fn get_default_user(ini: &Ini) -> ah::Result<UserId> {
     if let Some(default_user) = ini.get("CLIENT", "default-user") {
-        return default_user.parse();
+        return default_user
+            .parse()
+            .with_context(|| ini.describe("CLIENT", "default-user"));
     }
     Ok(Default::default())
 }
 
-fn get_nft_exe(ini: &Ini) -> ah::Result<PathBuf> {
-    if let Some(nft_exe) = ini.get("NFTABLES", "exe") {
-        return Ok(nft_exe.trim().into());
+fn get_metrics(ini: &Ini, daemon: &str) -> ah::Result<MetricsListen> {
+    if let Some(listen) = ini.get("METRICS", daemon) {
+        return listen
+            .parse()
+            .with_context(|| ini.describe("METRICS", daemon));
+    }
+    Ok(Default::default())
+}
+
+fn get_hook(ini: &Ini, event: &str) -> ah::Result<Option<String>> {
+    if let Some(command) = ini.get("HOOKS", event) {
+        let command = command.trim();
+        if !command.is_empty() {
+            return Ok(Some(command.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn get_hook_timeout(ini: &Ini) -> ah::Result<Duration> {
+    if let Some(timeout) = ini.get("HOOKS", "timeout") {
+        return parse_duration(timeout).with_context(|| ini.describe("HOOKS", "timeout"));
+    }
+    Ok(DEFAULT_HOOK_TIMEOUT)
+}
+
+fn get_nft_exe(ini: &Ini) -> ah::Result<PathBuf> {
+    if let Some(nft_exe) = ini.get("NFTABLES", "exe") {
+        return Ok(nft_exe.trim().into());
     }
     Ok("nft".into())
 }
@@ -405,8 +1092,9 @@ fn get_nft_family(ini: &Ini) -> ah::Result<String> {
         let nft_family = nft_family.trim();
         Ok(match nft_family {
             "inet" | "ip" | "ip6" => nft_family,
-            nft_family => {
-                return Err(err!("[NFTABLES] family={nft_family} is invalid"));
+            _ => {
+                return Err(err!("Invalid nftables family"))
+                    .with_context(|| ini.describe("NFTABLES", "family"));
             }
         }
         .to_string())
@@ -433,12 +1121,87 @@ fn get_nft_chain_input(ini: &Ini) -> ah::Result<String> {
 
 fn get_nft_timeout(ini: &Ini) -> ah::Result<Duration> {
     if let Some(nft_timeout) = ini.get("NFTABLES", "timeout") {
-        parse_duration(nft_timeout)
+        parse_duration(nft_timeout).with_context(|| ini.describe("NFTABLES", "timeout"))
     } else {
         Ok(DEFAULT_NFT_TIMEOUT)
     }
 }
 
+/// Get the known options of `section`.
+///
+/// Client and server may share one configuration file.
+/// Therefore, the options of both variants are known to both.
+///
+/// Returns `None`, if the section is unknown.
+fn known_options(section: &str) -> Option<&'static [&'static str]> {
+    match section {
+        "GENERAL" => Some(&[
+            "debug",
+            "port",
+            "listen",
+            "proxy-protocol",
+            "control-timeout",
+            "control-error-policy",
+            "seccomp",
+            "insecure-key-permissions",
+            "ban-threshold",
+            "ban-window",
+            "ban-duration",
+            "ban-firewall",
+            "conn-limit-addr",
+            "conn-limit-prefix",
+            "conn-prefix-v4",
+            "conn-prefix-v6",
+            "conn-rate",
+            "conn-burst",
+            "audit-log",
+            "timezone",
+            "schedule-cap-lease",
+            "max-leases-per-user",
+            "max-leases-per-resource",
+            "lease-quota-policy",
+            "firewall-key",
+        ]),
+        "CLIENT" => Some(&["default-user"]),
+        "NFTABLES" => Some(&["exe", "family", "table", "chain-input", "timeout"]),
+        "METRICS" => Some(&["letmeind", "letmeinfwd"]),
+        "HOOKS" => Some(&["on-open", "on-close", "on-expire", "timeout"]),
+        _ => None,
+    }
+}
+
+/// Find all unknown sections and options.
+fn get_unknown_options(ini: &Ini) -> Vec<ConfigProblem> {
+    let mut warnings = vec![];
+    for section in ini.sections() {
+        if section == "KEYS" || section == "RESOURCES" || section == "GROUPS" {
+            // The option names of these sections are IDs or names. They are parsed.
+            continue;
+        }
+        let Some(known) = known_options(section) else {
+            warnings.push(ConfigProblem {
+                severity: ProblemSeverity::Warning,
+                section: section.to_string(),
+                pos: ini.section_pos(section).cloned(),
+                message: "Unknown section. It is ignored.".to_string(),
+            });
+            continue;
+        };
+        for (option, _) in ini.options_iter(section).into_iter().flatten() {
+            if !known.contains(&option.as_str()) {
+                warnings.push(ConfigProblem {
+                    severity: ProblemSeverity::Warning,
+                    section: section.to_string(),
+                    pos: ini.option_pos(section, option).cloned(),
+                    message: format!("Unknown option '{option}'. It is ignored."),
+                });
+            }
+        }
+    }
+    warnings.sort_by(|a, b| a.pos.cmp(&b.pos));
+    warnings
+}
+
 /// Configuration variant.
 #[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
 pub enum ConfigVariant {
@@ -454,12 +1217,28 @@ pub enum ConfigVariant {
 pub struct Config {
     variant: ConfigVariant,
     path: Option<PathBuf>,
+    includes: ResolvedIncludes,
     debug: bool,
     port: ControlPort,
+    listen: Vec<Listener>,
+    proxy_protocol: Vec<Cidr>,
     control_timeout: Duration,
     control_error_policy: ErrorPolicy,
     seccomp: Seccomp,
-    keys: HashMap<UserId, Key>,
+    insecure_key_permissions: bool,
+    ban_threshold: u32,
+    ban_window: Duration,
+    ban_duration: Duration,
+    ban_firewall: bool,
+    conn_limits: ConnLimits,
+    audit_log: AuditLog,
+    timezone: TimeZone,
+    schedule_cap_lease: bool,
+    max_leases_per_user: u32,
+    max_leases_per_resource: u32,
+    lease_quota_policy: LeaseQuotaPolicy,
+    firewall_key: Option<SecretKey>,
+    keys: Keys,
     resources: HashMap<ResourceId, Resource>,
     default_user: UserId,
     nft_exe: PathBuf,
@@ -467,6 +1246,13 @@ pub struct Config {
     nft_table: String,
     nft_chain_input: String,
     nft_timeout: Duration,
+    metrics_letmeind: MetricsListen,
+    metrics_letmeinfwd: MetricsListen,
+    hook_on_open: Option<String>,
+    hook_on_close: Option<String>,
+    hook_on_expire: Option<String>,
+    hook_timeout: Duration,
+    warnings: Vec<ConfigProblem>,
 }
 
 impl Config {
@@ -476,6 +1262,9 @@ impl Config {
             variant,
             control_timeout: DEFAULT_CONTROL_TIMEOUT,
             nft_timeout: DEFAULT_NFT_TIMEOUT,
+            ban_window: DEFAULT_BAN_WINDOW,
+            ban_duration: DEFAULT_BAN_DURATION,
+            hook_timeout: DEFAULT_HOOK_TIMEOUT,
             ..Default::default()
         }
     }
@@ -508,6 +1297,12 @@ impl Config {
         path
     }
 
+    /// Get the warnings about unknown sections and options
+    /// that were found while loading the configuration.
+    pub fn warnings(&self) -> &[ConfigProblem] {
+        &self.warnings
+    }
+
     /// Get the actual path the configuration was read from.
     pub fn get_path(&self) -> Option<&Path> {
         self.path.as_deref()
@@ -515,47 +1310,165 @@ impl Config {
 
     /// (Re-)load a configuration from a file.
     pub fn load(&mut self, path: &Path) -> ah::Result<()> {
-        if let Ok(ini) = Ini::new_from_file(path) {
-            self.load_ini(&ini)?;
-        } else if self.variant == ConfigVariant::Server {
-            return Err(err!("Failed to load configuration {path:?}"));
+        self.load_file(path, None)
+    }
+
+    /// Load the configuration again from the file it has been loaded from.
+    ///
+    /// Wildcard and directory includes keep the files found by [Config::load].
+    /// Therefore, no directory is read. The daemons are not allowed to do that
+    /// after their seccomp rules are installed.
+    /// New files in included directories are only picked up by a restart.
+    pub fn reload(&self) -> ah::Result<Self> {
+        let path = self
+            .path
+            .as_deref()
+            .context("The configuration has not been loaded from a file")?;
+        let mut conf = Self::new(self.variant);
+        conf.load_file(path, Some(&self.includes))?;
+        Ok(conf)
+    }
+
+    fn load_file(&mut self, path: &Path, pinned: Option<&ResolvedIncludes>) -> ah::Result<()> {
+        let mut ini = Ini::new();
+        let result = match pinned {
+            Some(pinned) => ini.read_file_pinned(path, pinned),
+            None => ini.read_file(path),
+        };
+        match result {
+            Ok(()) => self.load_file_ini(&ini)?,
+            Err(e) => {
+                // A missing client configuration is not an error.
+                if self.variant == ConfigVariant::Server || path.exists() {
+                    return Err(e.context(format!("Failed to load configuration {path:?}")));
+                }
+            }
         }
         self.path = Some(path.to_path_buf());
         Ok(())
     }
 
+    /// Load the configuration from an [Ini] that has been read from a file.
+    fn load_file_ini(&mut self, ini: &Ini) -> ah::Result<()> {
+        self.load_ini(ini)?;
+        self.check_key_files(ini)?;
+        self.includes = ini.resolved_includes().clone();
+        Ok(())
+    }
+
+    /// Check the permissions of all files that contain keys.
+    ///
+    /// The server refuses insecure files, unless `insecure-key-permissions` is set.
+    /// Otherwise the problems are added to the [Config::warnings].
+    fn check_key_files(&mut self, ini: &Ini) -> ah::Result<()> {
+        let strict = self.variant == ConfigVariant::Server && !self.insecure_key_permissions;
+        let severity = if strict {
+            ProblemSeverity::Error
+        } else {
+            ProblemSeverity::Warning
+        };
+        let problems = perm::check_key_files(ini, severity)?;
+        if strict {
+            if let Some(problem) = problems.first() {
+                return Err(err!(
+                    "{problem} Fix the file permissions or set \
+                     'insecure-key-permissions = true' in [GENERAL]."
+                ));
+            }
+        }
+        self.warnings.extend(problems);
+        Ok(())
+    }
+
     /// (Re-)load a configuration from a parsed [Ini] instance.
     pub fn load_ini(&mut self, ini: &Ini) -> ah::Result<()> {
         let mut default_user = Default::default();
+        let mut listen = vec![];
+        let mut proxy_protocol = vec![];
         let mut nft_exe = Default::default();
         let mut nft_family = Default::default();
         let mut nft_table = Default::default();
         let mut nft_chain_input = Default::default();
         let mut nft_timeout = DEFAULT_NFT_TIMEOUT;
-
+        let mut ban_threshold = 0;
+        let mut ban_window = DEFAULT_BAN_WINDOW;
+        let mut ban_duration = DEFAULT_BAN_DURATION;
+        let mut ban_firewall = false;
+        let mut conn_limits = Default::default();
+        let mut audit_log = Default::default();
+        let mut timezone = Default::default();
+        let mut schedule_cap_lease = false;
+        let mut max_leases_per_user = 0;
+        let mut max_leases_per_resource = 0;
+        let mut lease_quota_policy = Default::default();
+        let mut firewall_key = None;
+        let mut metrics_letmeind = Default::default();
+        let mut metrics_letmeinfwd = Default::default();
+        let mut hook_on_open = None;
+        let mut hook_on_close = None;
+        let mut hook_on_expire = None;
+        let mut hook_timeout = DEFAULT_HOOK_TIMEOUT;
+
+        let warnings = get_unknown_options(ini);
         let debug = get_debug(ini)?;
         let port = get_port(ini)?;
         let control_timeout = get_control_timeout(ini)?;
         let control_error_policy = get_control_error_policy(ini)?;
         let seccomp = get_seccomp(ini)?;
-        let keys = get_keys(ini)?;
+        let insecure_key_permissions = get_insecure_key_permissions(ini)?;
+        let keys = get_keys(ini, self.variant)?;
         let resources = get_resources(ini)?;
         if self.variant == ConfigVariant::Client {
             default_user = get_default_user(ini)?;
         }
         if self.variant == ConfigVariant::Server {
+            listen = get_listen(ini, port)?;
+            proxy_protocol = get_proxy_protocol(ini)?;
             nft_exe = get_nft_exe(ini)?;
             nft_family = get_nft_family(ini)?;
             nft_table = get_nft_table(ini)?;
             nft_chain_input = get_nft_chain_input(ini)?;
             nft_timeout = get_nft_timeout(ini)?;
+            ban_threshold = get_ban_threshold(ini)?;
+            ban_window = get_ban_window(ini)?;
+            ban_duration = get_ban_duration(ini)?;
+            ban_firewall = get_ban_firewall(ini, &listen)?;
+            conn_limits = get_conn_limits(ini)?;
+            audit_log = get_audit_log(ini)?;
+            timezone = get_timezone(ini)?;
+            schedule_cap_lease = get_schedule_cap_lease(ini)?;
+            max_leases_per_user = get_max_leases(ini, "max-leases-per-user")?;
+            max_leases_per_resource = get_max_leases(ini, "max-leases-per-resource")?;
+            lease_quota_policy = get_lease_quota_policy(ini)?;
+            firewall_key = get_firewall_key(ini, self.variant)?;
+            metrics_letmeind = get_metrics(ini, "letmeind")?;
+            metrics_letmeinfwd = get_metrics(ini, "letmeinfwd")?;
+            hook_on_open = get_hook(ini, "on-open")?;
+            hook_on_close = get_hook(ini, "on-close")?;
+            hook_on_expire = get_hook(ini, "on-expire")?;
+            hook_timeout = get_hook_timeout(ini)?;
         }
 
         self.debug = debug;
         self.port = port;
+        self.listen = listen;
+        self.proxy_protocol = proxy_protocol;
         self.control_timeout = control_timeout;
         self.control_error_policy = control_error_policy;
         self.seccomp = seccomp;
+        self.insecure_key_permissions = insecure_key_permissions;
+        self.ban_threshold = ban_threshold;
+        self.ban_window = ban_window;
+        self.ban_duration = ban_duration;
+        self.ban_firewall = ban_firewall;
+        self.conn_limits = conn_limits;
+        self.audit_log = audit_log;
+        self.timezone = timezone;
+        self.schedule_cap_lease = schedule_cap_lease;
+        self.max_leases_per_user = max_leases_per_user;
+        self.max_leases_per_resource = max_leases_per_resource;
+        self.lease_quota_policy = lease_quota_policy;
+        self.firewall_key = firewall_key;
         self.keys = keys;
         self.resources = resources;
         self.default_user = default_user;
@@ -564,6 +1477,13 @@ impl Config {
         self.nft_table = nft_table;
         self.nft_chain_input = nft_chain_input;
         self.nft_timeout = nft_timeout;
+        self.metrics_letmeind = metrics_letmeind;
+        self.metrics_letmeinfwd = metrics_letmeinfwd;
+        self.hook_on_open = hook_on_open;
+        self.hook_on_close = hook_on_close;
+        self.hook_on_expire = hook_on_expire;
+        self.hook_timeout = hook_timeout;
+        self.warnings = warnings;
         Ok(())
     }
 
@@ -577,6 +1497,29 @@ impl Config {
         self.port
     }
 
+    /// Get the `listen` option from `[GENERAL]` section.
+    ///
+    /// If `listen` is absent, then this is one listener
+    /// on all addresses with the `port` option.
+    pub fn listeners(&self) -> &[Listener] {
+        &self.listen
+    }
+
+    /// Get the `proxy-protocol` option from `[GENERAL]` section.
+    pub fn proxy_protocol(&self) -> &[Cidr] {
+        &self.proxy_protocol
+    }
+
+    /// Check whether TCP connections from `addr` start with a PROXY protocol header.
+    pub fn is_trusted_proxy(&self, addr: IpAddr) -> bool {
+        self.proxy_protocol.iter().any(|net| net.contains(addr))
+    }
+
+    /// Check whether `port` is the port of any of the [Config::listeners].
+    pub fn is_control_port(&self, port: u16) -> bool {
+        self.listen.iter().any(|l| l.port.port == port)
+    }
+
     /// Get the `control-timeout` option from `[GENERAL]` section.
     pub fn control_timeout(&self) -> Duration {
         self.control_timeout
@@ -592,9 +1535,96 @@ impl Config {
         self.seccomp
     }
 
-    /// Get a key value by key identifier from the `[KEYS]` section.
+    /// Get the `insecure-key-permissions` option from `[GENERAL]` section.
+    pub fn insecure_key_permissions(&self) -> bool {
+        self.insecure_key_permissions
+    }
+
+    /// Get the `ban-threshold` option from `[GENERAL]` section.
+    ///
+    /// This is the number of failed authentications within `ban-window`
+    /// that cause a ban of the source address.
+    /// 0 means that banning is disabled.
+    pub fn ban_threshold(&self) -> u32 {
+        self.ban_threshold
+    }
+
+    /// Get the `ban-window` option from `[GENERAL]` section.
+    pub fn ban_window(&self) -> Duration {
+        self.ban_window
+    }
+
+    /// Get the `ban-duration` option from `[GENERAL]` section.
+    pub fn ban_duration(&self) -> Duration {
+        self.ban_duration
+    }
+
+    /// Get the `ban-firewall` option from `[GENERAL]` section.
+    pub fn ban_firewall(&self) -> bool {
+        self.ban_firewall
+    }
+
+    /// Get the `conn-*` connection limit options from `[GENERAL]` section.
+    pub fn conn_limits(&self) -> &ConnLimits {
+        &self.conn_limits
+    }
+
+    /// Get the `audit-log` option from `[GENERAL]` section.
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    /// Get the `timezone` option from `[GENERAL]` section.
+    pub fn timezone(&self) -> &TimeZone {
+        &self.timezone
+    }
+
+    /// Get the `schedule-cap-lease` option from `[GENERAL]` section.
+    pub fn schedule_cap_lease(&self) -> bool {
+        self.schedule_cap_lease
+    }
+
+    /// Get the `max-leases-per-user` option from `[GENERAL]` section.
+    /// 0 means unlimited.
+    pub fn max_leases_per_user(&self) -> u32 {
+        self.max_leases_per_user
+    }
+
+    /// Get the `max-leases-per-resource` option from `[GENERAL]` section.
+    /// 0 means unlimited.
+    pub fn max_leases_per_resource(&self) -> u32 {
+        self.max_leases_per_resource
+    }
+
+    /// Get the `lease-quota-policy` option from `[GENERAL]` section.
+    pub fn lease_quota_policy(&self) -> LeaseQuotaPolicy {
+        self.lease_quota_policy
+    }
+
+    /// Get the `firewall-key` option from `[GENERAL]` section.
+    ///
+    /// `None`, if the firewall socket messages are not authenticated.
+    pub fn firewall_key(&self) -> Option<&Key> {
+        self.firewall_key.as_ref().map(|key| key.key())
+    }
+
+    /// Get the key of a user from the `[KEYS]` section that is valid now.
+    ///
+    /// If the user has several valid keys, then the newest one is returned.
     pub fn key(&self, id: UserId) -> Option<&Key> {
-        self.keys.get(&id)
+        self.valid_keys(id, SystemTime::now())
+            .into_iter()
+            .max_by_key(|key| key.validity().not_before)
+            .map(|key| key.key())
+    }
+
+    /// Get all keys of a user from the `[KEYS]` section that are valid at the time `now`.
+    pub fn valid_keys(&self, id: UserId, now: SystemTime) -> Vec<&UserKey> {
+        self.keys
+            .get(&id)
+            .iter()
+            .filter(|key| key.is_valid_at(now))
+            .collect()
     }
 
     /// Get a resource value by resource identifier from the `[RESOURCES]` section.
@@ -622,6 +1652,14 @@ impl Config {
         None
     }
 
+    /// Check whether the source address `addr` is allowed
+    /// by at least one key of the user that is valid now.
+    pub fn user_allows_addr(&self, id: UserId, addr: IpAddr) -> bool {
+        self.valid_keys(id, SystemTime::now())
+            .iter()
+            .any(|key| key.allows_addr(addr))
+    }
+
     /// Get the `default-user` option from `[CLIENT]` section.
     pub fn default_user(&self) -> UserId {
         self.default_user
@@ -651,6 +1689,63 @@ impl Config {
     pub fn nft_timeout(&self) -> Duration {
         self.nft_timeout
     }
+
+    /// Get the `letmeind` option from `[METRICS]` section.
+    pub fn metrics_letmeind(&self) -> &MetricsListen {
+        &self.metrics_letmeind
+    }
+
+    /// Get the `letmeinfwd` option from `[METRICS]` section.
+    pub fn metrics_letmeinfwd(&self) -> &MetricsListen {
+        &self.metrics_letmeinfwd
+    }
+
+    /// Get the `on-open` option from `[HOOKS]` section.
+    pub fn hook_on_open(&self) -> Option<&str> {
+        self.hook_on_open.as_deref()
+    }
+
+    /// Get the `on-close` option from `[HOOKS]` section.
+    pub fn hook_on_close(&self) -> Option<&str> {
+        self.hook_on_close.as_deref()
+    }
+
+    /// Get the `on-expire` option from `[HOOKS]` section.
+    pub fn hook_on_expire(&self) -> Option<&str> {
+        self.hook_on_expire.as_deref()
+    }
+
+    /// Get the `timeout` option from `[HOOKS]` section.
+    pub fn hook_timeout(&self) -> Duration {
+        self.hook_timeout
+    }
+}
+
+/// A [Config] that can be replaced while it is in use.
+///
+/// Users get a snapshot of the current configuration with [SharedConfig::get].
+/// The snapshot stays valid, even if the configuration is replaced afterwards.
+#[derive(Debug)]
+pub struct SharedConfig {
+    conf: RwLock<Arc<Config>>,
+}
+
+impl SharedConfig {
+    pub fn new(conf: Config) -> Self {
+        Self {
+            conf: RwLock::new(Arc::new(conf)),
+        }
+    }
+
+    /// Get the current configuration.
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.conf.read().expect("SharedConfig lock poisoned"))
+    }
+
+    /// Replace the current configuration by `conf`.
+    pub fn replace(&self, conf: Arc<Config>) {
+        *self.conf.write().expect("SharedConfig lock poisoned") = conf;
+    }
 }
 
 #[cfg(test)]
@@ -685,6 +1780,244 @@ mod tests {
         assert_eq!(get_seccomp(&ini).unwrap(), Seccomp::Kill);
     }
 
+    #[test]
+    fn test_ban() {
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n").unwrap();
+        assert_eq!(get_ban_threshold(&ini).unwrap(), 0);
+        assert_eq!(get_ban_window(&ini).unwrap(), DEFAULT_BAN_WINDOW);
+        assert_eq!(get_ban_duration(&ini).unwrap(), DEFAULT_BAN_DURATION);
+        let tcp = get_listen(&ini, ControlPort::default()).unwrap();
+        assert!(!get_ban_firewall(&ini, &tcp).unwrap());
+
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GENERAL]\nban-threshold = 5\nban-window = 30\n\
+            ban-duration = 7200\nban-firewall = true\n",
+        )
+        .unwrap();
+        assert_eq!(get_ban_threshold(&ini).unwrap(), 5);
+        assert_eq!(get_ban_window(&ini).unwrap(), Duration::from_secs(30));
+        assert_eq!(get_ban_duration(&ini).unwrap(), Duration::from_secs(7200));
+        assert!(get_ban_firewall(&ini, &tcp).unwrap());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nport = 5800 / tcp, udp\nban-firewall = true\n")
+            .unwrap();
+        let udp = get_listen(&ini, get_port(&ini).unwrap()).unwrap();
+        assert!(get_ban_firewall(&ini, &udp).is_err());
+    }
+
+    #[test]
+    fn test_conn_limits() {
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n").unwrap();
+        assert_eq!(get_conn_limits(&ini).unwrap(), ConnLimits::default());
+
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GENERAL]\nconn-limit-addr = 2\nconn-limit-prefix = 8\n\
+            conn-prefix-v4 = 16\nconn-prefix-v6 = 48\nconn-rate = 0.5\nconn-burst = 3\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_conn_limits(&ini).unwrap(),
+            ConnLimits {
+                per_addr: 2,
+                per_prefix: 8,
+                prefix_v4: 16,
+                prefix_v6: 48,
+                rate: 0.5,
+                burst: 3,
+            }
+        );
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nconn-prefix-v4 = 33\n").unwrap();
+        assert!(get_conn_limits(&ini).is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n").unwrap();
+        assert_eq!(get_audit_log(&ini).unwrap(), AuditLog::Off);
+        ini.parse_str("[GENERAL]\naudit-log = Journal\n").unwrap();
+        assert_eq!(get_audit_log(&ini).unwrap(), AuditLog::Journal);
+        ini.parse_str("[GENERAL]\naudit-log = /var/log/letmeind-audit.log\n")
+            .unwrap();
+        assert_eq!(
+            get_audit_log(&ini).unwrap(),
+            AuditLog::File("/var/log/letmeind-audit.log".into())
+        );
+        ini.parse_str("[GENERAL]\naudit-log = relative.log\n")
+            .unwrap();
+        assert!(get_audit_log(&ini).is_err());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut ini = Ini::new();
+        ini.parse_str("[METRICS]\nletmeind = 127.0.0.1:9150\nletmeinfwd = /run/fwd.sock\n")
+            .unwrap();
+        assert_eq!(
+            get_metrics(&ini, "letmeind").unwrap(),
+            MetricsListen::Tcp("127.0.0.1:9150".parse().unwrap())
+        );
+        assert_eq!(
+            get_metrics(&ini, "letmeinfwd").unwrap(),
+            MetricsListen::Unix("/run/fwd.sock".into())
+        );
+        ini.parse_str("[METRICS]\nletmeind = [::1]:9150\nletmeinfwd = off\n")
+            .unwrap();
+        assert_eq!(
+            get_metrics(&ini, "letmeind").unwrap(),
+            MetricsListen::Tcp("[::1]:9150".parse().unwrap())
+        );
+        assert_eq!(get_metrics(&ini, "letmeinfwd").unwrap(), MetricsListen::Off);
+        ini.parse_str("[METRICS]\nletmeind = 0.0.0.0:9150\n")
+            .unwrap();
+        assert!(get_metrics(&ini, "letmeind").is_err());
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut ini = Ini::new();
+        ini.parse_str("[HOOKS]\n").unwrap();
+        assert_eq!(get_hook(&ini, "on-open").unwrap(), None);
+        assert_eq!(get_hook_timeout(&ini).unwrap(), DEFAULT_HOOK_TIMEOUT);
+
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[HOOKS]\non-open = /usr/local/bin/notify open\non-expire =\ntimeout = 2.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            get_hook(&ini, "on-open").unwrap().as_deref(),
+            Some("/usr/local/bin/notify open")
+        );
+        assert_eq!(get_hook(&ini, "on-close").unwrap(), None);
+        assert_eq!(get_hook(&ini, "on-expire").unwrap(), None);
+        assert_eq!(get_hook_timeout(&ini).unwrap(), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn test_error_position() {
+        let mut ini = Ini::new();
+        let e = ini.parse_str("[GENERAL]\ndebug = true\nfoo\n").unwrap_err();
+        assert!(e.to_string().starts_with("line 3: "));
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n\nban-window = soon\n").unwrap();
+        let e = get_ban_window(&ini).unwrap_err();
+        assert_eq!(e.to_string(), "line 3: [GENERAL] ban-window = 'soon'");
+
+        let mut ini = Ini::new();
+        ini.parse_str("[KEYS]\n00000001 = secret\n").unwrap();
+        let e = get_keys(&ini, ConfigVariant::Server).unwrap_err();
+        assert_eq!(e.to_string(), "line 2: [KEYS] 00000001");
+    }
+
+    #[test]
+    fn test_unknown_options() {
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GENERAL]\ndebug = true\nbann-threshold = 3\n\
+             [NFTABLES]\nexe = nft\n\
+             [FOO]\nbar = 1\n\
+             [KEYS]\n[RESOURCES]\n",
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Client);
+        conf.load_ini(&ini).unwrap();
+        let warnings = conf.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].to_string(),
+            "line 3: WARNING: [GENERAL] Unknown option 'bann-threshold'. It is ignored."
+        );
+        assert_eq!(
+            warnings[1].to_string(),
+            "line 6: WARNING: [FOO] Unknown section. It is ignored."
+        );
+    }
+
+    #[test]
+    fn test_key_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = std::env::temp_dir().join(format!("letmein-perm-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("letmeind.conf");
+        let key = "0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF0123456789ABCDEF";
+        let set_mode = |mode| {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        };
+
+        std::fs::write(&path, format!("[KEYS]\n00000001 = {key}\n")).unwrap();
+        set_mode(0o600);
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load(&path).unwrap();
+        assert!(conf.warnings().is_empty());
+
+        set_mode(0o644);
+        let mut conf = Config::new(ConfigVariant::Server);
+        let e = conf.load(&path).unwrap_err();
+        assert!(e.to_string().contains("accessible by all users"));
+
+        let mut conf = Config::new(ConfigVariant::Client);
+        conf.load(&path).unwrap();
+        assert_eq!(conf.warnings().len(), 1);
+        assert_eq!(conf.warnings()[0].severity, ProblemSeverity::Warning);
+        assert_eq!(conf.warnings()[0].pos.as_ref().unwrap().line(), 2);
+
+        std::fs::write(
+            &path,
+            format!("[GENERAL]\ninsecure-key-permissions = true\n[KEYS]\n00000001 = {key}\n"),
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load(&path).unwrap();
+        assert_eq!(conf.warnings().len(), 1);
+
+        std::fs::write(&path, format!("[GENERAL]\nfirewall-key = {key}\n")).unwrap();
+        set_mode(0o644);
+        let mut conf = Config::new(ConfigVariant::Server);
+        let e = conf.load(&path).unwrap_err().to_string();
+        assert!(e.contains("[GENERAL] The file contains secret keys"));
+
+        let key_path = dir.join("letmeinfwd.key");
+        std::fs::write(&key_path, key).unwrap();
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o444)).unwrap();
+        std::fs::write(&path, "[GENERAL]\nfirewall-key = file: letmeinfwd.key\n").unwrap();
+        set_mode(0o644);
+        let mut conf = Config::new(ConfigVariant::Server);
+        let e = conf.load(&path).unwrap_err();
+        assert!(e.to_string().contains("The key file"));
+
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o400)).unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load(&path).unwrap();
+        assert!(conf.warnings().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_config() {
+        let shared = SharedConfig::new(Config::new(ConfigVariant::Server));
+        let old = shared.get();
+        assert!(!old.debug());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\ndebug = true\n").unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        shared.replace(Arc::new(conf));
+        assert!(shared.get().debug());
+        assert!(!old.debug());
+    }
+
     #[test]
     fn test_port() {
         let mut ini = Ini::new();
@@ -735,6 +2068,95 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_listen() {
+        let tcp = |port| ControlPort {
+            port,
+            tcp: true,
+            udp: false,
+        };
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nport=1234").unwrap();
+        assert_eq!(
+            get_listen(&ini, get_port(&ini).unwrap()).unwrap(),
+            [Listener {
+                addr: "::".parse().unwrap(),
+                port: tcp(1234),
+            }]
+        );
+        ini.parse_str(
+            "[GENERAL]\nlisten = 192.0.2.1:5800, [2001:db8::1]:5800 / TCP, \
+             192.0.2.1:5800/udp, *:5900/udp",
+        )
+        .unwrap();
+        assert_eq!(
+            get_listen(&ini, Default::default()).unwrap(),
+            [
+                Listener {
+                    addr: "192.0.2.1".parse().unwrap(),
+                    port: ControlPort {
+                        port: 5800,
+                        tcp: true,
+                        udp: true,
+                    },
+                },
+                Listener {
+                    addr: "2001:db8::1".parse().unwrap(),
+                    port: tcp(5800),
+                },
+                Listener {
+                    addr: "::".parse().unwrap(),
+                    port: ControlPort {
+                        port: 5900,
+                        tcp: false,
+                        udp: true,
+                    },
+                },
+            ]
+        );
+
+        ini.parse_str("[GENERAL]\nlisten = 192.0.2.1:5800, 10.0.0.1:5810")
+            .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert_eq!(conf.listeners().len(), 2);
+        assert!(conf.is_control_port(5800));
+        assert!(conf.is_control_port(5810));
+        assert!(!conf.is_control_port(5900));
+
+        for bad in [
+            "",
+            "192.0.2.1",
+            "5800",
+            "192.0.2.1:5800/sctp",
+            "2001:db8::1:5800",
+        ] {
+            ini.parse_str(&format!("[GENERAL]\nlisten = {bad}"))
+                .unwrap();
+            assert!(get_listen(&ini, Default::default()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nproxy-protocol = 10.0.0.5, fd00::/8")
+            .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert_eq!(conf.proxy_protocol().len(), 2);
+        assert!(conf.is_trusted_proxy("10.0.0.5".parse().unwrap()));
+        assert!(conf.is_trusted_proxy("::ffff:10.0.0.5".parse().unwrap()));
+        assert!(conf.is_trusted_proxy("fd00::1".parse().unwrap()));
+        assert!(!conf.is_trusted_proxy("10.0.0.6".parse().unwrap()));
+        let conf = Config::new(ConfigVariant::Server);
+        assert!(!conf.is_trusted_proxy("10.0.0.5".parse().unwrap()));
+
+        ini.parse_str("[GENERAL]\nproxy-protocol = 10.0.0.5/8")
+            .unwrap();
+        assert!(get_proxy_protocol(&ini).is_err());
+    }
+
     #[test]
     fn test_keys() {
         let mut ini = Ini::new();
@@ -742,9 +2164,9 @@ mod tests {
             "[KEYS]\nABCD1234 = 998877665544332211009988776655443322110099887766554433221100CDEF\n",
         )
         .unwrap();
-        let keys = get_keys(&ini).unwrap();
+        let keys = get_keys(&ini, ConfigVariant::Server).unwrap();
         assert_eq!(
-            keys.get(&0xABCD1234.into()).unwrap(),
+            keys.get(&0xABCD1234.into())[0].key(),
             &[
                 0x99, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x99, 0x88, 0x77, 0x66,
                 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x99, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22,
@@ -753,6 +2175,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_key_rotation() {
+        let t = |s| parse::parse_datetime(s).unwrap();
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[KEYS]\n\
+             00000001 = 1111111111111111111111111111111111111111111111111111111111111111 \
+                 / not-after: 2025-06-30\n\
+             00000001.new = 2222222222222222222222222222222222222222222222222222222222222222 \
+                 / not-before: 2025-06-01\n\
+             00000002 = 3333333333333333333333333333333333333333333333333333333333333333 \
+                 / disabled\n",
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+
+        let names = |now| -> Vec<&str> {
+            let mut names: Vec<&str> = conf
+                .valid_keys(1.into(), t(now))
+                .into_iter()
+                .map(|k| k.name())
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names("2025-05-01"), ["00000001"]);
+        assert_eq!(names("2025-06-15"), ["00000001", "00000001.new"]);
+        assert_eq!(names("2025-07-01"), ["00000001.new"]);
+        assert!(conf.valid_keys(2.into(), t("2025-05-01")).is_empty());
+        assert!(conf.valid_keys(3.into(), t("2025-05-01")).is_empty());
+
+        // The newest valid key is used by the client.
+        assert_eq!(conf.key(1.into()), Some(&[0x22; 32]));
+        assert_eq!(conf.key(2.into()), None);
+
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[KEYS]\n\
+             1.a = 1111111111111111111111111111111111111111111111111111111111111111\n\
+             00000001.a = 2222222222222222222222222222222222222222222222222222222222222222\n",
+        )
+        .unwrap();
+        let e = get_keys(&ini, ConfigVariant::Server).unwrap_err();
+        assert!(format!("{e:#}").contains("Multiple definitions of key '00000001.a'"));
+    }
+
     #[test]
     fn test_resources() {
         let mut ini = Ini::new();
@@ -765,7 +2234,11 @@ mod tests {
                 port: 4096,
                 tcp: true,
                 udp: false,
-                users: vec![]
+                users: vec![],
+                log: None,
+                limit: None,
+                schedule: None,
+                from: vec![],
             }
         );
 
@@ -779,7 +2252,11 @@ mod tests {
                 port: 4096,
                 tcp: true,
                 udp: false,
-                users: vec![]
+                users: vec![],
+                log: None,
+                limit: None,
+                schedule: None,
+                from: vec![],
             }
         );
 
@@ -793,7 +2270,11 @@ mod tests {
                 port: 4096,
                 tcp: false,
                 udp: true,
-                users: vec![1.into(), 2.into(), 3.into()]
+                users: vec![1.into(), 2.into(), 3.into()],
+                log: None,
+                limit: None,
+                schedule: None,
+                from: vec![],
             }
         );
 
@@ -807,9 +2288,263 @@ mod tests {
                 port: 4096,
                 tcp: true,
                 udp: true,
-                users: vec![4.into()]
+                users: vec![4.into()],
+                log: None,
+                limit: None,
+                schedule: None,
+                from: vec![],
+            }
+        );
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n9876ABCD = port : 4096 / log / limit: 10 per minute\n")
+            .unwrap();
+        let resources = get_resources(&ini).unwrap();
+        assert_eq!(
+            resources.get(&0x9876ABCD.into()).unwrap(),
+            &Resource::Port {
+                port: 4096,
+                tcp: true,
+                udp: false,
+                users: vec![],
+                log: Some(DEFAULT_LOG_PREFIX.to_string()),
+                limit: Some(RateLimit {
+                    rate: 10,
+                    per: LimitUnit::Minute,
+                    burst: None,
+                }),
+                schedule: None,
+                from: vec![],
+            }
+        );
+
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[RESOURCES]\n9876ABCD = port : 4096 / log: ssh-knock / limit: 3 per Second / burst: 5\n",
+        )
+        .unwrap();
+        let resources = get_resources(&ini).unwrap();
+        assert_eq!(
+            resources.get(&0x9876ABCD.into()).unwrap(),
+            &Resource::Port {
+                port: 4096,
+                tcp: true,
+                udp: false,
+                users: vec![],
+                log: Some("ssh-knock".to_string()),
+                limit: Some(RateLimit {
+                    rate: 3,
+                    per: LimitUnit::Second,
+                    burst: Some(5),
+                }),
+                schedule: None,
+                from: vec![],
             }
         );
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n9876ABCD = port : 4096 / limit: 10 per fortnight\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n9876ABCD = port : 4096 / burst: 5\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GROUPS]\nops = 1, 2\ndev-team = 00000002, 3, 2\n\
+             [RESOURCES]\n\
+             00000010 = port: 22 / users: @ops, 4\n\
+             00000011 = port: 80 / users: @ops, @dev-team\n",
+        )
+        .unwrap();
+        let resources = get_resources(&ini).unwrap();
+        let users = |id: u32| {
+            let Resource::Port { users, .. } = resources.get(&id.into()).unwrap();
+            users.iter().map(|u| u32::from(*u)).collect::<Vec<_>>()
+        };
+        assert_eq!(users(0x10), [1, 2, 4]);
+        assert_eq!(users(0x11), [1, 2, 3]);
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n00000010 = port: 22 / users: @ops\n")
+            .unwrap();
+        let e = get_resources(&ini).unwrap_err();
+        assert!(format!("{e:#}").contains("Undefined group '@ops'"));
+
+        for group in ["ops =\n", "ops = 1, x\n", "o@ps = 1\n"] {
+            let mut ini = Ini::new();
+            ini.parse_str(&format!("[GROUPS]\n{group}")).unwrap();
+            assert!(get_resources(&ini).is_err());
+        }
+    }
+
+    #[test]
+    fn test_schedules() {
+        let t = |s| parse::parse_datetime(s).unwrap();
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GENERAL]\ntimezone = +01:00\nschedule-cap-lease = true\n\
+             [RESOURCES]\n\
+             00000010 = port: 22 / schedule: Mon-Fri 08:00-18:00\n\
+             00000011 = port: 80 / schedule: Mon-Fri 08:00-18:00, Sat 10:00-12:00\n\
+             00000012 = port: 443\n",
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert_eq!(conf.timezone(), &TimeZone::Fixed(3600));
+        assert!(conf.schedule_cap_lease());
+
+        let schedule = |id: u32| conf.resource(id.into()).unwrap().schedule();
+        assert!(schedule(0x12).is_none());
+        let tz = conf.timezone();
+        // 2025-01-06 is a Monday.
+        assert!(!schedule(0x10)
+            .unwrap()
+            .is_active_at(tz, t("2025-01-06T06:59")));
+        assert!(schedule(0x10)
+            .unwrap()
+            .is_active_at(tz, t("2025-01-06T07:00")));
+        assert!(!schedule(0x10)
+            .unwrap()
+            .is_active_at(tz, t("2025-01-11T10:00")));
+        assert!(schedule(0x11)
+            .unwrap()
+            .is_active_at(tz, t("2025-01-11T10:00")));
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n00000010 = port: 22 / schedule: Mon-Fri\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\ntimezone = Nowhere/../../etc/passwd\n")
+            .unwrap();
+        assert!(Config::new(ConfigVariant::Server).load_ini(&ini).is_err());
+    }
+
+    #[test]
+    fn test_lease_quotas() {
+        let mut ini = Ini::new();
+        ini.parse_str(
+            "[GENERAL]\nmax-leases-per-user = 3\nmax-leases-per-resource = 100\n\
+             lease-quota-policy = evict-oldest\n",
+        )
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert_eq!(conf.max_leases_per_user(), 3);
+        assert_eq!(conf.max_leases_per_resource(), 100);
+        assert_eq!(conf.lease_quota_policy(), LeaseQuotaPolicy::EvictOldest);
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n").unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert_eq!(conf.max_leases_per_user(), 0);
+        assert_eq!(conf.max_leases_per_resource(), 0);
+        assert_eq!(conf.lease_quota_policy(), LeaseQuotaPolicy::Reject);
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nlease-quota-policy = drop\n")
+            .unwrap();
+        assert!(get_lease_quota_policy(&ini).is_err());
+    }
+
+    #[test]
+    fn test_firewall_key() {
+        const K: &str = "998877665544332211009988776655443322110099887766554433221100CDEF";
+        let mut ini = Ini::new();
+        ini.parse_str(&format!("[GENERAL]\nfirewall-key = {K}\n"))
+            .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        let key = conf.firewall_key().unwrap();
+        assert_eq!(key[0], 0x99);
+        assert_eq!(key[31], 0xEF);
+
+        // The client doesn't use the key.
+        let mut conf = Config::new(ConfigVariant::Client);
+        conf.load_ini(&ini).unwrap();
+        assert!(conf.firewall_key().is_none());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\n").unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+        assert!(conf.firewall_key().is_none());
+
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nfirewall-key = 0011\n").unwrap();
+        assert!(get_firewall_key(&ini, ConfigVariant::Server).is_err());
+        let mut ini = Ini::new();
+        ini.parse_str(&format!("[GENERAL]\nfirewall-key = {}\n", "0".repeat(64)))
+            .unwrap();
+        assert!(get_firewall_key(&ini, ConfigVariant::Server).is_err());
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nfirewall-key = file: /nonexistent/letmeinfwd.key\n")
+            .unwrap();
+        assert!(get_firewall_key(&ini, ConfigVariant::Server).is_err());
+    }
+
+    #[test]
+    fn test_from() {
+        const K: &str = "998877665544332211009988776655443322110099887766554433221100CDEF";
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut ini = Ini::new();
+        ini.parse_str(&format!(
+            "[KEYS]\n\
+             00000001 = {K} / from: \"192.168.0.0/16\", \"10.1.0.0/16\"\n\
+             00000002 = {K}\n\
+             [RESOURCES]\n\
+             00000010 = port: 22 / from: \"10.0.0.0/8\", \"2001:db8::/32\" / users: 1\n\
+             00000011 = port: 80 / from: \"10.0.0.0/8\" / tcp / users: 1, 2\n\
+             00000012 = port: 443 / users: 1\n"
+        ))
+        .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+
+        let res = conf.resource(0x10.into()).unwrap();
+        assert!(res.allows_addr(ip("10.1.2.3")));
+        assert!(res.allows_addr(ip("2001:db8::1")));
+        assert!(!res.allows_addr(ip("192.168.1.1")));
+        assert!(conf
+            .resource(0x12.into())
+            .unwrap()
+            .allows_addr(ip("192.0.2.1")));
+        let Resource::Port { tcp, users, .. } = conf.resource(0x11.into()).unwrap();
+        assert!(tcp);
+        assert_eq!(users, &[1.into(), 2.into()]);
+
+        // The key of user 1 is restricted.
+        assert!(conf.user_allows_addr(1.into(), ip("10.1.2.3")));
+        assert!(!conf.user_allows_addr(1.into(), ip("10.2.2.3")));
+        assert!(conf.user_allows_addr(1.into(), ip("192.168.1.1")));
+        // The key of user 2 is not restricted.
+        assert!(conf.user_allows_addr(2.into(), ip("10.2.2.3")));
+        assert!(conf.user_allows_addr(2.into(), ip("192.0.2.1")));
+        // User 3 has no key.
+        assert!(!conf.user_allows_addr(3.into(), ip("10.1.2.3")));
+
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n00000010 = port: 22 / from: \"10.0.0.1/8\"\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n00000010 = port: 22 / from: \"10.0.0.0/8\" / from: ::1\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
+        let mut ini = Ini::new();
+        ini.parse_str("[RESOURCES]\n00000010 = port: 22 / from: 10.0.0.0/8\n")
+            .unwrap();
+        assert!(get_resources(&ini).is_err());
     }
 
     #[test]
@@ -7,7 +7,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use anyhow::{self as ah, format_err as err};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 pub fn parse_bool(s: &str) -> ah::Result<bool> {
     let s = s.to_lowercase();
@@ -28,7 +28,7 @@ pub fn parse_u16(s: &str) -> ah::Result<u16> {
     }
 }
 
-fn parse_u32(s: &str) -> ah::Result<u32> {
+pub fn parse_u32(s: &str) -> ah::Result<u32> {
     let s = s.trim();
     if let Some(s) = s.strip_prefix("0x") {
         Ok(u32::from_str_radix(s, 16)?)
@@ -37,7 +37,7 @@ fn parse_u32(s: &str) -> ah::Result<u32> {
     }
 }
 
-fn parse_f64(s: &str) -> ah::Result<f64> {
+pub fn parse_f64(s: &str) -> ah::Result<f64> {
     let s = s.trim();
     let value = s.parse::<f64>()?;
     if value.is_finite() {
@@ -85,6 +85,89 @@ pub fn parse_duration(s: &str) -> ah::Result<Duration> {
     Err(err!("Invalid Duration"))
 }
 
+/// Get the number of days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Get the year of a number of days since 1970-01-01 in the proleptic Gregorian calendar.
+pub fn year_from_days(days: i64) -> i64 {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let year = year_of_era + era * 400;
+    if month_index >= 10 {
+        year + 1
+    } else {
+        year
+    }
+}
+
+pub fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse a UTC date `YYYY-MM-DD` or a UTC date and time `YYYY-MM-DDTHH:MM[:SS]`.
+pub fn parse_datetime(s: &str) -> ah::Result<SystemTime> {
+    let s = s.trim();
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let date: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = date[..] else {
+        return Err(err!("Invalid date. Expected YYYY-MM-DD"));
+    };
+    let year: i64 = year.parse()?;
+    let month: u32 = month.parse()?;
+    let day: u32 = day.parse()?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return Err(err!("Invalid date"));
+    }
+
+    let mut secs_of_day = 0;
+    if let Some(time) = time {
+        let time: Vec<&str> = time.split(':').collect();
+        let (hour, minute, second) = match time[..] {
+            [hour, minute] => (hour, minute, "0"),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return Err(err!("Invalid time. Expected HH:MM or HH:MM:SS")),
+        };
+        let hour: i64 = hour.parse()?;
+        let minute: i64 = minute.parse()?;
+        let second: i64 = second.parse()?;
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+            return Err(err!("Invalid time"));
+        }
+        secs_of_day = hour * 3600 + minute * 60 + second;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + secs_of_day;
+    let secs = u64::try_from(secs).map_err(|_| err!("Date is before 1970"))?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
 /// Check if the string is a decimal or hexadecimal number (prefix 0x).
 pub fn is_number(s: &str) -> bool {
     let s = s.trim();
@@ -15,6 +15,51 @@ pub enum MapItem {
     Values(Vec<String>),
 }
 
+/// Split `s` at every `sep` that is not enclosed in double quotes.
+///
+/// The quotes are kept in the parts.
+pub fn split_unquoted(s: &str, sep: char) -> ah::Result<Vec<&str>> {
+    let mut parts = Vec::with_capacity(8);
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..idx]);
+            start = idx + c.len_utf8();
+        }
+    }
+    if quoted {
+        return Err(err!("Unterminated quote."));
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+/// Find the last `sep` in `s` that is not enclosed in double quotes.
+///
+/// Quotes are counted from the end of `s`.
+pub fn rfind_unquoted(s: &str, sep: char) -> Option<usize> {
+    let mut quoted = false;
+    for (idx, c) in s.char_indices().rev() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            return Some(idx);
+        }
+    }
+    None
+}
+
+/// Trim `s` and remove the double quotes around it, if any.
+pub fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
 pub struct Map {
     items: Vec<MapItem>,
 }
@@ -25,7 +70,7 @@ impl FromStr for Map {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut items = Vec::with_capacity(8);
 
-        for item in s.split('/') {
+        for item in split_unquoted(s, '/')? {
             let item = if let Some(idx) = item.find(':') {
                 let chlen = ':'.len_utf8();
                 if idx < chlen {
@@ -35,18 +80,18 @@ impl FromStr for Map {
                 if key.is_empty() {
                     return Err(err!("Invalid item key."));
                 }
-                let value = &item[idx + chlen..];
-                if value.find(',').is_some() {
+                let values = split_unquoted(&item[idx + chlen..], ',')?;
+                if values.len() > 1 {
                     MapItem::KeyValues(
                         key.to_string(),
-                        value.split(',').map(|v| v.trim().to_string()).collect(),
+                        values.iter().map(|v| unquote(v).to_string()).collect(),
                     )
                 } else {
-                    MapItem::KeyValue(key.to_string(), value.trim().to_string())
+                    MapItem::KeyValue(key.to_string(), unquote(values[0]).to_string())
                 }
             } else {
-                let values = item.split(',');
-                let values: Vec<String> = values.map(|s| s.trim().to_string()).collect();
+                let values = split_unquoted(item, ',')?;
+                let values: Vec<String> = values.iter().map(|s| unquote(s).to_string()).collect();
                 MapItem::Values(values)
             };
             items.push(item);
@@ -62,4 +107,46 @@ impl Map {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_unquoted() {
+        assert_eq!(
+            split_unquoted(r#"a / b: "1/2" / c"#, '/').unwrap(),
+            ["a ", r#" b: "1/2" "#, " c"]
+        );
+        assert_eq!(split_unquoted("a", '/').unwrap(), ["a"]);
+        assert!(split_unquoted(r#"a / "b"#, '/').is_err());
+        assert_eq!(rfind_unquoted(r#"a / b: "1/2""#, '/'), Some(2));
+        assert_eq!(rfind_unquoted(r#""1/2""#, '/'), None);
+        assert_eq!(unquote(r#" "1/2" "#), "1/2");
+        assert_eq!(unquote(" 1 "), "1");
+    }
+
+    #[test]
+    fn test_map() {
+        let map: Map = r#"port: 22 / from: "10.0.0.0/8", 192.0.2.1 / tcp, udp"#
+            .parse()
+            .unwrap();
+        let items = map.items();
+        assert_eq!(items.len(), 3);
+        let MapItem::KeyValue(key, value) = &items[0] else {
+            panic!("port is not a KeyValue");
+        };
+        assert_eq!((&key[..], &value[..]), ("port", "22"));
+        let MapItem::KeyValues(key, values) = &items[1] else {
+            panic!("from is not a KeyValues");
+        };
+        assert_eq!(key, "from");
+        assert_eq!(values, &["10.0.0.0/8", "192.0.2.1"]);
+        let MapItem::Values(values) = &items[2] else {
+            panic!("tcp, udp is not a Values");
+        };
+        assert_eq!(values, &["tcp", "udp"]);
+        assert!(r#"from: "10.0.0.0/8"#.parse::<Map>().is_err());
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,209 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Ownership and permission checks of files that contain key material.
+
+use crate::{
+    key_file_base,
+    key_source::{split_key_value, KeySource},
+    ConfigProblem, Ini, IniPos, ProblemSeverity,
+};
+use anyhow::{self as ah, Context as _};
+use std::{
+    fs::read_to_string,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+};
+
+/// The effective user and group IDs of the running process.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct ProcessIds {
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+}
+
+/// Get the effective ID from a `Uid:` or `Gid:` line of `/proc/self/status`.
+fn parse_status_id(value: &str) -> ah::Result<u32> {
+    // The fields are: real, effective, saved set, filesystem.
+    let effective = value
+        .split_whitespace()
+        .nth(1)
+        .context("Get effective ID")?;
+    effective.parse().context("Parse effective ID")
+}
+
+/// Get the supplementary group IDs from a `Groups:` line of `/proc/self/status`.
+fn parse_status_groups(value: &str) -> ah::Result<Vec<u32>> {
+    value
+        .split_whitespace()
+        .map(|gid| gid.parse().context("Parse supplementary group ID"))
+        .collect()
+}
+
+impl ProcessIds {
+    /// Get the IDs of the running process.
+    fn get() -> ah::Result<Self> {
+        let data = read_to_string("/proc/self/status").context("Read /proc/self/status")?;
+        let mut uid = None;
+        let mut gid = None;
+        let mut groups = vec![];
+        for line in data.lines() {
+            if let Some(value) = line.strip_prefix("Uid:") {
+                uid = Some(parse_status_id(value)?);
+            } else if let Some(value) = line.strip_prefix("Gid:") {
+                gid = Some(parse_status_id(value)?);
+            } else if let Some(value) = line.strip_prefix("Groups:") {
+                groups = parse_status_groups(value)?;
+            }
+        }
+        Ok(Self {
+            uid: uid.context("Uid not found in /proc/self/status")?,
+            gid: gid.context("Gid not found in /proc/self/status")?,
+            groups,
+        })
+    }
+
+    /// Check whether the process is a member of the group `gid`.
+    fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// Check the owner and the mode of one file.
+///
+/// Returns a description of every problem found.
+fn check_mode(uid: u32, gid: u32, mode: u32, process: &ProcessIds) -> Vec<String> {
+    let mut problems = vec![];
+    // root may read a file that is private to another user,
+    // such as the firewall key of letmeind.
+    let private = mode & 0o077 == 0;
+    if uid != 0 && uid != process.uid && !(process.uid == 0 && private) {
+        problems.push(format!(
+            "it is owned by uid {uid} instead of root or uid {}",
+            process.uid
+        ));
+    }
+    if mode & 0o040 != 0 && !process.in_group(gid) {
+        problems.push(format!(
+            "it is readable by group {gid}, which is not a group of this process"
+        ));
+    }
+    if mode & 0o007 != 0 {
+        problems.push("it is accessible by all users".to_string());
+    }
+    problems
+}
+
+/// Check all files that contain literal keys in the `[KEYS]` section
+/// or the `firewall-key` in the `[GENERAL]` section
+/// and all key files referenced with `file:` by these options.
+///
+/// Every configuration file with a problem is reported at the position of its first key.
+/// Every key file with a problem is reported at the position of its reference.
+pub fn check_key_files(ini: &Ini, severity: ProblemSeverity) -> ah::Result<Vec<ConfigProblem>> {
+    let mut options: Vec<(&str, &str, &str)> = ini
+        .options_iter("KEYS")
+        .into_iter()
+        .flatten()
+        .filter_map(|(id, value)| Some(("KEYS", id.as_str(), split_key_value(value).ok()?.0)))
+        .collect();
+    if let Some(value) = ini.get("GENERAL", "firewall-key") {
+        options.push(("GENERAL", "firewall-key", value));
+    }
+
+    let mut files: Vec<(&IniPos, &str)> = vec![];
+    let mut key_files: Vec<(&IniPos, &str, PathBuf)> = vec![];
+    for (section, option, value) in options {
+        let Some(pos) = ini.option_pos(section, option) else {
+            continue;
+        };
+        match KeySource::parse(value, key_file_base(ini, section, option)) {
+            Ok(KeySource::Literal(_)) if pos.file().is_some() => files.push((pos, section)),
+            Ok(KeySource::File(path)) => key_files.push((pos, section, path)),
+            _ => (),
+        }
+    }
+    files.sort();
+    files.dedup_by(|a, b| a.0.file() == b.0.file());
+    key_files.sort();
+
+    let process = ProcessIds::get()?;
+    let mut problems = vec![];
+    let mut check = |pos: &IniPos, section: &str, path: &Path, what: &str| -> ah::Result<()> {
+        let meta = std::fs::metadata(path)
+            .with_context(|| format!("{}: Get file permissions", path.display()))?;
+        let reasons = check_mode(meta.uid(), meta.gid(), meta.mode(), &process);
+        if !reasons.is_empty() {
+            problems.push(ConfigProblem {
+                severity,
+                section: section.to_string(),
+                pos: Some(pos.clone()),
+                message: format!(
+                    "{what} contains secret keys, but {}.",
+                    reasons.join(" and ")
+                ),
+            });
+        }
+        Ok(())
+    };
+    for (pos, section) in files {
+        let path = pos.file().expect("Position has a file");
+        check(pos, section, path, "The file")?;
+    }
+    for (pos, section, path) in &key_files {
+        check(pos, section, path, &format!("The key file {path:?}"))?;
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status_id() {
+        assert_eq!(parse_status_id("\t1000\t1001\t1002\t1003").unwrap(), 1001);
+        assert!(parse_status_id("\t1000").is_err());
+        assert_eq!(
+            parse_status_groups("\t4 27 300 ").unwrap(),
+            vec![4, 27, 300]
+        );
+        assert!(parse_status_groups("\t").unwrap().is_empty());
+        assert!(parse_status_groups("\t4 x").is_err());
+    }
+
+    #[test]
+    fn test_check_mode() {
+        let process = ProcessIds {
+            uid: 100,
+            gid: 200,
+            groups: vec![300],
+        };
+        assert!(check_mode(0, 0, 0o100600, &process).is_empty());
+        assert!(check_mode(100, 0, 0o100600, &process).is_empty());
+        assert!(check_mode(0, 200, 0o100640, &process).is_empty());
+        assert_eq!(check_mode(300, 200, 0o100600, &process).len(), 1);
+        assert!(check_mode(0, 300, 0o100640, &process).is_empty());
+        assert_eq!(check_mode(0, 0, 0o100640, &process).len(), 1);
+        assert_eq!(check_mode(0, 0, 0o100644, &process).len(), 2);
+        assert_eq!(check_mode(0, 200, 0o100602, &process).len(), 1);
+
+        // root reads the private firewall key of another user.
+        let root = ProcessIds {
+            uid: 0,
+            gid: 0,
+            groups: vec![],
+        };
+        assert!(check_mode(100, 0, 0o100400, &root).is_empty());
+        assert_eq!(check_mode(100, 0, 0o100440, &root).len(), 1);
+        assert_eq!(check_mode(100, 0, 0o100404, &root).len(), 2);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,301 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Weekly time windows of resources and keys.
+
+use crate::TimeZone;
+use anyhow::{self as ah, format_err as err, Context as _};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+const DAY_NAMES: [(&str, &str); 7] = [
+    ("mon", "monday"),
+    ("tue", "tuesday"),
+    ("wed", "wednesday"),
+    ("thu", "thursday"),
+    ("fri", "friday"),
+    ("sat", "saturday"),
+    ("sun", "sunday"),
+];
+const ALL_DAYS: u8 = 0x7F;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Convert a [SystemTime] to seconds since 1970-01-01 UTC.
+fn to_secs(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs().try_into().unwrap_or(i64::MAX))
+        .unwrap_or(0)
+}
+
+/// Convert seconds since 1970-01-01 UTC to a [SystemTime].
+fn from_secs(secs: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs.max(0).unsigned_abs())
+}
+
+/// Parse a weekday name. Returns 0 for Monday.
+fn parse_day(s: &str) -> ah::Result<u32> {
+    let s = s.trim().to_lowercase();
+    DAY_NAMES
+        .iter()
+        .position(|(short, long)| s == *short || s == *long)
+        .map(|d| d as u32)
+        .ok_or_else(|| err!("Invalid day '{s}'"))
+}
+
+/// Parse `Mon` or a day range `Mon-Fri` into a weekday bit mask.
+fn parse_days(s: &str) -> ah::Result<u8> {
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+        None => {
+            let day = parse_day(s)?;
+            (day, day)
+        }
+    };
+    let mut days = 0;
+    let mut day = first;
+    loop {
+        days |= 1 << day;
+        if day == last {
+            break;
+        }
+        day = (day + 1) % 7;
+    }
+    Ok(days)
+}
+
+/// Parse `HH:MM` into minutes after midnight. `24:00` is allowed.
+fn parse_time(s: &str) -> ah::Result<i64> {
+    let (hour, minute) = s
+        .trim()
+        .split_once(':')
+        .context("Invalid time. Expected HH:MM")?;
+    let hour: i64 = hour.parse().context("Invalid hour")?;
+    let minute: i64 = minute.parse().context("Invalid minute")?;
+    if !(0..=24).contains(&hour) || !(0..60).contains(&minute) || (hour == 24 && minute != 0) {
+        return Err(err!("Invalid time '{s}'"));
+    }
+    Ok(hour * 60 + minute)
+}
+
+/// A weekly time window.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Window {
+    /// Bit mask of the weekdays the window starts on. Bit 0 is Monday.
+    days: u8,
+    /// Start of the window, in minutes after midnight.
+    start: i64,
+    /// End of the window, in minutes after midnight.
+    /// If `end` is not after `start`, then the window ends on the next day.
+    end: i64,
+}
+
+impl FromStr for Window {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, times)) => (parse_days(days)?, times),
+            None => (ALL_DAYS, s.trim()),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .context("Invalid time range. Expected HH:MM-HH:MM")?;
+        let start = parse_time(start)?;
+        let end = parse_time(end)?;
+        if start == end || start == MINUTES_PER_DAY {
+            return Err(err!("Invalid time range '{times}'"));
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+impl Window {
+    fn has_day(&self, weekday: i64) -> bool {
+        self.days & (1 << weekday.rem_euclid(7)) != 0
+    }
+
+    /// Get the end of the window that contains the `local` time, in local seconds.
+    /// Returns `None`, if `local` is outside of the window.
+    fn end_at(&self, local: i64) -> Option<i64> {
+        let day = local.div_euclid(SECS_PER_DAY);
+        // 1970-01-01 was a Thursday.
+        let weekday = day + 3;
+        let minute = local.rem_euclid(SECS_PER_DAY) / 60;
+        let midnight = day * SECS_PER_DAY;
+        if self.start < self.end {
+            if self.has_day(weekday) && (self.start..self.end).contains(&minute) {
+                return Some(midnight + self.end * 60);
+            }
+        } else if self.has_day(weekday) && minute >= self.start {
+            return Some(midnight + SECS_PER_DAY + self.end * 60);
+        } else if self.has_day(weekday - 1) && minute < self.end {
+            return Some(midnight + self.end * 60);
+        }
+        None
+    }
+}
+
+/// A schedule of weekly time windows.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl FromStr for Schedule {
+    type Err = ah::Error;
+
+    /// Parse a comma separated list of windows `[DAYS] HH:MM-HH:MM`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let windows = s
+            .split(',')
+            .map(|w| {
+                w.parse()
+                    .with_context(|| format!("Schedule window '{}'", w.trim()))
+            })
+            .collect::<ah::Result<Vec<_>>>()?;
+        Ok(Self { windows })
+    }
+}
+
+impl Schedule {
+    /// Get the end of the window that contains the UTC time `t`, in local seconds.
+    fn end_at(&self, tz: &TimeZone, t: i64) -> Option<i64> {
+        let local = t + tz.offset_at(t);
+        self.windows.iter().filter_map(|w| w.end_at(local)).max()
+    }
+
+    /// Check whether `now` is inside of one of the windows.
+    pub fn is_active_at(&self, tz: &TimeZone, now: SystemTime) -> bool {
+        self.end_at(tz, to_secs(now)).is_some()
+    }
+
+    /// Get the time at which the schedule stops being active.
+    /// Adjacent windows are joined.
+    /// Returns `None`, if `now` is outside of all windows.
+    pub fn active_until(&self, tz: &TimeZone, now: SystemTime) -> Option<SystemTime> {
+        let mut t = to_secs(now);
+        let offset = tz.offset_at(t);
+        let mut end_local = self.end_at(tz, t)?;
+        // Follow adjacent windows for at most one week.
+        for _ in 0..8 {
+            // Convert the local end time to UTC with the offset that is valid at that time.
+            let end = end_local - tz.offset_at(end_local - offset);
+            if end <= t {
+                break;
+            }
+            t = end;
+            let Some(next) = self.end_at(tz, t) else {
+                break;
+            };
+            end_local = next;
+        }
+        Some(from_secs(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_datetime;
+
+    fn t(s: &str) -> SystemTime {
+        parse_datetime(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_days("Mon").unwrap(), 0x01);
+        assert_eq!(parse_days("mon-fri").unwrap(), 0x1F);
+        assert_eq!(parse_days("Sat-Mon").unwrap(), 0x61);
+        assert_eq!(parse_days("Sunday").unwrap(), 0x40);
+        assert!(parse_days("Mo").is_err());
+        assert!(parse_days("Mon-").is_err());
+        assert!(parse_days("Monx").is_err());
+
+        let s: Schedule = "Mon-Fri 08:00-18:00, Sat 22:00-02:00, 12:00-13:00"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            s.windows,
+            [
+                Window {
+                    days: 0x1F,
+                    start: 480,
+                    end: 1080
+                },
+                Window {
+                    days: 0x20,
+                    start: 1320,
+                    end: 120
+                },
+                Window {
+                    days: ALL_DAYS,
+                    start: 720,
+                    end: 780
+                },
+            ]
+        );
+        assert!("Mon 08:00".parse::<Schedule>().is_err());
+        assert!("Mon 08:00-08:00".parse::<Schedule>().is_err());
+        assert!("Mon 08:00-24:01".parse::<Schedule>().is_err());
+        assert!("Mon 24:00-08:00".parse::<Schedule>().is_err());
+        assert!("Mon 8-18".parse::<Schedule>().is_err());
+        assert!("".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_active() {
+        let utc = TimeZone::default();
+        let s: Schedule = "Mon-Fri 08:00-18:00".parse().unwrap();
+        // 2025-01-06 is a Monday.
+        assert!(!s.is_active_at(&utc, t("2025-01-06T07:59:59")));
+        assert!(s.is_active_at(&utc, t("2025-01-06T08:00")));
+        assert!(s.is_active_at(&utc, t("2025-01-10T17:59:59")));
+        assert!(!s.is_active_at(&utc, t("2025-01-10T18:00")));
+        assert!(!s.is_active_at(&utc, t("2025-01-11T12:00")));
+        assert_eq!(
+            s.active_until(&utc, t("2025-01-06T12:00")),
+            Some(t("2025-01-06T18:00"))
+        );
+        assert_eq!(s.active_until(&utc, t("2025-01-06T19:00")), None);
+
+        // Overnight window.
+        let s: Schedule = "Sun 22:00-02:00".parse().unwrap();
+        assert!(s.is_active_at(&utc, t("2025-01-05T23:00")));
+        assert!(s.is_active_at(&utc, t("2025-01-06T01:59")));
+        assert!(!s.is_active_at(&utc, t("2025-01-06T02:00")));
+        assert!(!s.is_active_at(&utc, t("2025-01-06T23:00")));
+        assert_eq!(
+            s.active_until(&utc, t("2025-01-05T23:00")),
+            Some(t("2025-01-06T02:00"))
+        );
+
+        // Adjacent windows are joined.
+        let s: Schedule = "Fri 18:00-24:00, Sat 00:00-12:00".parse().unwrap();
+        assert_eq!(
+            s.active_until(&utc, t("2025-01-10T20:00")),
+            Some(t("2025-01-11T12:00"))
+        );
+
+        // Time zone with daylight saving time.
+        let cet = TimeZone::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        let s: Schedule = "Mon-Fri 08:00-18:00".parse().unwrap();
+        assert!(s.is_active_at(&cet, t("2025-01-06T07:00")));
+        assert!(!s.is_active_at(&cet, t("2025-07-07T05:59")));
+        assert!(s.is_active_at(&cet, t("2025-07-07T06:00")));
+        assert_eq!(
+            s.active_until(&cet, t("2025-07-07T12:00")),
+            Some(t("2025-07-07T16:00"))
+        );
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
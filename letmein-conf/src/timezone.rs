@@ -0,0 +1,387 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Time zones for the evaluation of schedules.
+
+use crate::parse::{days_from_civil, days_in_month, is_leap_year, year_from_days};
+use anyhow::{self as ah, format_err as err, Context as _};
+use std::path::Path;
+
+/// The system's time zone database.
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// The system's local time zone.
+const LOCALTIME_FILE: &str = "/etc/localtime";
+
+/// The day of the year of a daylight saving time transition.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TransitionDay {
+    /// `Jn`: Day 1 to 365. February 29 is never counted.
+    Julian(u16),
+    /// `n`: Day 0 to 365. February 29 is counted in leap years.
+    Zero(u16),
+    /// `Mm.w.d`: Weekday `d` (0 = Sunday) of week `w` (5 = last) of month `m`.
+    MonthWeekDay(u8, u8, u8),
+}
+
+impl TransitionDay {
+    /// Get the number of days since 1970-01-01 of the transition day in `year`.
+    fn days(&self, year: i64) -> i64 {
+        let jan1 = days_from_civil(year, 1, 1);
+        match *self {
+            Self::Julian(n) => {
+                let n = i64::from(n) - 1;
+                if is_leap_year(year) && n >= 59 {
+                    jan1 + n + 1
+                } else {
+                    jan1 + n
+                }
+            }
+            Self::Zero(n) => jan1 + i64::from(n),
+            Self::MonthWeekDay(month, week, weekday) => {
+                let month = month.into();
+                let first = days_from_civil(year, month, 1);
+                // 1970-01-01 was a Thursday.
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day =
+                    (i64::from(weekday) - first_weekday).rem_euclid(7) + (i64::from(week) - 1) * 7;
+                while day >= days_in_month(year, month).into() {
+                    day -= 7;
+                }
+                first + day
+            }
+        }
+    }
+}
+
+/// A daylight saving time transition.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Transition {
+    day: TransitionDay,
+    /// Local time of the transition, in seconds after midnight.
+    time: i64,
+}
+
+/// A time zone.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TimeZone {
+    /// Fixed offset to UTC, in seconds east of UTC.
+    Fixed(i64),
+    /// Standard time and daylight saving time.
+    Dst {
+        /// Standard time offset to UTC, in seconds east of UTC.
+        std_offset: i64,
+        /// Daylight saving time offset to UTC, in seconds east of UTC.
+        dst_offset: i64,
+        /// Start of the daylight saving time, in standard time.
+        start: Transition,
+        /// End of the daylight saving time, in daylight saving time.
+        end: Transition,
+    },
+}
+
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::Fixed(0)
+    }
+}
+
+/// Parse `[+-]hh[:mm[:ss]]` into seconds.
+fn parse_hms(s: &str, max_hours: i64) -> ah::Result<i64> {
+    let (sign, s) = match s.as_bytes().first() {
+        Some(b'-') => (-1, &s[1..]),
+        Some(b'+') => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let mut secs = 0;
+    let mut parts = 0;
+    for (i, part) in s.split(':').enumerate() {
+        if part.is_empty() || part.len() > 3 || !part.chars().all(|c| c.is_ascii_digit()) {
+            return Err(err!("Invalid time '{s}'"));
+        }
+        let value: i64 = part.parse()?;
+        let max = if i == 0 { max_hours } else { 59 };
+        if i > 2 || value > max {
+            return Err(err!("Invalid time '{s}'"));
+        }
+        secs = secs * 60 + value;
+        parts = i + 1;
+    }
+    for _ in parts..3 {
+        secs *= 60;
+    }
+    Ok(sign * secs)
+}
+
+/// Split a POSIX TZ time zone abbreviation from the beginning of `s`.
+fn split_tz_name(s: &str) -> ah::Result<(&str, &str)> {
+    let (name, rest) = if let Some(s) = s.strip_prefix('<') {
+        let end = s.find('>').context("Unterminated '<' in time zone name")?;
+        (&s[..end], &s[end + 1..])
+    } else {
+        let end = s
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(s.len());
+        s.split_at(end)
+    };
+    if name.len() < 3 {
+        return Err(err!("Invalid time zone name '{name}'"));
+    }
+    Ok((name, rest))
+}
+
+/// Split a POSIX TZ offset or time from the beginning of `s`.
+fn split_tz_time(s: &str) -> (&str, &str) {
+    let end = s
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == ':' || (i == 0 && (c == '+' || c == '-'))))
+        .map(|(i, _)| i)
+        .unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Parse a POSIX TZ transition `date[/time]`.
+fn parse_transition(s: &str) -> ah::Result<Transition> {
+    let (date, time) = match s.split_once('/') {
+        Some((date, time)) => (date, parse_hms(time, 167)?),
+        None => (s, 2 * 3600),
+    };
+    let number = |s: &str, min: u16, max: u16| -> ah::Result<u16> {
+        match s.parse() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(err!("Invalid transition date '{date}'")),
+        }
+    };
+    let day = if let Some(n) = date.strip_prefix('J') {
+        TransitionDay::Julian(number(n, 1, 365)?)
+    } else if let Some(mwd) = date.strip_prefix('M') {
+        let mwd: Vec<&str> = mwd.split('.').collect();
+        let [month, week, weekday] = mwd[..] else {
+            return Err(err!("Invalid transition date '{date}'"));
+        };
+        TransitionDay::MonthWeekDay(
+            number(month, 1, 12)? as u8,
+            number(week, 1, 5)? as u8,
+            number(weekday, 0, 6)? as u8,
+        )
+    } else {
+        TransitionDay::Zero(number(date, 0, 365)?)
+    };
+    Ok(Transition { day, time })
+}
+
+impl TimeZone {
+    /// Parse a POSIX TZ string, such as `CET-1CEST,M3.5.0,M10.5.0/3`.
+    pub(crate) fn parse_posix(s: &str) -> ah::Result<Self> {
+        let (_, rest) = split_tz_name(s)?;
+        let (std_offset, rest) = split_tz_time(rest);
+        // POSIX offsets are positive west of UTC.
+        let std_offset = -parse_hms(std_offset, 24)?;
+        if rest.is_empty() {
+            return Ok(Self::Fixed(std_offset));
+        }
+
+        let (_, rest) = split_tz_name(rest)?;
+        let (dst_offset, rest) = split_tz_time(rest);
+        let dst_offset = if dst_offset.is_empty() {
+            std_offset + 3600
+        } else {
+            -parse_hms(dst_offset, 24)?
+        };
+        // Without rules the POSIX default rules apply.
+        let rules = if rest.is_empty() {
+            "M3.2.0,M11.1.0"
+        } else {
+            rest.strip_prefix(',')
+                .with_context(|| format!("Invalid daylight saving time rules '{rest}'"))?
+        };
+        let Some((start, end)) = rules.split_once(',') else {
+            return Err(err!("Invalid daylight saving time rules '{rules}'"));
+        };
+        Ok(Self::Dst {
+            std_offset,
+            dst_offset,
+            start: parse_transition(start)?,
+            end: parse_transition(end)?,
+        })
+    }
+
+    /// Parse the time zone from a TZif file of the time zone database.
+    ///
+    /// Only the POSIX TZ footer of the file is used.
+    /// It describes the current rules of the time zone.
+    fn parse_tzif(data: &[u8]) -> ah::Result<Self> {
+        if data.len() < 5 || &data[..4] != b"TZif" || data[4] < b'2' {
+            return Err(err!("Not a TZif version 2+ file"));
+        }
+        let footer = data
+            .strip_suffix(b"\n")
+            .and_then(|data| data.rsplit(|&b| b == b'\n').next())
+            .context("No TZ string in TZif file")?;
+        let footer = std::str::from_utf8(footer).context("Invalid TZ string in TZif file")?;
+        if footer.is_empty() {
+            return Err(err!("Empty TZ string in TZif file"));
+        }
+        Self::parse_posix(footer).with_context(|| format!("TZ string '{footer}'"))
+    }
+
+    /// Read the time zone from a TZif file.
+    fn read_tzif(path: &Path) -> ah::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Read {path:?}"))?;
+        Self::parse_tzif(&data).with_context(|| format!("{path:?}"))
+    }
+
+    /// Parse a time zone specification.
+    ///
+    /// `local`, `UTC`, a fixed offset `+HH:MM` or `-HH:MM`
+    /// or a name from the time zone database, such as `Europe/Berlin`.
+    pub fn parse(s: &str) -> ah::Result<Self> {
+        let s = s.trim();
+        if s == "local" {
+            let path = Path::new(LOCALTIME_FILE);
+            if !path.exists() {
+                return Ok(Self::default());
+            }
+            return Self::read_tzif(path);
+        }
+        if s.eq_ignore_ascii_case("UTC") {
+            return Ok(Self::default());
+        }
+        if s.starts_with(['+', '-']) {
+            return Ok(Self::Fixed(parse_hms(s, 24)?));
+        }
+        let valid = !s.is_empty()
+            && s.split('/').all(|c| {
+                !c.is_empty()
+                    && !c.starts_with('.')
+                    && c.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_-+.".contains(c))
+            });
+        if !valid {
+            return Err(err!("Invalid time zone name '{s}'"));
+        }
+        Self::read_tzif(&Path::new(ZONEINFO_DIR).join(s))
+    }
+
+    /// Get the offset to UTC, in seconds east of UTC,
+    /// at the time `t` in seconds since 1970-01-01 UTC.
+    pub fn offset_at(&self, t: i64) -> i64 {
+        match *self {
+            Self::Fixed(offset) => offset,
+            Self::Dst {
+                std_offset,
+                dst_offset,
+                start,
+                end,
+            } => {
+                let year = year_from_days((t + std_offset).div_euclid(86400));
+                let start = start.day.days(year) * 86400 + start.time - std_offset;
+                let end = end.day.days(year) * 86400 + end.time - dst_offset;
+                let dst = if start < end {
+                    start <= t && t < end
+                } else {
+                    !(end <= t && t < start)
+                };
+                if dst {
+                    dst_offset
+                } else {
+                    std_offset
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> i64 {
+        crate::parse::parse_datetime(s)
+            .unwrap()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn test_parse_hms() {
+        assert_eq!(parse_hms("1", 24).unwrap(), 3600);
+        assert_eq!(parse_hms("-01:30", 24).unwrap(), -5400);
+        assert_eq!(parse_hms("+2:00:30", 24).unwrap(), 7230);
+        assert!(parse_hms("25", 24).is_err());
+        assert!(parse_hms("1:60", 24).is_err());
+        assert!(parse_hms("", 24).is_err());
+    }
+
+    #[test]
+    fn test_posix() {
+        assert_eq!(
+            TimeZone::parse_posix("<+0530>-5:30").unwrap(),
+            TimeZone::Fixed(5 * 3600 + 1800)
+        );
+        assert_eq!(
+            TimeZone::parse_posix("EST5").unwrap(),
+            TimeZone::Fixed(-5 * 3600)
+        );
+
+        let cet = TimeZone::parse_posix("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(cet.offset_at(t("2025-01-15")), 3600);
+        assert_eq!(cet.offset_at(t("2025-03-30T00:59:59")), 3600);
+        assert_eq!(cet.offset_at(t("2025-03-30T01:00")), 7200);
+        assert_eq!(cet.offset_at(t("2025-10-26T00:59:59")), 7200);
+        assert_eq!(cet.offset_at(t("2025-10-26T01:00")), 3600);
+
+        let us = TimeZone::parse_posix("EST5EDT").unwrap();
+        assert_eq!(us.offset_at(t("2025-03-09T06:59:59")), -5 * 3600);
+        assert_eq!(us.offset_at(t("2025-03-09T07:00")), -4 * 3600);
+        assert_eq!(us.offset_at(t("2025-11-02T06:00")), -5 * 3600);
+
+        // Southern hemisphere.
+        let nz = TimeZone::parse_posix("NZST-12NZDT,M9.5.0,M4.1.0/3").unwrap();
+        assert_eq!(nz.offset_at(t("2025-01-15")), 13 * 3600);
+        assert_eq!(nz.offset_at(t("2025-07-15")), 12 * 3600);
+
+        let julian = TimeZone::parse_posix("AAA0BBB,J60/0,J300").unwrap();
+        assert_eq!(julian.offset_at(t("2024-02-29T12:00")), 0);
+        assert_eq!(julian.offset_at(t("2024-03-01T12:00")), 3600);
+
+        assert!(TimeZone::parse_posix("C-1").is_err());
+        assert!(TimeZone::parse_posix("CET-1CEST,M3.5.0").is_err());
+        assert!(TimeZone::parse_posix("CET-1CEST,M13.5.0,M10.5.0").is_err());
+    }
+
+    #[test]
+    fn test_tzif() {
+        let mut data = b"TZif2".to_vec();
+        data.extend_from_slice(&[0; 40]);
+        data.extend_from_slice(b"\nCET-1CEST,M3.5.0,M10.5.0/3\n");
+        assert!(matches!(
+            TimeZone::parse_tzif(&data).unwrap(),
+            TimeZone::Dst {
+                std_offset: 3600,
+                dst_offset: 7200,
+                ..
+            }
+        ));
+        assert!(TimeZone::parse_tzif(b"TZif\0\n\n").is_err());
+        assert!(TimeZone::parse_tzif(b"TZif2\n\n").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(TimeZone::parse("UTC").unwrap(), TimeZone::Fixed(0));
+        assert_eq!(TimeZone::parse("+02:00").unwrap(), TimeZone::Fixed(7200));
+        assert_eq!(TimeZone::parse("-03:30").unwrap(), TimeZone::Fixed(-12600));
+        assert!(TimeZone::parse("../etc/passwd").is_err());
+        assert!(TimeZone::parse("Europe/../../x").is_err());
+        assert!(TimeZone::parse("").is_err());
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -15,10 +15,14 @@
 #![forbid(unsafe_code)]
 
 use anyhow::{self as ah, format_err as err, Context as _};
+use hmac::{Hmac, Mac as _};
+use letmein_proto::{secure_random, Key, ResourceId, UserId};
+use sha3::Sha3_256;
 use std::{
     future::Future,
     net::{IpAddr, Ipv4Addr},
 };
+use subtle::ConstantTimeEq as _;
 use tokio::io::ErrorKind;
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -30,6 +34,9 @@ use tokio::net::windows::named_pipe::{NamedPipeClient, NamedPipeServer};
 /// Firewall daemon Unix socket file name.
 pub const SOCK_FILE: &str = "letmeinfwd.sock";
 
+/// Firewall daemon administration Unix socket file name.
+pub const ADMIN_SOCK_FILE: &str = "letmeinfwd-admin.sock";
+
 /// The operation to perform on the firewall.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
 #[repr(u16)]
@@ -43,6 +50,18 @@ pub enum FirewallOperation {
     Open,
     /// Close a port.
     Close,
+    /// Request the list of all active leases.
+    ListLeases,
+    /// One entry of the lease list.
+    Lease,
+    /// Revoke the lease(s) of an address and a port.
+    Revoke,
+    /// Revoke all leases of an address.
+    RevokeAddr,
+    /// Drop all packets from an address.
+    Ban,
+    /// Authentication challenge with the initial nonce of the connection.
+    Challenge,
 }
 
 impl TryFrom<u16> for FirewallOperation {
@@ -53,11 +72,23 @@ impl TryFrom<u16> for FirewallOperation {
         const OPERATION_ACK: u16 = FirewallOperation::Ack as u16;
         const OPERATION_NACK: u16 = FirewallOperation::Nack as u16;
         const OPERATION_CLOSE: u16 = FirewallOperation::Close as u16;
+        const OPERATION_LISTLEASES: u16 = FirewallOperation::ListLeases as u16;
+        const OPERATION_LEASE: u16 = FirewallOperation::Lease as u16;
+        const OPERATION_REVOKE: u16 = FirewallOperation::Revoke as u16;
+        const OPERATION_REVOKEADDR: u16 = FirewallOperation::RevokeAddr as u16;
+        const OPERATION_BAN: u16 = FirewallOperation::Ban as u16;
+        const OPERATION_CHALLENGE: u16 = FirewallOperation::Challenge as u16;
         match value {
             OPERATION_OPEN => Ok(Self::Open),
             OPERATION_ACK => Ok(Self::Ack),
             OPERATION_NACK => Ok(Self::Nack),
             OPERATION_CLOSE => Ok(Self::Close),
+            OPERATION_LISTLEASES => Ok(Self::ListLeases),
+            OPERATION_LEASE => Ok(Self::Lease),
+            OPERATION_REVOKE => Ok(Self::Revoke),
+            OPERATION_REVOKEADDR => Ok(Self::RevokeAddr),
+            OPERATION_BAN => Ok(Self::Ban),
+            OPERATION_CHALLENGE => Ok(Self::Challenge),
             _ => Err(err!("Invalid FirewallMessage/Operation value")),
         }
     }
@@ -138,23 +169,157 @@ impl From<AddrType> for u16 {
 /// Size of the `addr` field in the message.
 const ADDR_SIZE: usize = 16;
 
+/// Size of the `nonce` field in the message.
+const NONCE_SIZE: usize = 16;
+
+/// Size of the `auth` field in the message.
+const AUTH_SIZE: usize = 32;
+
+/// Version of the firewall control message layout.
+///
+/// Increment this, if the layout of the message changes.
+const FWMSG_VERSION: u16 = 2;
+
 /// Size of the firewall control message.
-const FWMSG_SIZE: usize = 2 + 2 + 2 + 2 + ADDR_SIZE;
+const FWMSG_SIZE: usize =
+    2 + 2 + 2 + 2 + 2 + ADDR_SIZE + 4 + 8 + 8 + 4 + 4 + NONCE_SIZE + AUTH_SIZE;
+
+/// Byte offset of the `version` field in the firewall control message.
+const FWMSG_OFFS_VERSION: usize = 0;
 
 /// Byte offset of the `operation` field in the firewall control message.
-const FWMSG_OFFS_OPERATION: usize = 0;
+const FWMSG_OFFS_OPERATION: usize = 2;
 
 /// Byte offset of the `port_type` field in the firewall control message.
-const FWMSG_OFFS_PORT_TYPE: usize = 2;
+const FWMSG_OFFS_PORT_TYPE: usize = 4;
 
 /// Byte offset of the `port` field in the firewall control message.
-const FWMSG_OFFS_PORT: usize = 4;
+const FWMSG_OFFS_PORT: usize = 6;
 
 /// Byte offset of the `addr_type` field in the firewall control message.
-const FWMSG_OFFS_ADDR_TYPE: usize = 6;
+const FWMSG_OFFS_ADDR_TYPE: usize = 8;
 
 /// Byte offset of the `addr` field in the firewall control message.
-const FWMSG_OFFS_ADDR: usize = 8;
+const FWMSG_OFFS_ADDR: usize = 10;
+
+/// Byte offset of the `timeout` field in the firewall control message.
+const FWMSG_OFFS_TIMEOUT: usize = 26;
+
+/// Byte offset of the `packets` field in the firewall control message.
+const FWMSG_OFFS_PACKETS: usize = 30;
+
+/// Byte offset of the `bytes` field in the firewall control message.
+const FWMSG_OFFS_BYTES: usize = 38;
+
+/// Byte offset of the `user` field in the firewall control message.
+const FWMSG_OFFS_USER: usize = 46;
+
+/// Byte offset of the `resource` field in the firewall control message.
+const FWMSG_OFFS_RESOURCE: usize = 50;
+
+/// Byte offset of the `nonce` field in the firewall control message.
+const FWMSG_OFFS_NONCE: usize = 54;
+
+/// Byte offset of the `auth` field in the firewall control message.
+const FWMSG_OFFS_AUTH: usize = 70;
+
+/// A nonce of the message authentication.
+type Nonce = [u8; NONCE_SIZE];
+
+/// Message authentication of one firewall socket connection.
+///
+/// Every message carries a fresh random nonce and an HMAC over the message
+/// and the nonce of the previous message of the connection.
+/// The first nonce is sent by the firewall daemon in a
+/// [FirewallOperation::Challenge] message.
+/// That way both sides prove the knowledge of the shared key
+/// and recorded messages can't be replayed.
+pub struct MsgAuth {
+    key: Key,
+    /// The nonce of the previous message of the connection.
+    nonce: Option<Nonce>,
+}
+
+impl Drop for MsgAuth {
+    fn drop(&mut self) {
+        self.key.fill(0);
+        std::hint::black_box(&mut self.key);
+    }
+}
+
+impl MsgAuth {
+    /// Create a new message authentication with the shared `key`.
+    pub fn new(key: &Key) -> Self {
+        Self {
+            key: *key,
+            nonce: None,
+        }
+    }
+
+    /// Calculate the authentication token of the serialized message `buf`.
+    fn authenticate(&self, prev_nonce: &Nonce, buf: &[u8; FWMSG_SIZE]) -> [u8; AUTH_SIZE] {
+        let mut mac = Hmac::<Sha3_256>::new_from_slice(&self.key)
+            .expect("HMAC<SHA3-256> initialization failed");
+        mac.update(prev_nonce);
+        mac.update(&buf[..FWMSG_OFFS_AUTH]);
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Add a fresh nonce and the authentication token to the serialized message `buf`.
+    fn sign(&mut self, buf: &mut [u8; FWMSG_SIZE]) -> ah::Result<()> {
+        let prev_nonce = self
+            .nonce
+            .context("Authentication: No challenge received")?;
+        let nonce: Nonce = secure_random();
+        buf[FWMSG_OFFS_NONCE..FWMSG_OFFS_NONCE + NONCE_SIZE].copy_from_slice(&nonce);
+        let auth = self.authenticate(&prev_nonce, buf);
+        buf[FWMSG_OFFS_AUTH..FWMSG_OFFS_AUTH + AUTH_SIZE].copy_from_slice(&auth);
+        self.nonce = Some(nonce);
+        Ok(())
+    }
+
+    /// Check the authentication token of the serialized message `buf`.
+    fn verify(&mut self, buf: &[u8; FWMSG_SIZE]) -> ah::Result<()> {
+        let prev_nonce = self.nonce.context("Authentication: No challenge sent")?;
+        let auth = self.authenticate(&prev_nonce, buf);
+        if !bool::from(auth.ct_eq(&buf[FWMSG_OFFS_AUTH..FWMSG_OFFS_AUTH + AUTH_SIZE])) {
+            return Err(err!("Authentication: Message authentication failed"));
+        }
+        let mut nonce: Nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&buf[FWMSG_OFFS_NONCE..FWMSG_OFFS_NONCE + NONCE_SIZE]);
+        self.nonce = Some(nonce);
+        Ok(())
+    }
+
+    /// Send a challenge with the initial nonce of the connection.
+    /// This is the first message of the firewall daemon on a new connection.
+    pub async fn send_challenge(&mut self, stream: &mut impl Stream) -> ah::Result<()> {
+        let mut buf = FirewallMessage::new_challenge().msg_serialize()?;
+        let nonce: Nonce = secure_random();
+        buf[FWMSG_OFFS_NONCE..FWMSG_OFFS_NONCE + NONCE_SIZE].copy_from_slice(&nonce);
+        send_buf(stream, &buf).await?;
+        self.nonce = Some(nonce);
+        Ok(())
+    }
+
+    /// Receive the challenge with the initial nonce of the connection.
+    pub async fn recv_challenge(&mut self, stream: &mut impl Stream) -> ah::Result<()> {
+        let Some(buf) = recv_buf(stream).await? else {
+            return Err(err!("Connection terminated"));
+        };
+        let msg = FirewallMessage::try_msg_deserialize(&buf)?;
+        if msg.operation() != FirewallOperation::Challenge {
+            return Err(err!(
+                "Authentication: Expected a challenge, but received {:?}",
+                msg.operation()
+            ));
+        }
+        let mut nonce: Nonce = [0; NONCE_SIZE];
+        nonce.copy_from_slice(&buf[FWMSG_OFFS_NONCE..FWMSG_OFFS_NONCE + NONCE_SIZE]);
+        self.nonce = Some(nonce);
+        Ok(())
+    }
+}
 
 /// A message to control the firewall.
 #[derive(PartialEq, Eq, Debug, Default)]
@@ -164,6 +329,11 @@ pub struct FirewallMessage {
     port: u16,
     addr_type: AddrType,
     addr: [u8; ADDR_SIZE],
+    timeout: u32,
+    packets: u64,
+    bytes: u64,
+    user: UserId,
+    resource: ResourceId,
 }
 
 /// Convert an `IpAddr` to the `operation` and `addr` fields of a firewall control message.
@@ -189,8 +359,15 @@ fn octets_to_addr(addr_type: AddrType, addr: &[u8; ADDR_SIZE]) -> IpAddr {
 }
 
 impl FirewallMessage {
-    /// Construct a new message that requests installing a firewall-port-open rule.
-    pub fn new_open(addr: IpAddr, port_type: PortType, port: u16) -> Self {
+    /// Construct a new message that requests installing a firewall-port-open rule
+    /// for the `resource` on behalf of `user`.
+    pub fn new_open(
+        addr: IpAddr,
+        port_type: PortType,
+        port: u16,
+        user: UserId,
+        resource: ResourceId,
+    ) -> Self {
         let (addr_type, addr) = addr_to_octets(addr);
         Self {
             operation: FirewallOperation::Open,
@@ -198,11 +375,38 @@ impl FirewallMessage {
             port,
             addr_type,
             addr,
+            user,
+            resource,
+            ..Default::default()
         }
     }
 
-    /// Construct a new message that requests removing a firewall-port-open rule.
-    pub fn new_close(addr: IpAddr, port_type: PortType, port: u16) -> Self {
+    /// Construct a new message that requests installing a firewall-port-open rule
+    /// for the `resource` on behalf of `user`
+    /// whose lease expires after at most `timeout` seconds.
+    pub fn new_open_timeout(
+        addr: IpAddr,
+        port_type: PortType,
+        port: u16,
+        user: UserId,
+        resource: ResourceId,
+        timeout: u32,
+    ) -> Self {
+        Self {
+            timeout: timeout.max(1),
+            ..Self::new_open(addr, port_type, port, user, resource)
+        }
+    }
+
+    /// Construct a new message that requests removing a firewall-port-open rule
+    /// for the `resource` on behalf of `user`.
+    pub fn new_close(
+        addr: IpAddr,
+        port_type: PortType,
+        port: u16,
+        user: UserId,
+        resource: ResourceId,
+    ) -> Self {
         let (addr_type, addr) = addr_to_octets(addr);
         Self {
             operation: FirewallOperation::Close,
@@ -210,6 +414,81 @@ impl FirewallMessage {
             port,
             addr_type,
             addr,
+            user,
+            resource,
+            ..Default::default()
+        }
+    }
+
+    /// Construct a new message that requests the list of all active leases.
+    ///
+    /// The firewall daemon answers with one [FirewallOperation::Lease] message
+    /// per lease, followed by an acknowledge message.
+    pub fn new_list_leases() -> Self {
+        Self {
+            operation: FirewallOperation::ListLeases,
+            ..Default::default()
+        }
+    }
+
+    /// Construct a new lease list entry message.
+    ///
+    /// `timeout` is the number of seconds until the lease expires.
+    /// `packets` and `bytes` are the firewall counters of the lease.
+    pub fn new_lease(
+        addr: IpAddr,
+        port_type: PortType,
+        port: u16,
+        timeout: u32,
+        packets: u64,
+        bytes: u64,
+    ) -> Self {
+        let (addr_type, addr) = addr_to_octets(addr);
+        Self {
+            operation: FirewallOperation::Lease,
+            port_type,
+            port,
+            addr_type,
+            addr,
+            timeout,
+            packets,
+            bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Construct a new message that requests revoking the lease(s) of an address and a port.
+    pub fn new_revoke(addr: IpAddr, port_type: PortType, port: u16) -> Self {
+        let (addr_type, addr) = addr_to_octets(addr);
+        Self {
+            operation: FirewallOperation::Revoke,
+            port_type,
+            port,
+            addr_type,
+            addr,
+            ..Default::default()
+        }
+    }
+
+    /// Construct a new message that requests revoking all leases of an address.
+    pub fn new_revoke_addr(addr: IpAddr) -> Self {
+        let (addr_type, addr) = addr_to_octets(addr);
+        Self {
+            operation: FirewallOperation::RevokeAddr,
+            addr_type,
+            addr,
+            ..Default::default()
+        }
+    }
+
+    /// Construct a new message that requests banning an address.
+    pub fn new_ban(addr: IpAddr) -> Self {
+        let (addr_type, addr) = addr_to_octets(addr);
+        Self {
+            operation: FirewallOperation::Ban,
+            addr_type,
+            addr,
+            ..Default::default()
         }
     }
 
@@ -229,6 +508,15 @@ impl FirewallMessage {
         }
     }
 
+    /// Construct a new authentication challenge message.
+    /// The nonce is added by [MsgAuth::send_challenge].
+    fn new_challenge() -> Self {
+        Self {
+            operation: FirewallOperation::Challenge,
+            ..Default::default()
+        }
+    }
+
     /// Get the operation type from this message.
     pub fn operation(&self) -> FirewallOperation {
         self.operation
@@ -237,16 +525,68 @@ impl FirewallMessage {
     /// Get the port number from this message.
     pub fn port(&self) -> Option<(PortType, u16)> {
         match self.operation {
-            FirewallOperation::Open | FirewallOperation::Close => Some((self.port_type, self.port)),
-            FirewallOperation::Ack | FirewallOperation::Nack => None,
+            FirewallOperation::Open
+            | FirewallOperation::Close
+            | FirewallOperation::Lease
+            | FirewallOperation::Revoke => Some((self.port_type, self.port)),
+            FirewallOperation::Ack
+            | FirewallOperation::Nack
+            | FirewallOperation::ListLeases
+            | FirewallOperation::RevokeAddr
+            | FirewallOperation::Ban
+            | FirewallOperation::Challenge => None,
         }
     }
 
     /// Get the `IpAddr` from this message.
     pub fn addr(&self) -> Option<IpAddr> {
         match self.operation {
-            FirewallOperation::Open | FirewallOperation::Close => Some(octets_to_addr(self.addr_type, &self.addr)),
-            FirewallOperation::Ack | FirewallOperation::Nack => None,
+            FirewallOperation::Open
+            | FirewallOperation::Close
+            | FirewallOperation::Lease
+            | FirewallOperation::Revoke
+            | FirewallOperation::RevokeAddr
+            | FirewallOperation::Ban => Some(octets_to_addr(self.addr_type, &self.addr)),
+            FirewallOperation::Ack
+            | FirewallOperation::Nack
+            | FirewallOperation::ListLeases
+            | FirewallOperation::Challenge => None,
+        }
+    }
+
+    /// Get the number of seconds until the lease expires from a lease list entry message.
+    ///
+    /// Get the maximum number of seconds of the lease from an open message.
+    /// `None`, if the lease of the open message is not limited.
+    pub fn timeout(&self) -> Option<u32> {
+        match self.operation {
+            FirewallOperation::Lease => Some(self.timeout),
+            FirewallOperation::Open if self.timeout != 0 => Some(self.timeout),
+            _ => None,
+        }
+    }
+
+    /// Get the user that requested the operation from an open or close message.
+    pub fn user(&self) -> Option<UserId> {
+        match self.operation {
+            FirewallOperation::Open | FirewallOperation::Close => Some(self.user),
+            _ => None,
+        }
+    }
+
+    /// Get the resource of the port from an open or close message.
+    pub fn resource(&self) -> Option<ResourceId> {
+        match self.operation {
+            FirewallOperation::Open | FirewallOperation::Close => Some(self.resource),
+            _ => None,
+        }
+    }
+
+    /// Get the packet and byte counters from a lease list entry message.
+    pub fn counters(&self) -> Option<(u64, u64)> {
+        match self.operation {
+            FirewallOperation::Lease => Some((self.packets, self.bytes)),
+            _ => None,
         }
     }
 
@@ -260,12 +600,28 @@ impl FirewallMessage {
             buf[0..2].copy_from_slice(&value.to_be_bytes());
         }
 
+        #[inline]
+        fn serialize_u32(buf: &mut [u8], value: u32) {
+            buf[0..4].copy_from_slice(&value.to_be_bytes());
+        }
+
+        #[inline]
+        fn serialize_u64(buf: &mut [u8], value: u64) {
+            buf[0..8].copy_from_slice(&value.to_be_bytes());
+        }
+
         let mut buf = [0; FWMSG_SIZE];
+        serialize_u16(&mut buf[FWMSG_OFFS_VERSION..], FWMSG_VERSION);
         serialize_u16(&mut buf[FWMSG_OFFS_OPERATION..], self.operation.into());
         serialize_u16(&mut buf[FWMSG_OFFS_PORT_TYPE..], self.port_type.into());
         serialize_u16(&mut buf[FWMSG_OFFS_PORT..], self.port);
         serialize_u16(&mut buf[FWMSG_OFFS_ADDR_TYPE..], self.addr_type.into());
         buf[FWMSG_OFFS_ADDR..FWMSG_OFFS_ADDR + ADDR_SIZE].copy_from_slice(&self.addr);
+        serialize_u32(&mut buf[FWMSG_OFFS_TIMEOUT..], self.timeout);
+        serialize_u64(&mut buf[FWMSG_OFFS_PACKETS..], self.packets);
+        serialize_u64(&mut buf[FWMSG_OFFS_BYTES..], self.bytes);
+        serialize_u32(&mut buf[FWMSG_OFFS_USER..], self.user.into());
+        serialize_u32(&mut buf[FWMSG_OFFS_RESOURCE..], self.resource.into());
 
         Ok(buf)
     }
@@ -284,11 +640,34 @@ impl FirewallMessage {
             Ok(u16::from_be_bytes(buf[0..2].try_into()?))
         }
 
+        #[inline]
+        fn deserialize_u32(buf: &[u8]) -> ah::Result<u32> {
+            Ok(u32::from_be_bytes(buf[0..4].try_into()?))
+        }
+
+        #[inline]
+        fn deserialize_u64(buf: &[u8]) -> ah::Result<u64> {
+            Ok(u64::from_be_bytes(buf[0..8].try_into()?))
+        }
+
+        let version = deserialize_u16(&buf[FWMSG_OFFS_VERSION..])?;
+        if version != FWMSG_VERSION {
+            return Err(err!(
+                "Deserialize: Message version {version} is not supported. \
+                 Expected version {FWMSG_VERSION}. \
+                 Please ensure that letmeind and letmeinfwd are of the same version."
+            ));
+        }
         let operation = deserialize_u16(&buf[FWMSG_OFFS_OPERATION..])?;
         let port_type = deserialize_u16(&buf[FWMSG_OFFS_PORT_TYPE..])?;
         let port = deserialize_u16(&buf[FWMSG_OFFS_PORT..])?;
         let addr_type = deserialize_u16(&buf[FWMSG_OFFS_ADDR_TYPE..])?;
         let addr = &buf[FWMSG_OFFS_ADDR..FWMSG_OFFS_ADDR + ADDR_SIZE];
+        let timeout = deserialize_u32(&buf[FWMSG_OFFS_TIMEOUT..])?;
+        let packets = deserialize_u64(&buf[FWMSG_OFFS_PACKETS..])?;
+        let bytes = deserialize_u64(&buf[FWMSG_OFFS_BYTES..])?;
+        let user = deserialize_u32(&buf[FWMSG_OFFS_USER..])?;
+        let resource = deserialize_u32(&buf[FWMSG_OFFS_RESOURCE..])?;
 
         Ok(Self {
             operation: operation.try_into()?,
@@ -296,53 +675,91 @@ impl FirewallMessage {
             port,
             addr_type: addr_type.try_into()?,
             addr: addr.try_into()?,
+            timeout,
+            packets,
+            bytes,
+            user: user.into(),
+            resource: resource.into(),
         })
     }
 
     /// Send this message over a [Stream].
     pub async fn send(&self, stream: &mut impl Stream) -> ah::Result<()> {
-        let txbuf = self.msg_serialize()?;
-        let mut txcount = 0;
-        loop {
-            stream.writable().await.context("Socket polling (tx)")?;
-            match stream.try_write(&txbuf[txcount..]) {
-                Ok(n) => {
-                    txcount += n;
-                    assert!(txcount <= txbuf.len());
-                    if txcount == txbuf.len() {
-                        return Ok(());
-                    }
-                }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(e) => {
-                    return Err(err!("Socket write: {e}"));
+        send_buf(stream, &self.msg_serialize()?).await
+    }
+
+    /// Send this message with authentication over a [Stream].
+    pub async fn send_auth(&self, stream: &mut impl Stream, auth: &mut MsgAuth) -> ah::Result<()> {
+        let mut txbuf = self.msg_serialize()?;
+        auth.sign(&mut txbuf)?;
+        send_buf(stream, &txbuf).await
+    }
+
+    /// Try to receive a message from a [Stream].
+    pub async fn recv(stream: &mut impl Stream) -> ah::Result<Option<Self>> {
+        match recv_buf(stream).await? {
+            Some(rxbuf) => Ok(Some(Self::try_msg_deserialize(&rxbuf)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Try to receive a message with authentication from a [Stream].
+    pub async fn recv_auth(
+        stream: &mut impl Stream,
+        auth: &mut MsgAuth,
+    ) -> ah::Result<Option<Self>> {
+        match recv_buf(stream).await? {
+            Some(rxbuf) => {
+                auth.verify(&rxbuf)?;
+                Ok(Some(Self::try_msg_deserialize(&rxbuf)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Send a serialized message over a [Stream].
+async fn send_buf(stream: &mut impl Stream, txbuf: &[u8; FWMSG_SIZE]) -> ah::Result<()> {
+    let mut txcount = 0;
+    loop {
+        stream.writable().await.context("Socket polling (tx)")?;
+        match stream.try_write(&txbuf[txcount..]) {
+            Ok(n) => {
+                txcount += n;
+                assert!(txcount <= txbuf.len());
+                if txcount == txbuf.len() {
+                    return Ok(());
                 }
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => {
+                return Err(err!("Socket write: {e}"));
+            }
         }
     }
+}
 
-    /// Try to receive a message from a [Stream].
-    pub async fn recv(stream: &mut impl Stream) -> ah::Result<Option<Self>> {
-        let mut rxbuf = [0; FWMSG_SIZE];
-        let mut rxcount = 0;
-        loop {
-            stream.readable().await.context("Socket polling (rx)")?;
-            match stream.try_read(&mut rxbuf[rxcount..]) {
-                Ok(n) => {
-                    if n == 0 {
-                        return Ok(None);
-                    }
-                    rxcount += n;
-                    assert!(rxcount <= FWMSG_SIZE);
-                    if rxcount == FWMSG_SIZE {
-                        return Ok(Some(Self::try_msg_deserialize(&rxbuf)?));
-                    }
+/// Try to receive a serialized message from a [Stream].
+async fn recv_buf(stream: &mut impl Stream) -> ah::Result<Option<[u8; FWMSG_SIZE]>> {
+    let mut rxbuf = [0; FWMSG_SIZE];
+    let mut rxcount = 0;
+    loop {
+        stream.readable().await.context("Socket polling (rx)")?;
+        match stream.try_read(&mut rxbuf[rxcount..]) {
+            Ok(n) => {
+                if n == 0 {
+                    return Ok(None);
                 }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(e) => {
-                    return Err(err!("Socket read: {e}"));
+                rxcount += n;
+                assert!(rxcount <= FWMSG_SIZE);
+                if rxcount == FWMSG_SIZE {
+                    return Ok(Some(rxbuf));
                 }
             }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => {
+                return Err(err!("Socket read: {e}"));
+            }
         }
     }
 }
@@ -395,27 +812,49 @@ mod tests {
 
     #[test]
     fn test_msg_open_v6() {
-        let msg = FirewallMessage::new_open("::1".parse().unwrap(), PortType::Tcp, 0x9876);
+        let msg = FirewallMessage::new_open(
+            "::1".parse().unwrap(),
+            PortType::Tcp,
+            0x9876,
+            1.into(),
+            2.into(),
+        );
         assert_eq!(msg.operation(), FirewallOperation::Open);
         assert_eq!(msg.port(), Some((PortType::Tcp, 0x9876)));
         assert_eq!(msg.addr(), Some("::1".parse().unwrap()));
+        assert_eq!(msg.user(), Some(1.into()));
+        assert_eq!(msg.resource(), Some(2.into()));
         check_ser_de(&msg);
 
         let msg = FirewallMessage::new_open(
             "0102:0304:0506:0708:090A:0B0C:0D0E:0F10".parse().unwrap(),
             PortType::Tcp,
             0x9876,
+            0x11223344.into(),
+            0x55667788.into(),
         );
         let bytes = msg.msg_serialize().unwrap();
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x02, // operation
                 0x00, 0x00, // port_type
                 0x98, 0x76, // port
                 0x00, 0x00, // addr_type
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // addr
                 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x11, 0x22, 0x33, 0x44, // user
+                0x55, 0x66, 0x77, 0x88, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
 
@@ -423,17 +862,31 @@ mod tests {
             "0102:0304:0506:0708:090A:0B0C:0D0E:0F10".parse().unwrap(),
             PortType::Udp,
             0x9876,
+            0x11223344.into(),
+            0x55667788.into(),
         );
         let bytes = msg.msg_serialize().unwrap();
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x02, // operation
                 0x00, 0x01, // port_type
                 0x98, 0x76, // port
                 0x00, 0x00, // addr_type
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // addr
                 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x11, 0x22, 0x33, 0x44, // user
+                0x55, 0x66, 0x77, 0x88, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
 
@@ -441,41 +894,185 @@ mod tests {
             "0102:0304:0506:0708:090A:0B0C:0D0E:0F10".parse().unwrap(),
             PortType::TcpUdp,
             0x9876,
+            0x11223344.into(),
+            0x55667788.into(),
         );
         let bytes = msg.msg_serialize().unwrap();
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x02, // operation
                 0x00, 0x02, // port_type
                 0x98, 0x76, // port
                 0x00, 0x00, // addr_type
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // addr
                 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x11, 0x22, 0x33, 0x44, // user
+                0x55, 0x66, 0x77, 0x88, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
     }
 
     #[test]
     fn test_msg_open_v4() {
-        let msg = FirewallMessage::new_open("1.2.3.4".parse().unwrap(), PortType::Tcp, 0x9876);
+        let msg = FirewallMessage::new_open(
+            "1.2.3.4".parse().unwrap(),
+            PortType::Tcp,
+            0x9876,
+            0x11223344.into(),
+            0x55667788.into(),
+        );
         assert_eq!(msg.operation(), FirewallOperation::Open);
         assert_eq!(msg.port(), Some((PortType::Tcp, 0x9876)));
         assert_eq!(msg.addr(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(msg.timeout(), None);
+        assert_eq!(msg.user(), Some(0x11223344.into()));
+        assert_eq!(msg.resource(), Some(0x55667788.into()));
         check_ser_de(&msg);
 
         let bytes = msg.msg_serialize().unwrap();
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x02, // operation
                 0x00, 0x00, // port_type
                 0x98, 0x76, // port
                 0x00, 0x01, // addr_type
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
                 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x11, 0x22, 0x33, 0x44, // user
+                0x55, 0x66, 0x77, 0x88, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
+
+        let msg = FirewallMessage::new_open_timeout(
+            "1.2.3.4".parse().unwrap(),
+            PortType::Tcp,
+            0x9876,
+            1.into(),
+            2.into(),
+            0x01020304,
+        );
+        assert_eq!(msg.timeout(), Some(0x01020304));
+        check_ser_de(&msg);
+        let bytes = msg.msg_serialize().unwrap();
+        assert_eq!(bytes[26..30], [0x01, 0x02, 0x03, 0x04]);
+
+        // A zero timeout would mean "not limited".
+        let msg = FirewallMessage::new_open_timeout(
+            "1.2.3.4".parse().unwrap(),
+            PortType::Tcp,
+            0x9876,
+            1.into(),
+            2.into(),
+            0,
+        );
+        assert_eq!(msg.timeout(), Some(1));
+    }
+
+    #[test]
+    fn test_msg_close() {
+        let msg = FirewallMessage::new_close(
+            "1.2.3.4".parse().unwrap(),
+            PortType::Udp,
+            0x9876,
+            0x11223344.into(),
+            0x55667788.into(),
+        );
+        assert_eq!(msg.operation(), FirewallOperation::Close);
+        assert_eq!(msg.port(), Some((PortType::Udp, 0x9876)));
+        assert_eq!(msg.addr(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(msg.timeout(), None);
+        assert_eq!(msg.user(), Some(0x11223344.into()));
+        assert_eq!(msg.resource(), Some(0x55667788.into()));
+        check_ser_de(&msg);
+
+        let bytes = msg.msg_serialize().unwrap();
+        assert_eq!(bytes[2..4], [0x00, 0x03]);
+        assert_eq!(
+            bytes[46..54],
+            [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]
+        );
+    }
+
+    #[test]
+    fn test_msg_version() {
+        let mut bytes = FirewallMessage::new_ack().msg_serialize().unwrap();
+        assert_eq!(bytes[0..2], [0x00, 0x02]);
+        bytes[1] = 0x01;
+        assert!(FirewallMessage::try_msg_deserialize(&bytes).is_err());
+        bytes[1] = 0x00;
+        assert!(FirewallMessage::try_msg_deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_msg_auth() {
+        let key = [0x42; 32];
+        let mut client = MsgAuth::new(&key);
+        let mut server = MsgAuth::new(&key);
+
+        // No challenge yet.
+        let mut buf = FirewallMessage::new_ack().msg_serialize().unwrap();
+        assert!(client.sign(&mut buf).is_err());
+
+        let challenge: Nonce = secure_random();
+        client.nonce = Some(challenge);
+        server.nonce = Some(challenge);
+
+        let msg = FirewallMessage::new_open(
+            "1.2.3.4".parse().unwrap(),
+            PortType::Tcp,
+            42,
+            1.into(),
+            2.into(),
+        );
+        let mut request = msg.msg_serialize().unwrap();
+        client.sign(&mut request).unwrap();
+        assert_ne!(request[FWMSG_OFFS_AUTH..], [0; AUTH_SIZE]);
+        server.verify(&request).unwrap();
+        assert_eq!(FirewallMessage::try_msg_deserialize(&request).unwrap(), msg);
+
+        let mut reply = FirewallMessage::new_ack().msg_serialize().unwrap();
+        server.sign(&mut reply).unwrap();
+        client.verify(&reply).unwrap();
+
+        // A replayed message is rejected.
+        assert!(server.verify(&request).is_err());
+
+        // A modified message is rejected.
+        let mut request = msg.msg_serialize().unwrap();
+        client.sign(&mut request).unwrap();
+        let mut modified = request;
+        modified[FWMSG_OFFS_PORT + 1] ^= 1;
+        assert!(server.verify(&modified).is_err());
+        server.verify(&request).unwrap();
+
+        // A message with a different key is rejected.
+        let mut other = MsgAuth::new(&[0x43; 32]);
+        other.nonce = server.nonce;
+        let mut request = msg.msg_serialize().unwrap();
+        other.sign(&mut request).unwrap();
+        assert!(server.verify(&request).is_err());
     }
 
     #[test]
@@ -490,12 +1087,24 @@ mod tests {
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x01, // operation
                 0x00, 0x00, // port_type
                 0x00, 0x00, // port
                 0x00, 0x00, // addr_type
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x00, 0x00, 0x00, 0x00, // user
+                0x00, 0x00, 0x00, 0x00, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
     }
@@ -512,15 +1121,117 @@ mod tests {
         assert_eq!(
             bytes,
             [
+                0x00, 0x02, // version
                 0x00, 0x00, // operation
                 0x00, 0x00, // port_type
                 0x00, 0x00, // port
                 0x00, 0x00, // addr_type
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
+                0x00, 0x00, 0x00, 0x00, // timeout
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // packets
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bytes
+                0x00, 0x00, 0x00, 0x00, // user
+                0x00, 0x00, 0x00, 0x00, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
             ]
         );
     }
+
+    #[test]
+    fn test_msg_list_leases() {
+        let msg = FirewallMessage::new_list_leases();
+        assert_eq!(msg.operation(), FirewallOperation::ListLeases);
+        assert_eq!(msg.port(), None);
+        assert_eq!(msg.addr(), None);
+        assert_eq!(msg.timeout(), None);
+        assert_eq!(msg.counters(), None);
+        assert_eq!(msg.user(), None);
+        assert_eq!(msg.resource(), None);
+        check_ser_de(&msg);
+    }
+
+    #[test]
+    fn test_msg_lease() {
+        let msg = FirewallMessage::new_lease(
+            "1.2.3.4".parse().unwrap(),
+            PortType::TcpUdp,
+            0x9876,
+            0x01020304,
+            0x1112131415161718,
+            0x2122232425262728,
+        );
+        assert_eq!(msg.operation(), FirewallOperation::Lease);
+        assert_eq!(msg.port(), Some((PortType::TcpUdp, 0x9876)));
+        assert_eq!(msg.addr(), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(msg.timeout(), Some(0x01020304));
+        assert_eq!(
+            msg.counters(),
+            Some((0x1112131415161718, 0x2122232425262728))
+        );
+        check_ser_de(&msg);
+
+        let bytes = msg.msg_serialize().unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x00, 0x02, // version
+                0x00, 0x05, // operation
+                0x00, 0x02, // port_type
+                0x98, 0x76, // port
+                0x00, 0x01, // addr_type
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // addr
+                0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, // addr
+                0x01, 0x02, 0x03, 0x04, // timeout
+                0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, // packets
+                0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, // bytes
+                0x00, 0x00, 0x00, 0x00, // user
+                0x00, 0x00, 0x00, 0x00, // resource
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // nonce
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // auth
+            ]
+        );
+    }
+
+    #[test]
+    fn test_msg_revoke() {
+        let msg = FirewallMessage::new_revoke("::1".parse().unwrap(), PortType::Udp, 42);
+        assert_eq!(msg.operation(), FirewallOperation::Revoke);
+        assert_eq!(msg.port(), Some((PortType::Udp, 42)));
+        assert_eq!(msg.addr(), Some("::1".parse().unwrap()));
+        assert_eq!(msg.timeout(), None);
+        check_ser_de(&msg);
+
+        let msg = FirewallMessage::new_revoke_addr("1.2.3.4".parse().unwrap());
+        assert_eq!(msg.operation(), FirewallOperation::RevokeAddr);
+        assert_eq!(msg.port(), None);
+        assert_eq!(msg.addr(), Some("1.2.3.4".parse().unwrap()));
+        check_ser_de(&msg);
+
+        let bytes = msg.msg_serialize().unwrap();
+        assert_eq!(bytes[2..4], [0x00, 0x07]);
+    }
+
+    #[test]
+    fn test_msg_ban() {
+        let msg = FirewallMessage::new_ban("1.2.3.4".parse().unwrap());
+        assert_eq!(msg.operation(), FirewallOperation::Ban);
+        assert_eq!(msg.port(), None);
+        assert_eq!(msg.addr(), Some("1.2.3.4".parse().unwrap()));
+        check_ser_de(&msg);
+
+        let bytes = msg.msg_serialize().unwrap();
+        assert_eq!(bytes[2..4], [0x00, 0x08]);
+    }
 }
 
 // vim: ts=4 sw=4 expandtab
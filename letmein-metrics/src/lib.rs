@@ -0,0 +1,327 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! This crate implements the metrics of the `letmein` daemons.
+//!
+//! The metrics are served in the Prometheus text exposition format
+//! over a minimal HTTP responder on a loopback TCP port or on a Unix socket.
+
+#![forbid(unsafe_code)]
+
+use anyhow::{self as ah, format_err as err, Context as _};
+use letmein_conf::MetricsListen;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs::{metadata, remove_file, set_permissions, Permissions},
+    future::Future,
+    os::unix::fs::{FileTypeExt as _, PermissionsExt as _},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::{TcpListener, UnixListener},
+    time::timeout,
+};
+
+/// Timeout for receiving the request and sending the response.
+const IO_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Maximum size of the HTTP request header.
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// The type of a metric.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MetricKind {
+    /// Monotonically increasing value.
+    Counter,
+    /// Value that can go up and down.
+    Gauge,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// Label set of one metric sample.
+type Labels = Vec<(&'static str, String)>;
+
+/// All samples of one metric name.
+#[derive(Debug)]
+struct Family {
+    kind: MetricKind,
+    help: &'static str,
+    samples: BTreeMap<Labels, f64>,
+}
+
+/// Escape a label value for the text exposition format.
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Registry of metrics.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    families: Mutex<BTreeMap<&'static str, Family>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Register a metric `name` of the given `kind`.
+    ///
+    /// Registered metrics without labels are rendered with value 0
+    /// until they are updated for the first time.
+    pub fn register(&self, name: &'static str, kind: MetricKind, help: &'static str) {
+        let mut families = self.families.lock().expect("Metrics lock poisoned");
+        families.entry(name).or_insert_with(|| Family {
+            kind,
+            help,
+            samples: BTreeMap::new(),
+        });
+    }
+
+    fn update(
+        &self,
+        name: &'static str,
+        labels: &[(&'static str, &str)],
+        f: impl FnOnce(&mut f64),
+    ) {
+        let mut families = self.families.lock().expect("Metrics lock poisoned");
+        let Some(family) = families.get_mut(name) else {
+            panic!("Metric '{name}' is not registered.");
+        };
+        let labels = labels.iter().map(|(k, v)| (*k, (*v).to_string())).collect();
+        f(family.samples.entry(labels).or_default());
+    }
+
+    /// Increment the counter `name` with the given `labels` by one.
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    /// Increment the counter `name` with the given `labels` by `value`.
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.update(name, labels, |v| *v += value);
+    }
+
+    /// Set the metric `name` with the given `labels` to `value`.
+    ///
+    /// This is used for gauges and for counters that are maintained elsewhere.
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.update(name, labels, |v| *v = value);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let families = self.families.lock().expect("Metrics lock poisoned");
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let _ = writeln!(out, "# HELP {name} {}", family.help);
+            let _ = writeln!(out, "# TYPE {name} {}", family.kind.as_str());
+            if family.samples.is_empty() {
+                let _ = writeln!(out, "{name} 0");
+            }
+            for (labels, value) in &family.samples {
+                if labels.is_empty() {
+                    let _ = writeln!(out, "{name} {value}");
+                } else {
+                    let labels: Vec<String> = labels
+                        .iter()
+                        .map(|(k, v)| format!("{k}=\"{}\"", escape_label_value(v)))
+                        .collect();
+                    let _ = writeln!(out, "{name}{{{}}} {value}", labels.join(","));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Read the HTTP request header and send the `body` as response.
+async fn respond(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), body: &str) -> ah::Result<()> {
+    // Read the request header. The request itself is ignored.
+    let mut buf = Vec::with_capacity(512);
+    let mut chunk = [0_u8; 512];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(err!("Request too big"));
+        }
+    }
+
+    let response = format!(
+        "HTTP/1.0 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Remove an existing socket at `path` from a previous run.
+///
+/// Anything else than a socket is never removed.
+fn remove_socket_file(path: &Path) -> ah::Result<()> {
+    if let Ok(meta) = metadata(path) {
+        if meta.file_type().is_socket() {
+            remove_file(path).context("Remove existing metrics socket")?;
+        } else {
+            return Err(err!(
+                "The metrics socket path {path:?} exists and is not a socket."
+            ));
+        }
+    }
+    Ok(())
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Metrics HTTP server.
+pub struct MetricsServer {
+    listener: Listener,
+}
+
+impl MetricsServer {
+    /// Bind the metrics server to `listen`.
+    ///
+    /// Returns `None`, if the metrics server is disabled.
+    pub async fn bind(listen: &MetricsListen) -> ah::Result<Option<Self>> {
+        let listener = match listen {
+            MetricsListen::Off => return Ok(None),
+            MetricsListen::Tcp(addr) => Listener::Tcp(
+                TcpListener::bind(addr)
+                    .await
+                    .context("Bind metrics TCP socket")?,
+            ),
+            MetricsListen::Unix(path) => {
+                remove_socket_file(path)?;
+                let listener = UnixListener::bind(path).context("Bind metrics Unix socket")?;
+                // Only the owner may query the metrics.
+                set_permissions(path, Permissions::from_mode(0o600))
+                    .context("Set metrics socket mode")?;
+                Listener::Unix(listener)
+            }
+        };
+        Ok(Some(Self { listener }))
+    }
+
+    /// Serve metrics requests forever.
+    ///
+    /// `render` is called for every request to generate the response body.
+    pub async fn serve<F, Fut>(&self, render: F) -> ah::Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = String>,
+    {
+        loop {
+            let result = match &self.listener {
+                Listener::Tcp(listener) => {
+                    let (mut stream, _) = listener.accept().await?;
+                    let body = render().await;
+                    timeout(IO_TIMEOUT, respond(&mut stream, &body)).await
+                }
+                Listener::Unix(listener) => {
+                    let (mut stream, _) = listener.accept().await?;
+                    let body = render().await;
+                    timeout(IO_TIMEOUT, respond(&mut stream, &body)).await
+                }
+            };
+            match result {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => eprintln!("Metrics request error: {e}"),
+                Err(_) => eprintln!("Metrics request timed out."),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let m = Metrics::new();
+        m.register("test_knocks_total", MetricKind::Counter, "Knocks.");
+        m.register("test_leases", MetricKind::Gauge, "Leases.");
+        m.register("test_errors_total", MetricKind::Counter, "Errors.");
+        m.inc("test_knocks_total", &[("resource", "00000001")]);
+        m.inc("test_knocks_total", &[("resource", "00000001")]);
+        m.inc("test_knocks_total", &[("resource", "a\"b")]);
+        m.set("test_leases", &[], 3.0);
+        assert_eq!(
+            m.render(),
+            "# HELP test_errors_total Errors.\n\
+             # TYPE test_errors_total counter\n\
+             test_errors_total 0\n\
+             # HELP test_knocks_total Knocks.\n\
+             # TYPE test_knocks_total counter\n\
+             test_knocks_total{resource=\"00000001\"} 2\n\
+             test_knocks_total{resource=\"a\\\"b\"} 1\n\
+             # HELP test_leases Leases.\n\
+             # TYPE test_leases gauge\n\
+             test_leases 3\n"
+        );
+    }
+
+    #[test]
+    fn test_remove_socket_file() {
+        let dir = std::env::temp_dir().join(format!("letmein-metrics-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Missing path.
+        let path = dir.join("metrics.sock");
+        remove_socket_file(&path).unwrap();
+
+        // Stale socket.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        remove_socket_file(&path).unwrap();
+        assert!(!path.exists());
+
+        // Any other file must not be removed.
+        let path = dir.join("metrics.conf");
+        std::fs::write(&path, "data").unwrap();
+        assert!(remove_socket_file(&path).is_err());
+        assert!(path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -22,7 +22,7 @@ use hmac::{Hmac, Mac as _};
 use sha3::Sha3_256;
 use subtle::ConstantTimeEq as _;
 
-pub use crate::socket::{NetSocket, UdpDispatcher};
+pub use crate::socket::{NetSocket, UdpDispatcher, UdpDispatcherStats};
 
 /// Internal debugging.
 const DEBUG: bool = false;
@@ -57,7 +57,7 @@ const ZERO_AUTH: Auth = [0; AUTH_SIZE];
 /// Identification number of a resource.
 ///
 /// Used in the wire protocol and in the configuration file.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
 pub struct ResourceId(u32);
 
 /// Identification number of a user (and a key).
@@ -202,6 +202,37 @@ impl From<Operation> for u32 {
     }
 }
 
+/// The reason of a [Operation::GoAway] rejection.
+///
+/// The reason is transmitted in the otherwise unused `auth` field of the message.
+/// Servers that don't send a reason transmit [GoAwayReason::Unspecified].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum GoAwayReason {
+    /// No specific reason is given.
+    Unspecified = 0,
+
+    /// The knock was authenticated, but the resource or the key
+    /// can't be used at this time of the day or week.
+    Schedule = 1,
+}
+
+impl From<u32> for GoAwayReason {
+    fn from(value: u32) -> Self {
+        const REASON_SCHEDULE: u32 = GoAwayReason::Schedule as u32;
+        match value {
+            REASON_SCHEDULE => Self::Schedule,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl From<GoAwayReason> for u32 {
+    fn from(reason: GoAwayReason) -> u32 {
+        reason as _
+    }
+}
+
 /// letmeind message size, in bytes.
 /// All message types have the same size.
 pub const MSG_SIZE: usize = 4 + 4 + 4 + 4 + SALT_SIZE + AUTH_SIZE;
@@ -248,6 +279,25 @@ impl Message {
         }
     }
 
+    /// Create a new [Operation::GoAway] message with a `reason`.
+    pub fn new_go_away(user: UserId, resource: ResourceId, reason: GoAwayReason) -> Self {
+        let mut msg = Self::new(Operation::GoAway, user, resource);
+        let reason: u32 = reason.into();
+        msg.auth[0..4].copy_from_slice(&reason.to_be_bytes());
+        msg
+    }
+
+    /// Get the reason of an [Operation::GoAway] message.
+    ///
+    /// Returns `None`, if this is not an [Operation::GoAway] message.
+    pub fn go_away_reason(&self) -> Option<GoAwayReason> {
+        if self.operation() != Operation::GoAway {
+            return None;
+        }
+        let reason = u32::from_be_bytes(self.auth[0..4].try_into().expect("auth is 4+ bytes"));
+        Some(reason.into())
+    }
+
     /// Get the [Operation] of this message.
     pub fn operation(&self) -> Operation {
         self.operation
@@ -621,7 +671,22 @@ mod tests {
         assert_eq!(msg.user(), 0x0F52E045.into());
         assert_eq!(msg.resource(), 0x9AF4EFA0.into());
         assert_eq!(msg.auth, [0; 32]);
+        assert_eq!(msg.go_away_reason(), Some(GoAwayReason::Unspecified));
+        check_ser_de(&msg);
+
+        let msg =
+            Message::new_go_away(0x0F52E045.into(), 0x9AF4EFA0.into(), GoAwayReason::Schedule);
+        assert_eq!(msg.operation(), Operation::GoAway);
+        assert_eq!(msg.auth[0..4], [0, 0, 0, 1]);
+        assert_eq!(msg.go_away_reason(), Some(GoAwayReason::Schedule));
         check_ser_de(&msg);
+
+        let mut msg = Message::new(Operation::GoAway, 0.into(), 0.into());
+        msg.auth[3] = 0xFF;
+        assert_eq!(msg.go_away_reason(), Some(GoAwayReason::Unspecified));
+
+        let msg = Message::new(Operation::Knock, 0.into(), 0.into());
+        assert_eq!(msg.go_away_reason(), None);
     }
 
     #[test]
@@ -40,6 +40,16 @@ struct UdpConn<const MSG_SIZE: usize, const Q_SIZE: usize> {
     accepted: bool,
 }
 
+/// Statistics of a [UdpDispatcher].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UdpDispatcherStats {
+    /// Number of connections that were dropped due to a RX queue overflow.
+    pub rx_queue_overflows: u64,
+
+    /// Number of connections that were dropped due to the connection limit.
+    pub conn_overflows: u64,
+}
+
 /// Very simple "connection" tracking for UDP.
 ///
 /// Tracking is purely based on the peer's IP address and source port.
@@ -63,6 +73,9 @@ struct UdpDispatcherRx<const MSG_SIZE: usize, const Q_SIZE: usize> {
 
     /// The number of queued datagrams in all connections.
     nr_queued_dgrams: usize,
+
+    /// Overflow statistics.
+    stats: UdpDispatcherStats,
 }
 
 impl<const MSG_SIZE: usize, const Q_SIZE: usize> UdpDispatcherRx<MSG_SIZE, Q_SIZE> {
@@ -73,6 +86,7 @@ impl<const MSG_SIZE: usize, const Q_SIZE: usize> UdpDispatcherRx<MSG_SIZE, Q_SIZ
             conn: HashMap::new(),
             max_nr_conn,
             nr_queued_dgrams: 0,
+            stats: Default::default(),
         }
     }
 
@@ -104,6 +118,7 @@ impl<const MSG_SIZE: usize, const Q_SIZE: usize> UdpDispatcherRx<MSG_SIZE, Q_SIZ
                 assert!(conn.rx_queue.len() <= Q_SIZE);
                 if conn.rx_queue.len() == Q_SIZE {
                     self.conn.remove(&peer_addr); // Close connection.
+                    self.stats.rx_queue_overflows += 1;
                     return Err(err!("UDP socket read: RX queue overflow (max={}).", Q_SIZE));
                 }
                 conn.rx_queue.push_back(buf);
@@ -113,6 +128,7 @@ impl<const MSG_SIZE: usize, const Q_SIZE: usize> UdpDispatcherRx<MSG_SIZE, Q_SIZ
                 // we exceeded the maximum number of connections.
                 if self.conn.len() > self.max_nr_conn {
                     self.conn.remove(&peer_addr); // Close connection.
+                    self.stats.conn_overflows += 1;
                     return Err(err!(
                         "UDP socket read: Too many connections (max={}).",
                         self.max_nr_conn
@@ -320,6 +336,11 @@ impl<const MSG_SIZE: usize, const Q_SIZE: usize> UdpDispatcher<MSG_SIZE, Q_SIZE>
     pub async fn disconnect(&self, peer_addr: SocketAddr) {
         self.rx.lock().await.disconnect(peer_addr);
     }
+
+    /// Get the overflow statistics.
+    pub async fn stats(&self) -> UdpDispatcherStats {
+        self.rx.lock().await.stats
+    }
 }
 
 /// Socket abstraction for sending and receiving data
@@ -31,7 +31,17 @@ macro_rules! sys {
 
 #[cfg(has_seccomp_support)]
 fn seccomp_cond(idx: u8, value: u64, bit_width: u8) -> ah::Result<seccompiler::SeccompCondition> {
-    use seccompiler::{SeccompCmpArgLen, SeccompCmpOp, SeccompCondition};
+    seccomp_cond_op(idx, seccompiler::SeccompCmpOp::Eq, value, bit_width)
+}
+
+#[cfg(has_seccomp_support)]
+fn seccomp_cond_op(
+    idx: u8,
+    op: seccompiler::SeccompCmpOp,
+    value: u64,
+    bit_width: u8,
+) -> ah::Result<seccompiler::SeccompCondition> {
+    use seccompiler::{SeccompCmpArgLen, SeccompCondition};
 
     let bit_width = match bit_width {
         PTR => {
@@ -55,7 +65,7 @@ fn seccomp_cond(idx: u8, value: u64, bit_width: u8) -> ah::Result<seccompiler::S
         bit_width => panic!("seccomp_cond: Invalid bit_width: {bit_width}"),
     };
 
-    Ok(SeccompCondition::new(idx, arglen, SeccompCmpOp::Eq, value)?)
+    Ok(SeccompCondition::new(idx, arglen, op, value)?)
 }
 
 #[cfg(has_seccomp_support)]
@@ -94,6 +104,7 @@ pub enum Allow {
     SetSockOpt { level_optname: Option<(i32, i32)> },
     Access,
     Open,
+    OpenReadOnly,
     Read,
     Write,
     Ioctl { op: Option<u32> },
@@ -109,6 +120,7 @@ pub enum Allow {
     Clone,
     Exec,
     Wait,
+    Kill,
     GetRlimit,
     Uname,
     Pidfd,
@@ -135,7 +147,7 @@ impl Filter {
     pub fn compile_for_arch(allow: &[Allow], deny_action: Action, arch: &str) -> ah::Result<Self> {
         assert!(!allow.is_empty());
 
-        use seccompiler::{SeccompAction, SeccompFilter, SeccompRule};
+        use seccompiler::{SeccompAction, SeccompCmpOp, SeccompFilter, SeccompRule};
         use std::collections::BTreeMap;
 
         type RulesMap = BTreeMap<i64, Vec<SeccompRule>>;
@@ -288,6 +300,21 @@ impl Filter {
                     add_sys(&mut map, sys!(SYS_open));
                     add_sys(&mut map, sys!(SYS_openat));
                 }
+                Allow::OpenReadOnly => {
+                    // Open files without write access, creation or truncation.
+                    let flags = (libc::O_ACCMODE | libc::O_CREAT | libc::O_TRUNC) as u64;
+                    let read_only = |idx| -> ah::Result<SeccompRule> {
+                        Ok(SeccompRule::new(vec![seccomp_cond_op(
+                            idx,
+                            SeccompCmpOp::MaskedEq(flags),
+                            0,
+                            32,
+                        )?])?)
+                    };
+                    #[cfg(target_arch = "x86_64")]
+                    add_sys_args_match(&mut map, sys!(SYS_open), read_only(1)?);
+                    add_sys_args_match(&mut map, sys!(SYS_openat), read_only(2)?);
+                }
                 Allow::Read => {
                     add_sys(&mut map, sys!(SYS_pread64));
                     add_sys(&mut map, sys!(SYS_preadv2));
@@ -368,6 +395,9 @@ impl Filter {
                 Allow::Wait => {
                     add_sys(&mut map, sys!(SYS_wait4));
                 }
+                Allow::Kill => {
+                    add_sys(&mut map, sys!(SYS_kill));
+                }
                 Allow::GetRlimit => {
                     add_sys_args_match(
                         &mut map,
@@ -9,7 +9,7 @@
 use crate::resolver::{resolve, ResMode};
 use anyhow::{self as ah, format_err as err, Context as _};
 use letmein_conf::ControlPort;
-use letmein_proto::{Message, MsgNetSocket, MsgUdpDispatcher, Operation};
+use letmein_proto::{GoAwayReason, Message, MsgNetSocket, MsgUdpDispatcher, Operation};
 use std::{
     net::{Ipv4Addr, Ipv6Addr},
     sync::Arc,
@@ -87,8 +87,14 @@ impl Client {
         let Some(reply) = reply else {
             return Err(err!("Connection terminated"));
         };
-        if reply.operation() == Operation::GoAway {
-            return Err(err!("The server rejected the request"));
+        if let Some(reason) = reply.go_away_reason() {
+            return Err(match reason {
+                GoAwayReason::Unspecified => err!("The server rejected the request"),
+                GoAwayReason::Schedule => err!(
+                    "The server rejected the request: \
+                     The resource can't be opened at this time (schedule)"
+                ),
+            });
         }
         if reply.operation() != expect_operation {
             return Err(err!(
@@ -258,6 +258,9 @@ async fn async_main(opts: Opts) -> ah::Result<()> {
     let mut conf = Config::new(ConfigVariant::Client);
     conf.load(&opts.get_config())
         .context("Configuration file")?;
+    for warning in conf.warnings() {
+        eprintln!("{warning}");
+    }
     let conf = Arc::new(conf);
 
     // Install `seccomp` rules, if required.
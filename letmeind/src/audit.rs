@@ -0,0 +1,204 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Structured audit log of the knock decisions.
+
+use crate::firewall_client::PortType;
+use anyhow::{self as ah, Context as _};
+use letmein_conf::{AuditLog, Config};
+use letmein_proto::{Operation, ResourceId, UserId};
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write as _,
+    net::SocketAddr,
+    os::unix::net::UnixDatagram,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// The systemd journal native protocol socket.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The authentication stage a connection has reached.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuditAuth {
+    /// Not authenticated.
+    None,
+    /// Basic (not replay-safe) authentication passed.
+    Basic,
+    /// Full challenge-response authentication passed.
+    Full,
+}
+
+/// The firewall lease resulting from a sequence.
+#[derive(Clone, Copy, Debug)]
+pub struct AuditLease {
+    pub port_type: PortType,
+    pub port: u16,
+    /// Lease time for opened ports. `None` for closed ports.
+    pub timeout: Option<Duration>,
+}
+
+/// One audit event. There is one event per control connection.
+#[derive(Debug)]
+pub struct AuditEvent {
+    pub peer_addr: SocketAddr,
+    pub l4proto: &'static str,
+    pub user: Option<UserId>,
+    /// The name of the `[KEYS]` entry that authenticated the user.
+    pub key: Option<String>,
+    pub resource: Option<ResourceId>,
+    pub operation: Option<Operation>,
+    pub auth: AuditAuth,
+    pub error: Option<String>,
+    pub lease: Option<AuditLease>,
+}
+
+impl AuditEvent {
+    /// Convert the event to its JSON representation.
+    fn to_json(&self) -> Value {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let auth = match self.auth {
+            AuditAuth::None => "none",
+            AuditAuth::Basic => "basic",
+            AuditAuth::Full => "full",
+        };
+        let operation = self.operation.map(|op| match op {
+            Operation::Knock => "knock".to_string(),
+            Operation::Close => "close".to_string(),
+            op => format!("{op:?}").to_lowercase(),
+        });
+        let lease = self.lease.map(|lease| {
+            let protocol = match lease.port_type {
+                PortType::Tcp => "tcp",
+                PortType::Udp => "udp",
+                PortType::TcpUdp => "tcp,udp",
+            };
+            json!({
+                "port": lease.port,
+                "protocol": protocol,
+                "timeout": lease.timeout.map(|t| t.as_secs()),
+            })
+        });
+        json!({
+            "time": time,
+            "peer": self.peer_addr.ip().to_string(),
+            "peer_port": self.peer_addr.port(),
+            "l4proto": self.l4proto,
+            "user": self.user.map(|u| u.to_string()),
+            "key": self.key,
+            "resource": self.resource.map(|r| r.to_string()),
+            "operation": operation,
+            "auth": auth,
+            "result": if self.error.is_none() { "ok" } else { "error" },
+            "error": self.error,
+            "lease": lease,
+        })
+    }
+}
+
+enum Sink {
+    Off,
+    Journal(UnixDatagram),
+    File(File),
+}
+
+/// Audit event writer.
+///
+/// The log destination is opened on construction,
+/// so this must be created before the seccomp rules are installed.
+pub struct Audit {
+    sink: Mutex<Sink>,
+}
+
+impl Audit {
+    pub fn new(conf: &Config) -> ah::Result<Self> {
+        let sink = match conf.audit_log() {
+            AuditLog::Off => Sink::Off,
+            AuditLog::Journal => {
+                let sock = UnixDatagram::unbound().context("Create journal socket")?;
+                sock.connect(JOURNAL_SOCKET)
+                    .context("Connect to the systemd journal")?;
+                Sink::Journal(sock)
+            }
+            AuditLog::File(path) => Sink::File(
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .with_context(|| format!("Open audit log file {path:?}"))?,
+            ),
+        };
+        Ok(Self {
+            sink: Mutex::new(sink),
+        })
+    }
+
+    /// Write one audit event.
+    pub fn log(&self, event: &AuditEvent) {
+        let mut sink = self.sink.lock().expect("Audit lock poisoned");
+        let result = match &mut *sink {
+            Sink::Off => return,
+            Sink::Journal(sock) => {
+                // Native journal protocol. The JSON line does not contain newlines.
+                let dgram = format!(
+                    "MESSAGE={}\nPRIORITY=6\nSYSLOG_IDENTIFIER=letmeind\nLETMEIN_AUDIT=1\n",
+                    event.to_json()
+                );
+                sock.send(dgram.as_bytes()).map(|_| ())
+            }
+            Sink::File(file) => file.write_all(format!("{}\n", event.to_json()).as_bytes()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write audit event: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let event = AuditEvent {
+            peer_addr: "[2001:db8::1]:4000".parse().unwrap(),
+            l4proto: "TCP",
+            user: Some(0x12.into()),
+            key: Some("00000012.new".to_string()),
+            resource: Some(0xAB.into()),
+            operation: Some(Operation::Knock),
+            auth: AuditAuth::Full,
+            error: None,
+            lease: Some(AuditLease {
+                port_type: PortType::Tcp,
+                port: 22,
+                timeout: Some(Duration::from_secs(600)),
+            }),
+        };
+        let value = event.to_json();
+        assert_eq!(value["peer"], json!("2001:db8::1"));
+        assert_eq!(value["user"], json!("00000012"));
+        assert_eq!(value["key"], json!("00000012.new"));
+        assert_eq!(value["resource"], json!("000000AB"));
+        assert_eq!(value["operation"], json!("knock"));
+        assert_eq!(value["auth"], json!("full"));
+        assert_eq!(value["result"], json!("ok"));
+        assert_eq!(
+            value["lease"],
+            json!({"port": 22, "protocol": "tcp", "timeout": 600})
+        );
+        assert!(!value.to_string().contains('\n'));
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,123 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Tracking of failed authentications and banning of source addresses.
+
+use crate::limit::normalize_addr;
+use letmein_conf::Config;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::Instant,
+};
+
+/// Maximum number of tracked source addresses.
+const MAX_TRACKED_ADDRS: usize = 1 << 16;
+
+/// Failure history of one source address.
+#[derive(Default)]
+struct BanEntry {
+    /// Time stamps of the failures within the ban window.
+    failures: VecDeque<Instant>,
+    /// If banned: The end of the ban.
+    banned_until: Option<Instant>,
+}
+
+impl BanEntry {
+    /// Remove all failures that are older than the ban window.
+    fn prune(&mut self, conf: &Config, now: Instant) {
+        while let Some(&first) = self.failures.front() {
+            if now.saturating_duration_since(first) > conf.ban_window() {
+                self.failures.pop_front();
+            } else {
+                break;
+            }
+        }
+        if let Some(until) = self.banned_until {
+            if now >= until {
+                self.banned_until = None;
+            }
+        }
+    }
+
+    /// Check whether this entry carries no information anymore.
+    fn is_empty(&self) -> bool {
+        self.failures.is_empty() && self.banned_until.is_none()
+    }
+}
+
+/// List of failed authentications and banned source addresses.
+#[derive(Default)]
+pub struct BanList {
+    entries: Mutex<HashMap<IpAddr, BanEntry>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Check whether `addr` is currently banned.
+    pub fn is_banned(&self, conf: &Config, addr: IpAddr) -> bool {
+        if conf.ban_threshold() == 0 {
+            return false;
+        }
+        let addr = normalize_addr(addr);
+        let now = Instant::now();
+        let entries = self.entries.lock().expect("BanList lock poisoned");
+        entries
+            .get(&addr)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Record a failed authentication of `addr`.
+    ///
+    /// Returns `true`, if this failure caused a new ban of `addr`.
+    pub fn record_failure(&self, conf: &Config, addr: IpAddr) -> bool {
+        let threshold = conf.ban_threshold();
+        if threshold == 0 {
+            return false;
+        }
+        let addr = normalize_addr(addr);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("BanList lock poisoned");
+
+        // Don't let the table grow without bounds.
+        if entries.len() >= MAX_TRACKED_ADDRS && !entries.contains_key(&addr) {
+            entries.retain(|_, entry| {
+                entry.prune(conf, now);
+                !entry.is_empty()
+            });
+            if entries.len() >= MAX_TRACKED_ADDRS {
+                // Still full. Forget the addresses that are not banned.
+                entries.retain(|_, entry| entry.banned_until.is_some());
+            }
+            if entries.len() >= MAX_TRACKED_ADDRS {
+                return false;
+            }
+        }
+
+        let entry = entries.entry(addr).or_default();
+        entry.prune(conf, now);
+        if entry.banned_until.is_some() {
+            return false;
+        }
+        entry.failures.push_back(now);
+        if entry.failures.len() >= threshold.try_into().unwrap_or(usize::MAX) {
+            entry.failures.clear();
+            entry.banned_until = Some(now + conf.ban_duration());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
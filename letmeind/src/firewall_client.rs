@@ -7,81 +7,149 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use anyhow::{self as ah, format_err as err, Context as _};
-use letmein_fwproto::{FirewallMessage, FirewallOperation, SOCK_FILE};
-use std::{net::IpAddr, path::Path};
+use letmein_fwproto::{FirewallMessage, FirewallOperation, MsgAuth, SOCK_FILE};
+use letmein_proto::{Key, ResourceId, UserId};
+use std::{net::IpAddr, path::Path, time::Duration};
 use tokio::net::UnixStream;
 
 pub use letmein_fwproto::PortType;
 
 pub struct FirewallClient {
     stream: UnixStream,
+    auth: Option<MsgAuth>,
 }
 
 impl FirewallClient {
     /// Connect to the firewall daemon via Unix socket.
-    pub async fn new(rundir: &Path) -> ah::Result<Self> {
+    /// If `key` is given, then all messages are authenticated with this key.
+    pub async fn new(rundir: &Path, key: Option<&Key>) -> ah::Result<Self> {
         let sock_path = rundir.join("letmeinfwd").join(SOCK_FILE);
-        let stream = UnixStream::connect(sock_path)
+        let mut stream = UnixStream::connect(sock_path)
             .await
             .context("Connect to Unix socket")?;
-        Ok(Self { stream })
+        let auth = match key {
+            Some(key) => {
+                let mut auth = MsgAuth::new(key);
+                auth.recv_challenge(&mut stream)
+                    .await
+                    .context("Receive authentication challenge")?;
+                Some(auth)
+            }
+            None => None,
+        };
+        Ok(Self { stream, auth })
+    }
+
+    async fn send_msg(&mut self, msg: &FirewallMessage) -> ah::Result<()> {
+        match &mut self.auth {
+            Some(auth) => msg.send_auth(&mut self.stream, auth).await,
+            None => msg.send(&mut self.stream).await,
+        }
     }
 
-    /// Send a request to open a firewall `port` for the specified `addr`.
+    async fn recv_msg(&mut self) -> ah::Result<Option<FirewallMessage>> {
+        match &mut self.auth {
+            Some(auth) => FirewallMessage::recv_auth(&mut self.stream, auth).await,
+            None => FirewallMessage::recv(&mut self.stream).await,
+        }
+    }
+
+    /// Send a request to open the firewall `port` of `resource`
+    /// for the specified `addr` on behalf of `user`.
+    /// If `max_timeout` is given, then the lease times out after at most this duration.
     pub async fn open_port(
         &mut self,
         addr: IpAddr,
         port_type: PortType,
         port: u16,
+        user: UserId,
+        resource: ResourceId,
+        max_timeout: Option<Duration>,
     ) -> ah::Result<()> {
         // Send an open-port request to the firewall daemon.
-        FirewallMessage::new_open(addr, port_type, port)
-            .send(&mut self.stream)
+        let msg = match max_timeout {
+            Some(max_timeout) => {
+                // A timeout of 0 means unlimited. Never round down to 0.
+                let secs = max_timeout.as_secs().max(1).try_into().unwrap_or(u32::MAX);
+                FirewallMessage::new_open_timeout(addr, port_type, port, user, resource, secs)
+            }
+            None => FirewallMessage::new_open(addr, port_type, port, user, resource),
+        };
+        self.send_msg(&msg)
             .await
             .context("Send port-open message")?;
 
         // Receive the open-port reply.
-        let Some(msg_reply) = FirewallMessage::recv(&mut self.stream)
-            .await
-            .context("Receive port-open reply")?
-        else {
+        let Some(msg_reply) = self.recv_msg().await.context("Receive port-open reply")? else {
             return Err(err!("Connection terminated"));
         };
 
         match msg_reply.operation() {
             FirewallOperation::Ack => Ok(()),
             FirewallOperation::Nack => Err(err!("The firewall rejected the port-open request")),
-            FirewallOperation::Open => Err(err!("Received invalid reply")),
-            FirewallOperation::Close => Err(err!("Received invalid reply")),
+            FirewallOperation::Open
+            | FirewallOperation::Close
+            | FirewallOperation::ListLeases
+            | FirewallOperation::Lease
+            | FirewallOperation::Revoke
+            | FirewallOperation::RevokeAddr
+            | FirewallOperation::Ban
+            | FirewallOperation::Challenge => Err(err!("Received invalid reply")),
         }
     }
 
-    /// Send a request to close a firewall `port` for the specified `addr`.
+    /// Send a request to close the firewall `port` of `resource`
+    /// for the specified `addr` on behalf of `user`.
     pub async fn close_port(
         &mut self,
         addr: IpAddr,
         port_type: PortType,
         port: u16,
+        user: UserId,
+        resource: ResourceId,
     ) -> ah::Result<()> {
         // Send a close-port request to the firewall daemon.
-        FirewallMessage::new_close(addr, port_type, port)
-            .send(&mut self.stream)
-            .await
-            .context("Send port-close message")?;
+        self.send_msg(&FirewallMessage::new_close(
+            addr, port_type, port, user, resource,
+        ))
+        .await
+        .context("Send port-close message")?;
 
         // Receive the close-port reply.
-        let Some(msg_reply) = FirewallMessage::recv(&mut self.stream)
-            .await
-            .context("Receive port-close reply")?
-        else {
+        let Some(msg_reply) = self.recv_msg().await.context("Receive port-close reply")? else {
             return Err(err!("Connection terminated"));
         };
 
         match msg_reply.operation() {
             FirewallOperation::Ack => Ok(()),
             FirewallOperation::Nack => Err(err!("The firewall rejected the port-close request")),
-            FirewallOperation::Open => Err(err!("Received invalid reply")),
-            FirewallOperation::Close => Err(err!("Received invalid reply")),
+            FirewallOperation::Open
+            | FirewallOperation::Close
+            | FirewallOperation::ListLeases
+            | FirewallOperation::Lease
+            | FirewallOperation::Revoke
+            | FirewallOperation::RevokeAddr
+            | FirewallOperation::Ban
+            | FirewallOperation::Challenge => Err(err!("Received invalid reply")),
+        }
+    }
+
+    /// Send a request to drop all packets from `addr`.
+    pub async fn ban_addr(&mut self, addr: IpAddr) -> ah::Result<()> {
+        // Send a ban request to the firewall daemon.
+        self.send_msg(&FirewallMessage::new_ban(addr))
+            .await
+            .context("Send ban message")?;
+
+        // Receive the ban reply.
+        let Some(msg_reply) = self.recv_msg().await.context("Receive ban reply")? else {
+            return Err(err!("Connection terminated"));
+        };
+
+        match msg_reply.operation() {
+            FirewallOperation::Ack => Ok(()),
+            FirewallOperation::Nack => Err(err!("The firewall rejected the ban request")),
+            _ => Err(err!("Received invalid reply")),
         }
     }
 }
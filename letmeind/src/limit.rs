@@ -0,0 +1,254 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Per-source admission control of control port connections.
+
+use letmein_conf::ConnLimits;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Maximum number of tracked token buckets.
+const MAX_BUCKETS: usize = 1 << 16;
+
+/// Reason for rejecting a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reject {
+    /// Too many simultaneous connections from the source address.
+    Addr,
+    /// Too many simultaneous connections from the source prefix.
+    Prefix,
+    /// Connection rate of the source address exceeded.
+    Rate,
+}
+
+impl std::fmt::Display for Reject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Addr => write!(f, "too many connections from address"),
+            Self::Prefix => write!(f, "too many connections from prefix"),
+            Self::Rate => write!(f, "connection rate exceeded"),
+        }
+    }
+}
+
+/// Normalize an IPv4-mapped IPv6 address to IPv4.
+pub fn normalize_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Mask `addr` to its configured prefix.
+fn addr_prefix(limits: &ConnLimits, addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let len = u32::from(limits.prefix_v4.min(32));
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let len = u32::from(limits.prefix_v6.min(128));
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
+
+/// Token bucket of one source address.
+struct Bucket {
+    tokens: f64,
+    stamp: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket according to the elapsed time.
+    fn refill(&mut self, limits: &ConnLimits, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.stamp).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.rate).min(limits.burst.into());
+        self.stamp = now;
+    }
+
+    /// Check whether the bucket is completely filled.
+    fn is_full(&self, limits: &ConnLimits) -> bool {
+        self.tokens >= f64::from(limits.burst)
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    per_addr: HashMap<IpAddr, u32>,
+    per_prefix: HashMap<IpAddr, u32>,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+/// Decrement a connection counter and remove it, if it reaches zero.
+fn counter_dec(map: &mut HashMap<IpAddr, u32>, key: &IpAddr) {
+    if let Some(count) = map.get_mut(key) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+/// Connection admission control.
+#[derive(Default)]
+pub struct ConnLimiter {
+    state: Mutex<LimiterState>,
+}
+
+impl ConnLimiter {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Default::default())
+    }
+
+    /// Take a connection token from the bucket of `addr`.
+    fn take_token(
+        state: &mut LimiterState,
+        limits: &ConnLimits,
+        addr: IpAddr,
+        now: Instant,
+    ) -> bool {
+        if limits.rate <= 0.0 {
+            return true;
+        }
+
+        // Don't let the table grow without bounds.
+        if state.buckets.len() >= MAX_BUCKETS && !state.buckets.contains_key(&addr) {
+            state.buckets.retain(|_, bucket| {
+                bucket.refill(limits, now);
+                !bucket.is_full(limits)
+            });
+            if state.buckets.len() >= MAX_BUCKETS {
+                return false;
+            }
+        }
+
+        let bucket = state.buckets.entry(addr).or_insert_with(|| Bucket {
+            tokens: limits.burst.into(),
+            stamp: now,
+        });
+        bucket.refill(limits, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Try to admit a new connection from `addr`.
+    ///
+    /// On success the returned [ConnPermit] must be held for the
+    /// lifetime of the connection.
+    pub fn admit(
+        self: &Arc<Self>,
+        limits: &ConnLimits,
+        addr: IpAddr,
+    ) -> Result<ConnPermit, Reject> {
+        let addr = normalize_addr(addr);
+        let prefix = addr_prefix(limits, addr);
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("ConnLimiter lock poisoned");
+
+        let addr_count = state.per_addr.get(&addr).copied().unwrap_or(0);
+        if limits.per_addr != 0 && addr_count >= limits.per_addr {
+            return Err(Reject::Addr);
+        }
+        let prefix_count = state.per_prefix.get(&prefix).copied().unwrap_or(0);
+        if limits.per_prefix != 0 && prefix_count >= limits.per_prefix {
+            return Err(Reject::Prefix);
+        }
+        if !Self::take_token(&mut state, limits, addr, now) {
+            return Err(Reject::Rate);
+        }
+
+        *state.per_addr.entry(addr).or_default() += 1;
+        *state.per_prefix.entry(prefix).or_default() += 1;
+        Ok(ConnPermit {
+            limiter: Arc::clone(self),
+            addr,
+            prefix,
+        })
+    }
+}
+
+/// Admission of one connection.
+///
+/// The per-source connection counters are released on drop.
+pub struct ConnPermit {
+    limiter: Arc<ConnLimiter>,
+    addr: IpAddr,
+    prefix: IpAddr,
+}
+
+impl Drop for ConnPermit {
+    fn drop(&mut self) {
+        let mut state = self
+            .limiter
+            .state
+            .lock()
+            .expect("ConnLimiter lock poisoned");
+        counter_dec(&mut state.per_addr, &self.addr);
+        counter_dec(&mut state.per_prefix, &self.prefix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conn_limits() {
+        let limits = ConnLimits {
+            per_addr: 2,
+            per_prefix: 3,
+            ..Default::default()
+        };
+        let limiter = ConnLimiter::new();
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let c: IpAddr = "::ffff:10.0.0.3".parse().unwrap();
+
+        let pa1 = limiter.admit(&limits, a).unwrap();
+        let _pa2 = limiter.admit(&limits, a).unwrap();
+        assert_eq!(limiter.admit(&limits, a).err(), Some(Reject::Addr));
+        let _pb1 = limiter.admit(&limits, b).unwrap();
+        assert_eq!(limiter.admit(&limits, c).err(), Some(Reject::Prefix));
+        drop(pa1);
+        let _pc1 = limiter.admit(&limits, c).unwrap();
+        assert!(limiter.admit(&limits, "10.0.1.1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_conn_rate() {
+        let limits = ConnLimits {
+            rate: 0.001,
+            burst: 2,
+            ..Default::default()
+        };
+        let limiter = ConnLimiter::new();
+        let a: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(limiter.admit(&limits, a).is_ok());
+        assert!(limiter.admit(&limits, a).is_ok());
+        assert_eq!(limiter.admit(&limits, a).err(), Some(Reject::Rate));
+        assert!(limiter
+            .admit(&limits, "2001:db8::2".parse().unwrap())
+            .is_ok());
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -11,22 +11,33 @@
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 std::compile_error!("letmeind server does not support non-Linux platforms.");
 
+mod audit;
+mod ban;
 mod firewall_client;
+mod limit;
+mod metrics;
 mod protocol;
+mod proxy;
 mod seccomp;
 mod server;
 
 use crate::{
+    audit::Audit,
+    ban::BanList,
+    firewall_client::FirewallClient,
+    metrics::{count_sequence, new_metrics, sample_metrics, BANNED},
     protocol::Protocol,
     seccomp::install_seccomp_rules,
     server::{ConnectionOps as _, Server},
 };
 use anyhow::{self as ah, format_err as err, Context as _};
 use clap::Parser;
-use letmein_conf::{Config, ConfigVariant, Seccomp};
+use letmein_conf::{Config, ConfigVariant, Seccomp, SharedConfig};
+use letmein_metrics::MetricsServer;
 use std::{
     fs::{create_dir_all, metadata, OpenOptions},
     io::Write as _,
+    net::IpAddr,
     os::unix::fs::MetadataExt as _,
     path::{Path, PathBuf},
     sync::Arc,
@@ -103,6 +114,13 @@ struct Opts {
     #[arg(long)]
     seccomp: Option<Seccomp>,
 
+    /// Check the configuration file for inconsistencies and exit.
+    ///
+    /// Exit code: 0 if no errors were found, 1 if errors were found,
+    /// 2 if the configuration could not be loaded.
+    #[arg(long)]
+    check_config: bool,
+
     /// Show version information and exit.
     #[arg(long, short = 'v')]
     version: bool,
@@ -119,15 +137,64 @@ impl Opts {
     }
 }
 
+/// Ask letmeinfwd to drop all packets from `addr`.
+async fn ban_in_firewall(conf: &Config, rundir: &Path, addr: IpAddr) -> ah::Result<()> {
+    let mut fw = FirewallClient::new(rundir, conf.firewall_key()).await?;
+    fw.ban_addr(addr).await
+}
+
+/// Read the letmeind.conf configuration file.
+fn load_config(opts: &Opts) -> ah::Result<Config> {
+    let mut conf = Config::new(ConfigVariant::Server);
+    conf.load(&opts.get_config())
+        .context("Configuration file")?;
+    for warning in conf.warnings() {
+        eprintln!("{warning}");
+    }
+    Ok(conf)
+}
+
+/// Load the configuration file again with the includes found at startup.
+fn reload_config_file(conf: &SharedConfig) -> ah::Result<Config> {
+    let new_conf = conf.get().reload().context("Configuration file")?;
+    for warning in new_conf.warnings() {
+        eprintln!("{warning}");
+    }
+    Ok(new_conf)
+}
+
+/// Reload the configuration file.
+/// If the new configuration is invalid, then the old configuration stays active.
+fn reload_config(conf: &SharedConfig) {
+    let new_conf = match reload_config_file(conf) {
+        Ok(new_conf) => new_conf,
+        Err(e) => {
+            eprintln!("SIGHUP: Failed to reload the configuration: {e:#}");
+            eprintln!("SIGHUP: Keeping the old configuration.");
+            return;
+        }
+    };
+    let old_conf = conf.get();
+    if old_conf.listeners() != new_conf.listeners()
+        || old_conf.seccomp() != new_conf.seccomp()
+        || old_conf.audit_log() != new_conf.audit_log()
+        || old_conf.metrics_letmeind() != new_conf.metrics_letmeind()
+    {
+        eprintln!(
+            "SIGHUP: WARNING: Changes to the 'port', 'listen', 'seccomp', 'audit-log' and \
+             '[METRICS]' options require a restart of letmeind."
+        );
+    }
+    conf.replace(Arc::new(new_conf));
+    eprintln!("SIGHUP: Configuration reloaded.");
+}
+
 async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     // Create directories in /run
     make_run_subdir(&opts.rundir)?;
 
     // Read the letmeind.conf configuration file.
-    let mut conf = Config::new(ConfigVariant::Server);
-    conf.load(&opts.get_config())
-        .context("Configuration file")?;
-    let conf = Arc::new(conf);
+    let conf = Arc::new(SharedConfig::new(load_config(&opts)?));
 
     // Register unix signal handlers.
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
@@ -138,46 +205,120 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     let (exit_sock_tx, mut exit_sock_rx) = sync::mpsc::channel(1);
 
     // Start the TCP control port listener.
-    let mut srv = Server::new(&conf, opts.no_systemd, opts.num_connections)
+    let mut srv = Server::new(&conf.get(), opts.no_systemd, opts.num_connections)
         .await
         .context("Server init")?;
 
+    // Start the metrics listener.
+    let metrics = Arc::new(new_metrics());
+    let metrics_srv = MetricsServer::bind(conf.get().metrics_letmeind())
+        .await
+        .context("Metrics server init")?;
+
+    // Open the audit log.
+    // This must happen before installing the seccomp rules.
+    let audit = Arc::new(Audit::new(&conf.get()).context("Audit log")?);
+
     // Create the PID-file.
     make_pidfile(&opts.rundir)?;
 
     // Install `seccomp` rules, if required.
-    let seccomp = opts.seccomp.unwrap_or(conf.seccomp());
+    let seccomp = opts.seccomp.unwrap_or(conf.get().seccomp());
     install_seccomp_rules(seccomp)?;
 
+    // Spawn task: Metrics server.
+    if let Some(metrics_srv) = metrics_srv {
+        let metrics = Arc::clone(&metrics);
+        let udp = srv.udp_dispatchers();
+        task::spawn(async move {
+            let result = metrics_srv
+                .serve(|| async {
+                    sample_metrics(&metrics, &udp).await;
+                    metrics.render()
+                })
+                .await;
+            if let Err(e) = result {
+                eprintln!("Metrics server ERROR: {e}");
+            }
+        });
+    }
+
     // Spawn task: Socket handler.
     task::spawn({
         let conf = Arc::clone(&conf);
         let opts = Arc::clone(&opts);
+        let audit = Arc::clone(&audit);
+        let metrics = Arc::clone(&metrics);
 
         async move {
-            let conn_semaphore = Semaphore::new(opts.num_connections);
+            let conn_semaphore = Arc::new(Semaphore::new(opts.num_connections));
+            let ban_list = Arc::new(BanList::new());
             loop {
-                let conf = Arc::clone(&conf);
                 let opts = Arc::clone(&opts);
-                match srv.accept().await {
+                let ban_list = Arc::clone(&ban_list);
+                let audit = Arc::clone(&audit);
+                let metrics = Arc::clone(&metrics);
+                match srv.accept(&conf.get()).await {
                     Ok(conn) => {
+                        let conf = conf.get();
+
+                        // Drop banned peers before processing any message.
+                        let peer_ip = conn.peer_addr().ip();
+                        if ban_list.is_banned(&conf, peer_ip) {
+                            metrics.inc(BANNED, &[]);
+                            conn.close().await;
+                            continue;
+                        }
+
                         // Socket connection handler.
                         let conn = Arc::new(conn);
-                        if let Ok(_permit) = conn_semaphore.acquire().await {
+                        // Reject the connection immediately, if all slots are busy.
+                        if let Ok(permit) = Arc::clone(&conn_semaphore).try_acquire_owned() {
                             let conn = Arc::clone(&conn);
                             task::spawn(async move {
+                                // Hold the slot until the connection is finished.
+                                let _permit = permit;
                                 let mut proto = Protocol::new(&*conn, &conf, &opts.rundir);
-                                if let Err(e) = proto.run().await {
+                                let result = proto.run().await;
+                                let event = proto.audit_event(&result);
+                                audit.log(&event);
+                                count_sequence(&metrics, &event, proto.fail_reason());
+                                if let Err(e) = result {
                                     eprintln!(
                                         "Client '{}/{}' ERROR: {}",
                                         conn.peer_addr(),
                                         conn.l4proto(),
                                         e
                                     );
+                                    if proto.failed_auth()
+                                        && ban_list.record_failure(&conf, peer_ip)
+                                    {
+                                        eprintln!(
+                                            "Client '{peer_ip}' banned for {} s \
+                                             after too many failed authentications.",
+                                            conf.ban_duration().as_secs()
+                                        );
+                                        if conf.ban_firewall() {
+                                            if let Err(e) =
+                                                ban_in_firewall(&conf, &opts.rundir, peer_ip).await
+                                            {
+                                                eprintln!(
+                                                    "Client '{peer_ip}' firewall ban ERROR: {e}"
+                                                );
+                                            }
+                                        }
+                                    }
                                 }
                                 conn.close().await;
                             });
                         } else {
+                            if conf.debug() {
+                                println!(
+                                    "Client '{}/{}' rejected: too many connections.",
+                                    conn.peer_addr(),
+                                    conn.l4proto()
+                                );
+                            }
                             conn.close().await;
                         }
                     }
@@ -204,7 +345,7 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
                 break;
             }
             _ = sighup.recv() => {
-                eprintln!("SIGHUP: Reloading is not supported. Please restart letmeind instead.");
+                reload_config(&conf);
             }
             code = exit_sock_rx.recv() => {
                 exitcode = code.unwrap_or_else(|| Err(err!("Unknown error code.")));
@@ -224,6 +365,10 @@ fn main() -> ah::Result<()> {
         return Ok(());
     }
 
+    if opts.check_config {
+        std::process::exit(Config::check_file_print(&opts.get_config()));
+    }
+
     runtime::Builder::new_current_thread()
         .thread_keep_alive(Duration::from_millis(0))
         .max_blocking_threads(1)
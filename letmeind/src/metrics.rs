@@ -0,0 +1,93 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Metrics of the letmeind daemon.
+
+use crate::{audit::AuditEvent, protocol::FailReason};
+use letmein_metrics::{MetricKind, Metrics};
+use letmein_proto::{MsgUdpDispatcher, Operation};
+use std::sync::Arc;
+
+/// Successful sequences by operation and resource.
+pub const SEQUENCES: &str = "letmeind_sequences_total";
+/// Failed sequences by reason.
+pub const FAILURES: &str = "letmeind_failures_total";
+/// Sequences that failed due to a communication timeout.
+pub const TIMEOUTS: &str = "letmeind_timeouts_total";
+/// Connections that were closed due to an active ban.
+pub const BANNED: &str = "letmeind_banned_connections_total";
+/// UDP connections that were dropped due to a RX queue overflow.
+pub const UDP_RX_QUEUE_OVERFLOWS: &str = "letmeind_udp_rx_queue_overflows_total";
+/// UDP connections that were dropped due to the connection limit.
+pub const UDP_CONN_OVERFLOWS: &str = "letmeind_udp_conn_overflows_total";
+
+/// Create the metrics registry of letmeind.
+pub fn new_metrics() -> Metrics {
+    let m = Metrics::new();
+    m.register(
+        SEQUENCES,
+        MetricKind::Counter,
+        "Successful knock and close sequences.",
+    );
+    m.register(FAILURES, MetricKind::Counter, "Failed sequences.");
+    m.register(TIMEOUTS, MetricKind::Counter, "Sequences that timed out.");
+    m.register(
+        BANNED,
+        MetricKind::Counter,
+        "Connections closed due to a brute-force ban.",
+    );
+    m.register(
+        UDP_RX_QUEUE_OVERFLOWS,
+        MetricKind::Counter,
+        "UDP connections dropped due to RX queue overflow.",
+    );
+    m.register(
+        UDP_CONN_OVERFLOWS,
+        MetricKind::Counter,
+        "UDP connections dropped due to the connection limit.",
+    );
+    m
+}
+
+/// Count the result of one sequence.
+pub fn count_sequence(m: &Metrics, event: &AuditEvent, fail_reason: Option<FailReason>) {
+    if event.error.is_none() {
+        let operation = match event.operation {
+            Some(Operation::Close) => "close",
+            _ => "knock",
+        };
+        let resource = event.resource.map(|r| r.to_string()).unwrap_or_default();
+        m.inc(
+            SEQUENCES,
+            &[("operation", operation), ("resource", &resource)],
+        );
+    } else if fail_reason == Some(FailReason::Timeout) {
+        m.inc(TIMEOUTS, &[]);
+    } else {
+        let reason = fail_reason.map(|r| r.as_str()).unwrap_or("io");
+        m.inc(FAILURES, &[("reason", reason)]);
+    }
+}
+
+/// Update the metrics that are sampled from other components.
+pub async fn sample_metrics(m: &Metrics, udp: &[Arc<MsgUdpDispatcher>]) {
+    if udp.is_empty() {
+        return;
+    }
+    let mut rx_queue_overflows = 0;
+    let mut conn_overflows = 0;
+    for udp in udp {
+        let stats = udp.stats().await;
+        rx_queue_overflows += stats.rx_queue_overflows;
+        conn_overflows += stats.conn_overflows;
+    }
+    m.set(UDP_RX_QUEUE_OVERFLOWS, &[], rx_queue_overflows as f64);
+    m.set(UDP_CONN_OVERFLOWS, &[], conn_overflows as f64);
+}
+
+// vim: ts=4 sw=4 expandtab
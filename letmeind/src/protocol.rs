@@ -7,13 +7,14 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use crate::{
+    audit::{AuditAuth, AuditEvent, AuditLease},
     firewall_client::{FirewallClient, PortType},
     server::ConnectionOps,
 };
 use anyhow::{self as ah, format_err as err};
 use letmein_conf::{Config, ErrorPolicy, Resource};
-use letmein_proto::{Message, Operation, ResourceId, UserId};
-use std::path::Path;
+use letmein_proto::{GoAwayReason, Message, Operation, ResourceId, UserId};
+use std::{path::Path, time::SystemTime};
 use tokio::time::timeout;
 
 /// Protocol authentication state.
@@ -30,6 +31,51 @@ enum AuthState {
     ChallengeResponseAuth,
 }
 
+/// The reason for a failed sequence.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum FailReason {
+    /// Communication with the peer timed out.
+    Timeout,
+    /// The peer disconnected.
+    Disconnected,
+    /// The peer sent an unexpected message.
+    InvalidMessage,
+    /// The user is not in the configuration.
+    UnknownUser,
+    /// The authentication failed.
+    Auth,
+    /// The resource is not in the configuration.
+    UnknownResource,
+    /// The user is not allowed to access the resource.
+    Forbidden,
+    /// The resource or the key can't be used from the peer address.
+    Address,
+    /// The resource or the key can't be used at this time.
+    Schedule,
+    /// Invalid configuration.
+    Config,
+    /// The firewall daemon failed.
+    Firewall,
+}
+
+impl FailReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Disconnected => "disconnected",
+            Self::InvalidMessage => "invalid-message",
+            Self::UnknownUser => "unknown-user",
+            Self::Auth => "auth",
+            Self::UnknownResource => "unknown-resource",
+            Self::Forbidden => "forbidden",
+            Self::Address => "address",
+            Self::Schedule => "schedule",
+            Self::Config => "config",
+            Self::Firewall => "firewall",
+        }
+    }
+}
+
 /// Implementation of the wire protocol message sequence.
 pub struct Protocol<'a, C> {
     conn: &'a C,
@@ -37,7 +83,11 @@ pub struct Protocol<'a, C> {
     rundir: &'a Path,
     user_id: Option<UserId>,
     resource_id: Option<ResourceId>,
+    key_name: Option<String>,
     auth_state: AuthState,
+    operation: Option<Operation>,
+    lease: Option<AuditLease>,
+    fail_reason: Option<FailReason>,
 }
 
 impl<'a, C: ConnectionOps> Protocol<'a, C> {
@@ -48,48 +98,124 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
             rundir,
             user_id: None,
             resource_id: None,
+            key_name: None,
             auth_state: AuthState::NotAuth,
+            operation: None,
+            lease: None,
+            fail_reason: None,
+        }
+    }
+
+    /// Check whether the last [Protocol::run] failed with a wrong key or an unknown user.
+    ///
+    /// Only these failures count towards a ban of the peer.
+    /// The source address of UDP datagrams is not verified.
+    /// Therefore, failures of UDP peers only count after a completed challenge round-trip.
+    pub fn failed_auth(&self) -> bool {
+        let failed = matches!(
+            self.fail_reason,
+            Some(FailReason::Auth | FailReason::UnknownUser)
+        );
+        if self.conn.l4proto() == "UDP" {
+            // The response to the challenge has been received from the peer.
+            failed && self.auth_state == AuthState::BasicAuth
+        } else {
+            failed
+        }
+    }
+
+    /// Get the reason of the failure of the last [Protocol::run].
+    /// `None`, if the reason is not known, e.g. for I/O errors.
+    pub fn fail_reason(&self) -> Option<FailReason> {
+        self.fail_reason
+    }
+
+    /// Record the failure `reason` of the sequence and pass the error through.
+    fn fail(&mut self, reason: FailReason, e: ah::Error) -> ah::Error {
+        self.fail_reason = Some(reason);
+        e
+    }
+
+    /// Build the audit event of the last [Protocol::run] with its `result`.
+    pub fn audit_event(&self, result: &ah::Result<()>) -> AuditEvent {
+        AuditEvent {
+            peer_addr: self.conn.peer_addr(),
+            l4proto: self.conn.l4proto(),
+            user: self.user_id,
+            key: self.key_name.clone(),
+            resource: self.resource_id,
+            operation: self.operation,
+            auth: match self.auth_state {
+                AuthState::NotAuth => AuditAuth::None,
+                AuthState::BasicAuth => AuditAuth::Basic,
+                AuthState::ChallengeResponseAuth => AuditAuth::Full,
+            },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            lease: self.lease,
         }
     }
 
     async fn recv_msg(&mut self, expect_operation: Operation) -> ah::Result<Message> {
         if let Some(msg) = timeout(self.conf.control_timeout(), self.conn.recv_msg())
             .await
-            .map_err(|_| err!("RX communication with peer timed out"))??
+            .map_err(|_| {
+                self.fail(
+                    FailReason::Timeout,
+                    err!("RX communication with peer timed out"),
+                )
+            })??
         {
             if msg.operation() != expect_operation {
                 let _ = self.send_go_away().await;
-                return Err(err!(
-                    "Invalid reply message operation. Expected {:?}, got {:?}",
-                    expect_operation,
-                    msg.operation()
+                return Err(self.fail(
+                    FailReason::InvalidMessage,
+                    err!(
+                        "Invalid reply message operation. Expected {:?}, got {:?}",
+                        expect_operation,
+                        msg.operation()
+                    ),
                 ));
             }
             if let Some(user_id) = self.user_id {
                 if msg.user() != user_id {
                     let _ = self.send_go_away().await;
-                    return Err(err!("Received message user mismatch"));
+                    return Err(self.fail(
+                        FailReason::InvalidMessage,
+                        err!("Received message user mismatch"),
+                    ));
                 }
             }
             if let Some(resource_id) = self.resource_id {
                 if msg.resource() != resource_id {
                     let _ = self.send_go_away().await;
-                    return Err(err!("Received message resource mismatch"));
+                    return Err(self.fail(
+                        FailReason::InvalidMessage,
+                        err!("Received message resource mismatch"),
+                    ));
                 }
             }
             Ok(msg)
         } else {
-            Err(err!("Disconnected."))
+            Err(self.fail(FailReason::Disconnected, err!("Disconnected.")))
         }
     }
 
     async fn send_msg(&mut self, msg: &Message) -> ah::Result<()> {
         timeout(self.conf.control_timeout(), self.conn.send_msg(msg))
             .await
-            .map_err(|_| err!("TX communication with peer timed out"))?
+            .map_err(|_| {
+                self.fail(
+                    FailReason::Timeout,
+                    err!("TX communication with peer timed out"),
+                )
+            })?
     }
 
     async fn send_go_away(&mut self) -> ah::Result<()> {
+        self.send_go_away_reason(GoAwayReason::Unspecified).await
+    }
+
+    async fn send_go_away_reason(&mut self, reason: GoAwayReason) -> ah::Result<()> {
         // Check if we are allowed to send the error message.
         match self.conf.control_error_policy() {
             ErrorPolicy::Always => (),
@@ -108,10 +234,10 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
         }
 
         // Send the error message.
-        self.send_msg(&Message::new(
-            Operation::GoAway,
+        self.send_msg(&Message::new_go_away(
             self.user_id.unwrap_or(u32::MAX.into()),
             self.resource_id.unwrap_or(u32::MAX.into()),
+            reason,
         ))
         .await
     }
@@ -119,29 +245,43 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
     pub async fn run(&mut self) -> ah::Result<()> {
         self.user_id = None;
         self.resource_id = None;
+        self.key_name = None;
         self.auth_state = AuthState::NotAuth;
+        self.operation = None;
+        self.lease = None;
+        self.fail_reason = None;
 
         // Receive the initial message (Knock or Close).
         let initial_msg = match timeout(self.conf.control_timeout(), self.conn.recv_msg())
             .await
-            .map_err(|_| err!("RX communication with peer timed out"))?
-        {
+            .map_err(|_| {
+                self.fail(
+                    FailReason::Timeout,
+                    err!("RX communication with peer timed out"),
+                )
+            })? {
             Ok(Some(msg)) => msg,
-            Ok(None) => return Err(err!("Disconnected.")),
+            Ok(None) => {
+                return Err(self.fail(FailReason::Disconnected, err!("Disconnected.")));
+            }
             Err(e) => return Err(err!("Failed to receive message: {}", e)),
         };
 
         // Check if it's a Close or Knock operation
         if initial_msg.operation() != Operation::Knock && initial_msg.operation() != Operation::Close {
             let _ = self.send_go_away().await;
-            return Err(err!(
-                "Invalid initial message operation. Expected Knock or Close, got {:?}",
-                initial_msg.operation()
+            return Err(self.fail(
+                FailReason::InvalidMessage,
+                err!(
+                    "Invalid initial message operation. Expected Knock or Close, got {:?}",
+                    initial_msg.operation()
+                ),
             ));
         }
         
         // Store the operation type before moving initial_msg
         let operation = initial_msg.operation();
+        self.operation = Some(operation);
         let knock = initial_msg;
 
         let user_id = knock.user();
@@ -150,53 +290,78 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
         let resource_id = knock.resource();
         self.resource_id = Some(resource_id);
 
-        // Get the shared key.
-        let Some(key) = self.conf.key(user_id) else {
+        // Get the currently valid shared keys.
+        let now = SystemTime::now();
+        let keys = self.conf.valid_keys(user_id, now);
+        if keys.is_empty() {
             let _ = self.send_go_away().await;
-            return Err(err!("Unknown user: {user_id}"));
-        };
+            return Err(self.fail(
+                FailReason::UnknownUser,
+                err!("Unknown user or no valid key: {user_id}"),
+            ));
+        }
 
         // Authenticate the received message.
         // This check is not replay-safe. But that's fine.
-        if !knock.check_auth_ok_no_challenge(key) {
-            let _ = self.send_go_away().await;
-            return Err(err!("Knock: Authentication failed"));
+        // All keys are checked, so that the timing does not tell which key matched.
+        let mut matched = None;
+        for key in keys {
+            if knock.check_auth_ok_no_challenge(key.key()) && matched.is_none() {
+                matched = Some(key);
+            }
         }
+        let Some(user_key) = matched else {
+            let _ = self.send_go_away().await;
+            return Err(self.fail(FailReason::Auth, err!("Knock: Authentication failed")));
+        };
+        self.key_name = Some(user_key.name().to_string());
+        let key = user_key.key();
         self.auth_state = AuthState::BasicAuth;
 
         // Get the requested resource from the configuration.
         let Some(resource) = self.conf.resource(resource_id) else {
             let _ = self.send_go_away().await;
-            return Err(err!("Unknown resource: {resource_id}"));
+            return Err(self.fail(
+                FailReason::UnknownResource,
+                err!("Unknown resource: {resource_id}"),
+            ));
         };
 
         // Check if the authenticating user is allowed to access this resource.
         match resource {
-            Resource::Port {
-                port,
-                tcp: _,
-                udp: _,
-                users: _,
-            } => {
+            Resource::Port { port, .. } => {
                 // Check the mapped user on the resource.
                 if !resource.contains_user(user_id) {
                     let _ = self.send_go_away().await;
-                    return Err(err!(
-                        "Resource {resource_id} not allowed for user {user_id}"
+                    return Err(self.fail(
+                        FailReason::Forbidden,
+                        err!("Resource {resource_id} not allowed for user {user_id}"),
                     ));
                 }
                 // The control port is never allowed.
-                let control_port = self.conf.port().port;
-                if *port == control_port {
+                if self.conf.is_control_port(*port) {
                     let _ = self.send_go_away().await;
-                    return Err(err!(
-                        "Incorrect configuration: The resource {resource_id} uses the \
-                         letmein control port {control_port}. That is not allowed."
+                    return Err(self.fail(
+                        FailReason::Config,
+                        err!(
+                            "Incorrect configuration: The resource {resource_id} uses the \
+                             letmein control port {port}. That is not allowed."
+                        ),
                     ));
                 }
             }
         }
 
+        // Check the source address allow-lists of the resource and of the key.
+        let peer_ip = self.conn.peer_addr().ip();
+        if !resource.allows_addr(peer_ip) || !user_key.allows_addr(peer_ip) {
+            let _ = self.send_go_away().await;
+            return Err(self.fail(
+                FailReason::Address,
+                err!("Resource {resource_id} not allowed for user {user_id} from {peer_ip}"),
+            ));
+        }
+
         // Generate and send a challenge.
         let mut challenge = Message::new(Operation::Challenge, user_id, resource_id);
         challenge.generate_challenge();
@@ -208,18 +373,37 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
         // Authenticate the challenge-response.
         if !response.check_auth_ok(key, challenge) {
             let _ = self.send_go_away().await;
-            return Err(err!("Response: Authentication failed"));
+            return Err(self.fail(FailReason::Auth, err!("Response: Authentication failed")));
         }
         self.auth_state = AuthState::ChallengeResponseAuth;
 
+        // Check the schedules of the resource and of the key.
+        // Closing a port is always allowed.
+        let mut max_timeout = None;
+        if operation == Operation::Knock {
+            let tz = self.conf.timezone();
+            let schedules = [resource.schedule(), user_key.validity().schedule.as_ref()];
+            if schedules.iter().flatten().any(|s| !s.is_active_at(tz, now)) {
+                let _ = self.send_go_away_reason(GoAwayReason::Schedule).await;
+                return Err(self.fail(
+                    FailReason::Schedule,
+                    err!("Resource {resource_id} not allowed for user {user_id} at this time"),
+                ));
+            }
+            if self.conf.schedule_cap_lease() {
+                // The lease shall not last beyond the end of the schedule.
+                max_timeout = schedules
+                    .iter()
+                    .flatten()
+                    .filter_map(|s| s.active_until(tz, now))
+                    .min()
+                    .map(|end| end.duration_since(now).unwrap_or_default());
+            }
+        }
+
         // Reconfigure the firewall.
         match resource {
-            Resource::Port {
-                port,
-                tcp,
-                udp,
-                users: _,
-            } => {
+            Resource::Port { port, tcp, udp, .. } => {
                 // Port type to open.
                 let port_type = match (tcp, udp) {
                     (true, false) => PortType::Tcp,
@@ -229,10 +413,14 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
                 };
 
                 // Connect to letmeinfwd unix socket.
-                let mut fw = match FirewallClient::new(self.rundir).await {
+                let fw = FirewallClient::new(self.rundir, self.conf.firewall_key()).await;
+                let mut fw = match fw {
                     Err(e) => {
                         let _ = self.send_go_away().await;
-                        return Err(err!("Failed to connect to letmeinfwd: {e}"));
+                        return Err(self.fail(
+                            FailReason::Firewall,
+                            err!("Failed to connect to letmeinfwd: {e}"),
+                        ));
                     }
                     Ok(fw) => fw,
                 };
@@ -242,21 +430,52 @@ impl<'a, C: ConnectionOps> Protocol<'a, C> {
                 if operation == Operation::Close {
                     // Close port operation
                     if let Err(e) = fw
-                        .close_port(self.conn.peer_addr().ip(), port_type, *port)
+                        .close_port(
+                            self.conn.peer_addr().ip(),
+                            port_type,
+                            *port,
+                            user_id,
+                            resource_id,
+                        )
                         .await
                     {
                         let _ = self.send_go_away().await;
-                        return Err(err!("letmeinfwd firewall close: {e}"));
+                        return Err(
+                            self.fail(FailReason::Firewall, err!("letmeinfwd firewall close: {e}"))
+                        );
                     }
+                    self.lease = Some(AuditLease {
+                        port_type,
+                        port: *port,
+                        timeout: None,
+                    });
                 } else {
                     // Open port operation (Knock)
                     if let Err(e) = fw
-                        .open_port(self.conn.peer_addr().ip(), port_type, *port)
+                        .open_port(
+                            self.conn.peer_addr().ip(),
+                            port_type,
+                            *port,
+                            user_id,
+                            resource_id,
+                            max_timeout,
+                        )
                         .await
                     {
                         let _ = self.send_go_away().await;
-                        return Err(err!("letmeinfwd firewall open: {e}"));
+                        return Err(
+                            self.fail(FailReason::Firewall, err!("letmeinfwd firewall open: {e}"))
+                        );
                     }
+                    self.lease = Some(AuditLease {
+                        port_type,
+                        port: *port,
+                        timeout: Some(
+                            max_timeout
+                                .unwrap_or(self.conf.nft_timeout())
+                                .min(self.conf.nft_timeout()),
+                        ),
+                    });
                 }
             }
         }
@@ -0,0 +1,177 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! PROXY protocol version 2 header of connections from a trusted proxy.
+
+use anyhow::{self as ah, format_err as err, Context as _};
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::TcpStream, time};
+
+/// The signature at the start of every PROXY protocol version 2 header.
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Size of the fixed part of the header.
+const FIXED_SIZE: usize = 16;
+
+const VERSION: u8 = 0x20;
+const CMD_LOCAL: u8 = 0x00;
+const CMD_PROXY: u8 = 0x01;
+const AF_UNSPEC: u8 = 0x00;
+const AF_INET: u8 = 0x10;
+const AF_INET6: u8 = 0x20;
+const AF_UNIX: u8 = 0x30;
+
+/// Parse the fixed part of the header.
+///
+/// Returns the length of the variable part that follows.
+fn parse_fixed(fixed: &[u8; FIXED_SIZE]) -> ah::Result<usize> {
+    if fixed[..SIGNATURE.len()] != SIGNATURE {
+        return Err(err!("Invalid signature"));
+    }
+    if fixed[12] & 0xF0 != VERSION {
+        return Err(err!("Unsupported version {}", fixed[12] >> 4));
+    }
+    Ok(u16::from_be_bytes([fixed[14], fixed[15]]).into())
+}
+
+/// Parse the address of the original client from the header.
+///
+/// Returns `None`, if the proxy does not forward a client connection
+/// or if the client address is not an IP address.
+fn parse_addr(fixed: &[u8; FIXED_SIZE], var: &[u8]) -> ah::Result<Option<SocketAddr>> {
+    match fixed[12] & 0x0F {
+        // Connection of the proxy itself, e.g. a health check.
+        CMD_LOCAL => return Ok(None),
+        CMD_PROXY => (),
+        cmd => return Err(err!("Unknown command {cmd}")),
+    }
+    match fixed[13] & 0xF0 {
+        AF_INET => {
+            // Source address, destination address, source port, destination port.
+            if var.len() < 12 {
+                return Err(err!("IPv4 address block is too short"));
+            }
+            let addr: [u8; 4] = var[0..4].try_into().expect("IPv4 address");
+            let port = u16::from_be_bytes([var[8], var[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(addr).into(), port)))
+        }
+        AF_INET6 => {
+            if var.len() < 36 {
+                return Err(err!("IPv6 address block is too short"));
+            }
+            let addr: [u8; 16] = var[0..16].try_into().expect("IPv6 address");
+            let port = u16::from_be_bytes([var[32], var[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(addr).into(), port)))
+        }
+        AF_UNSPEC | AF_UNIX => Ok(None),
+        af => Err(err!("Unknown address family {}", af >> 4)),
+    }
+}
+
+/// Read exactly `buf.len()` bytes from the `stream`.
+async fn read_exact(stream: &TcpStream, buf: &mut [u8]) -> ah::Result<()> {
+    let mut count = 0;
+    while count < buf.len() {
+        stream.readable().await.context("Socket polling (rx)")?;
+        match stream.try_read(&mut buf[count..]) {
+            Ok(0) => return Err(err!("Connection closed")),
+            Ok(n) => count += n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => return Err(err!("Socket read: {e}")),
+        }
+    }
+    Ok(())
+}
+
+/// Receive the PROXY protocol version 2 header from the `stream`.
+///
+/// Nothing after the header is consumed from the `stream`.
+/// Returns the address of the original client
+/// or `None`, if the header does not carry a client IP address.
+pub async fn recv_proxy_header(
+    stream: &TcpStream,
+    timeout: Duration,
+) -> ah::Result<Option<SocketAddr>> {
+    let recv = async {
+        let mut fixed = [0; FIXED_SIZE];
+        read_exact(stream, &mut fixed).await?;
+        let mut var = vec![0; parse_fixed(&fixed)?];
+        read_exact(stream, &mut var).await?;
+        parse_addr(&fixed, &var)
+    };
+    time::timeout(timeout, recv)
+        .await
+        .map_err(|_| err!("Timeout"))?
+        .context("PROXY protocol header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(cmd: u8, af: u8, var: &[u8]) -> [u8; FIXED_SIZE] {
+        let mut fixed = [0; FIXED_SIZE];
+        fixed[..12].copy_from_slice(&SIGNATURE);
+        fixed[12] = VERSION | cmd;
+        fixed[13] = af | 0x01;
+        fixed[14..].copy_from_slice(&(var.len() as u16).to_be_bytes());
+        fixed
+    }
+
+    #[test]
+    fn test_parse() {
+        let var = [
+            192, 0, 2, 1, // source address
+            10, 0, 0, 1, // destination address
+            0x30, 0x39, // source port
+            0x16, 0xA8, // destination port
+            0x04, 0x00, 0x01, 0xFF, // TLV
+        ];
+        let fixed = header(CMD_PROXY, AF_INET, &var);
+        assert_eq!(parse_fixed(&fixed).unwrap(), 16);
+        assert_eq!(
+            parse_addr(&fixed, &var).unwrap(),
+            Some("192.0.2.1:12345".parse().unwrap())
+        );
+        assert!(parse_addr(&fixed, &var[..11]).is_err());
+
+        let mut var = [0; 36];
+        var[0] = 0x20;
+        var[1] = 0x01;
+        var[2] = 0x0D;
+        var[3] = 0xB8;
+        var[15] = 0x01;
+        var[32..34].copy_from_slice(&5800_u16.to_be_bytes());
+        let fixed = header(CMD_PROXY, AF_INET6, &var);
+        assert_eq!(
+            parse_addr(&fixed, &var).unwrap(),
+            Some("[2001:db8::1]:5800".parse().unwrap())
+        );
+
+        let fixed = header(CMD_LOCAL, AF_UNSPEC, &[]);
+        assert_eq!(parse_fixed(&fixed).unwrap(), 0);
+        assert_eq!(parse_addr(&fixed, &[]).unwrap(), None);
+        let fixed = header(CMD_PROXY, AF_UNIX, &[0; 216]);
+        assert_eq!(parse_addr(&fixed, &[0; 216]).unwrap(), None);
+        let fixed = header(0x02, AF_INET, &[0; 12]);
+        assert!(parse_addr(&fixed, &[0; 12]).is_err());
+
+        let mut fixed = header(CMD_PROXY, AF_INET, &[0; 12]);
+        fixed[12] = 0x11;
+        assert!(parse_fixed(&fixed).is_err());
+        let mut fixed = header(CMD_PROXY, AF_INET, &[0; 12]);
+        fixed[0] = b'P';
+        assert!(parse_fixed(&fixed).is_err());
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -10,14 +10,16 @@ use anyhow::{self as ah, Context as _};
 use letmein_conf::Seccomp;
 use letmein_seccomp::{seccomp_supported, Action, Allow, Filter};
 
-const ALLOW_LIST: [Allow; 13] = [
+const ALLOW_LIST: [Allow; 15] = [
     Allow::Mmap,
     Allow::Mprotect,
+    Allow::OpenReadOnly,
     Allow::Read,
     Allow::Write,
     Allow::Fcntl {
         op: Some(libc::F_GETFD as _),
     },
+    Allow::Stat,
     Allow::Recv,
     Allow::Send,
     Allow::Listen,
@@ -6,29 +6,21 @@
 // or the MIT license, at your option.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use crate::{
+    limit::{ConnLimiter, ConnPermit},
+    proxy::recv_proxy_header,
+};
 use anyhow::{self as ah, format_err as err, Context as _};
 use letmein_conf::Config;
 use letmein_proto::{Message, MsgNetSocket, MsgUdpDispatcher};
 use letmein_systemd::{systemd_notify_ready, SystemdSocket};
-use std::{
-    convert::Infallible,
-    net::{Ipv6Addr, SocketAddr},
-    pin::Pin,
-    sync::Arc,
-    time::Duration,
-};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
     net::{TcpListener, TcpStream, UdpSocket},
-    task::{self, JoinHandle},
-    time,
+    sync::mpsc,
+    task,
 };
 
-async fn sleep_forever() -> Infallible {
-    loop {
-        time::sleep(Duration::from_secs(60)).await;
-    }
-}
-
 pub trait ConnectionOps {
     fn peer_addr(&self) -> SocketAddr;
     fn l4proto(&self) -> &'static str;
@@ -41,6 +33,7 @@ pub struct Connection {
     socket: MsgNetSocket,
     peer_addr: SocketAddr,
     l4proto: &'static str,
+    _permit: Option<ConnPermit>,
 }
 
 impl Connection {
@@ -49,6 +42,7 @@ impl Connection {
             socket,
             peer_addr,
             l4proto,
+            _permit: None,
         })
     }
 }
@@ -82,52 +76,65 @@ impl Drop for Connection {
     }
 }
 
-type TcpJoinHandle = Pin<Box<JoinHandle<ah::Result<(TcpStream, SocketAddr)>>>>;
+/// A connection that has been accepted by one of the listeners.
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    /// TCP connection from a trusted proxy with the address of the original client.
+    Proxied(TcpStream, SocketAddr),
+    Udp(Arc<MsgUdpDispatcher>, SocketAddr),
+}
+
+type AcceptSender = mpsc::Sender<ah::Result<Accepted>>;
 
-fn spawn_tcp_accept(tcp: Arc<Option<TcpListener>>) -> TcpJoinHandle {
-    Box::pin(task::spawn(async move {
-        if let Some(tcp) = tcp.as_ref() {
-            return Ok(tcp.accept().await?);
+fn spawn_tcp_accept(tcp: TcpListener, tx: AcceptSender) {
+    task::spawn(async move {
+        loop {
+            let result = tcp
+                .accept()
+                .await
+                .map(|(stream, peer_addr)| Accepted::Tcp(stream, peer_addr))
+                .context("TCP accept");
+            if tx.send(result).await.is_err() {
+                break;
+            }
         }
-        sleep_forever().await;
-        unreachable!();
-    }))
+    });
 }
 
-type UdpJoinHandle = Pin<Box<JoinHandle<ah::Result<(Arc<MsgUdpDispatcher>, SocketAddr)>>>>;
-
-fn spawn_udp_accept(udp: Arc<Option<Arc<MsgUdpDispatcher>>>) -> UdpJoinHandle {
-    Box::pin(task::spawn(async move {
-        if let Some(udp) = udp.as_ref() {
-            let peer_addr = udp.accept().await?;
-            return Ok((Arc::clone(udp), peer_addr));
+fn spawn_udp_accept(udp: Arc<MsgUdpDispatcher>, tx: AcceptSender) {
+    task::spawn(async move {
+        loop {
+            let result = udp
+                .accept()
+                .await
+                .map(|peer_addr| Accepted::Udp(Arc::clone(&udp), peer_addr));
+            if tx.send(result).await.is_err() {
+                break;
+            }
         }
-        sleep_forever().await;
-        unreachable!();
-    }))
+    });
 }
 
 pub struct Server {
-    tcp: Arc<Option<TcpListener>>,
-    tcp_join: TcpJoinHandle,
-    udp: Arc<Option<Arc<MsgUdpDispatcher>>>,
-    udp_join: UdpJoinHandle,
+    udp: Vec<Arc<MsgUdpDispatcher>>,
+    accept_tx: AcceptSender,
+    accept_rx: mpsc::Receiver<ah::Result<Accepted>>,
+    limiter: Arc<ConnLimiter>,
 }
 
 impl Server {
     pub async fn new(conf: &Config, no_systemd: bool, max_nr_udp_conn: usize) -> ah::Result<Self> {
-        let mut tcp = None;
-        let mut udp = None;
+        let mut tcp = vec![];
+        let mut udp = vec![];
+        let tcp_enabled = conf.listeners().iter().any(|l| l.port.tcp);
+        let udp_enabled = conf.listeners().iter().any(|l| l.port.udp);
 
-        // Get socket from systemd?
+        // Get sockets from systemd?
         if !no_systemd {
             for socket in SystemdSocket::get_all()?.into_iter() {
                 match socket {
                     SystemdSocket::Tcp(listener) => {
-                        if tcp.is_some() {
-                            return Err(err!("Received multiple TCP sockets from systemd."));
-                        }
-                        if !conf.port().tcp {
+                        if !tcp_enabled {
                             // Received socket from systemd, but TCP is not configured.
                             drop(listener);
                             continue;
@@ -137,16 +144,13 @@ impl Server {
                         listener
                             .set_nonblocking(true)
                             .context("Set socket non-blocking")?;
-                        tcp = Some(
+                        tcp.push(
                             TcpListener::from_std(listener)
                                 .context("Convert std TcpListener to tokio TcpListener")?,
                         );
                     }
                     SystemdSocket::Udp(socket) => {
-                        if udp.is_some() {
-                            return Err(err!("Received multiple UDP sockets from systemd."));
-                        }
-                        if !conf.port().udp {
+                        if !udp_enabled {
                             // Received socket from systemd, but UDP is not configured.
                             drop(socket);
                             continue;
@@ -156,7 +160,7 @@ impl Server {
                         socket
                             .set_nonblocking(true)
                             .context("Set socket non-blocking")?;
-                        udp = Some(Arc::new(MsgUdpDispatcher::new(
+                        udp.push(Arc::new(MsgUdpDispatcher::new(
                             UdpSocket::from_std(socket)
                                 .context("Convert std UdpSocket to tokio UdpSocket")?,
                             max_nr_udp_conn,
@@ -168,60 +172,191 @@ impl Server {
                 }
             }
 
-            if tcp.is_some() || udp.is_some() {
+            if !tcp.is_empty() || !udp.is_empty() {
                 systemd_notify_ready()?;
             }
         }
 
         // Without systemd.
-        if tcp.is_none() && udp.is_none() {
-            // TCP bind.
-            if conf.port().tcp {
-                tcp = Some(
-                    TcpListener::bind((Ipv6Addr::UNSPECIFIED, conf.port().port))
-                        .await
-                        .context("Bind")?,
-                );
-            }
-            // UDP bind.
-            if conf.port().udp {
-                udp = Some(Arc::new(MsgUdpDispatcher::new(
-                    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, conf.port().port))
-                        .await
-                        .context("Bind")?,
-                    max_nr_udp_conn,
-                )));
+        if tcp.is_empty() && udp.is_empty() {
+            for listener in conf.listeners() {
+                let addr = (listener.addr, listener.port.port);
+                // TCP bind.
+                if listener.port.tcp {
+                    tcp.push(
+                        TcpListener::bind(addr)
+                            .await
+                            .with_context(|| format!("Bind TCP {listener}"))?,
+                    );
+                }
+                // UDP bind.
+                if listener.port.udp {
+                    udp.push(Arc::new(MsgUdpDispatcher::new(
+                        UdpSocket::bind(addr)
+                            .await
+                            .with_context(|| format!("Bind UDP {listener}"))?,
+                        max_nr_udp_conn,
+                    )));
+                }
             }
         }
 
-        let tcp = Arc::new(tcp);
-        let tcp_join = spawn_tcp_accept(Arc::clone(&tcp));
-        let udp = Arc::new(udp);
-        let udp_join = spawn_udp_accept(Arc::clone(&udp));
-        Ok(Self {
-            tcp,
-            tcp_join,
+        Ok(Self::from_sockets(tcp, udp))
+    }
+
+    fn from_sockets(tcp: Vec<TcpListener>, udp: Vec<Arc<MsgUdpDispatcher>>) -> Self {
+        let (accept_tx, accept_rx) = mpsc::channel(1);
+        for tcp in tcp {
+            spawn_tcp_accept(tcp, accept_tx.clone());
+        }
+        for udp in &udp {
+            spawn_udp_accept(Arc::clone(udp), accept_tx.clone());
+        }
+        Self {
             udp,
-            udp_join,
-        })
+            accept_tx,
+            accept_rx,
+            limiter: ConnLimiter::new(),
+        }
+    }
+
+    /// Get the UDP dispatchers of all UDP listeners.
+    pub fn udp_dispatchers(&self) -> Vec<Arc<MsgUdpDispatcher>> {
+        self.udp.clone()
+    }
+
+    /// Receive the PROXY protocol header of a connection from a trusted proxy
+    /// in the background and pass the connection on to [Server::accept_any].
+    ///
+    /// This must not block the accept loop. Health checks of a load balancer
+    /// usually connect without sending anything.
+    fn spawn_proxy_header(&self, conf: &Config, stream: TcpStream, proxy_addr: SocketAddr) {
+        let tx = self.accept_tx.clone();
+        let timeout = conf.control_timeout();
+        let debug = conf.debug();
+        task::spawn(async move {
+            match recv_proxy_header(&stream, timeout).await {
+                Ok(Some(peer_addr)) => {
+                    let _ = tx.send(Ok(Accepted::Proxied(stream, peer_addr))).await;
+                }
+                Ok(None) => {
+                    // A knock must never open a lease for the proxy itself.
+                    if debug {
+                        println!("Proxy '{proxy_addr}/TCP': No client address in header. Closing.");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Proxy '{proxy_addr}/TCP' ERROR: {e:#}");
+                }
+            }
+        });
     }
 
-    pub async fn accept(&mut self) -> ah::Result<Connection> {
-        tokio::select! {
-            result = &mut self.tcp_join => {
-                self.tcp_join = spawn_tcp_accept(Arc::clone(&self.tcp));
-                let (stream, peer_addr) = result??;
-                let ns = MsgNetSocket::from_tcp(stream)?;
-                Ok(Connection::new(ns, peer_addr, "TCP")?)
+    async fn accept_any(&mut self, conf: &Config) -> ah::Result<Connection> {
+        loop {
+            // The server holds a sender. Therefore, the channel is never closed.
+            let accepted = self.accept_rx.recv().await.expect("Accept channel closed");
+            match accepted? {
+                Accepted::Tcp(stream, peer_addr) => {
+                    if conf.is_trusted_proxy(peer_addr.ip()) {
+                        self.spawn_proxy_header(conf, stream, peer_addr);
+                        continue;
+                    }
+                    let ns = MsgNetSocket::from_tcp(stream)?;
+                    return Connection::new(ns, peer_addr, "TCP");
+                }
+                Accepted::Proxied(stream, peer_addr) => {
+                    let ns = MsgNetSocket::from_tcp(stream)?;
+                    return Connection::new(ns, peer_addr, "TCP");
+                }
+                Accepted::Udp(udp_disp, peer_addr) => {
+                    let ns = MsgNetSocket::from_udp(udp_disp, peer_addr)?;
+                    return Connection::new(ns, peer_addr, "UDP");
+                }
             }
-            result = &mut self.udp_join => {
-                self.udp_join = spawn_udp_accept(Arc::clone(&self.udp));
-                let (udp_disp, peer_addr) = result??;
-                let ns = MsgNetSocket::from_udp(udp_disp, peer_addr)?;
-                Ok(Connection::new(ns, peer_addr, "UDP")?)
+        }
+    }
+
+    /// Accept the next connection that passes the per-source connection limits.
+    ///
+    /// Connections over the limits are closed immediately.
+    pub async fn accept(&mut self, conf: &Config) -> ah::Result<Connection> {
+        loop {
+            let mut conn = self.accept_any(conf).await?;
+            match self.limiter.admit(conf.conn_limits(), conn.peer_addr.ip()) {
+                Ok(permit) => {
+                    conn._permit = Some(permit);
+                    return Ok(conn);
+                }
+                Err(reason) => {
+                    if conf.debug() {
+                        println!(
+                            "Client '{}/{}' rejected: {reason}.",
+                            conn.peer_addr, conn.l4proto
+                        );
+                    }
+                    conn.close().await;
+                }
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use letmein_conf::{ConfigVariant, Ini};
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn test_silent_proxy() {
+        let mut ini = Ini::new();
+        ini.parse_str("[GENERAL]\nproxy-protocol = 127.0.0.1\ncontrol-timeout = 10\n")
+            .unwrap();
+        let mut conf = Config::new(ConfigVariant::Server);
+        conf.load_ini(&ini).unwrap();
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut srv = Server::from_sockets(vec![tcp], vec![]);
+
+        // A health check that never sends a header.
+        let _silent = TcpStream::connect(addr).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        // A health check with a LOCAL header must not become a connection.
+        let local = TcpStream::connect(addr).await.unwrap();
+        let header = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        local.writable().await.unwrap();
+        assert_eq!(local.try_write(header).unwrap(), header.len());
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C".to_vec();
+        header.extend_from_slice(&[192, 0, 2, 1, 127, 0, 0, 1, 0x30, 0x39, 0x16, 0xA8]);
+        client.writable().await.unwrap();
+        assert_eq!(client.try_write(&header).unwrap(), header.len());
+
+        let conn = time::timeout(Duration::from_secs(2), srv.accept(&conf))
+            .await
+            .expect("Accept is blocked by the silent connection")
+            .unwrap();
+        assert_eq!(conn.peer_addr(), "192.0.2.1:12345".parse().unwrap());
+        conn.close().await;
+        time::timeout(Duration::from_secs(2), async {
+            loop {
+                local.readable().await.unwrap();
+                match local.try_read(&mut [0; 1]) {
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+                    Err(_) => break 0,
+                }
+            }
+        })
+        .await
+        .map(|n| assert_eq!(n, 0))
+        .expect("The LOCAL connection is not closed");
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,258 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Lease administration over the root-only admin Unix socket.
+
+use crate::{
+    firewall::{FirewallAdmin, LeaseCounters, LeasePort},
+    server::{addr_check, remove_socket_file},
+    set_owner_mode, Opts,
+};
+use anyhow::{self as ah, format_err as err, Context as _};
+use letmein_conf::Config;
+use letmein_fwproto::{FirewallMessage, FirewallOperation, PortType, ADMIN_SOCK_FILE};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+
+/// Get the path to the admin socket.
+fn admin_sock_path(rundir: &Path) -> PathBuf {
+    rundir.join("letmeinfwd").join(ADMIN_SOCK_FILE)
+}
+
+/// Convert a lease port to the protocol port type.
+fn lease_port_type(port: LeasePort) -> (PortType, u16) {
+    match port {
+        LeasePort::Tcp(p) => (PortType::Tcp, p),
+        LeasePort::Udp(p) => (PortType::Udp, p),
+        LeasePort::TcpUdp(p) => (PortType::TcpUdp, p),
+    }
+}
+
+pub struct AdminConnection {
+    stream: UnixStream,
+}
+
+impl AdminConnection {
+    async fn send_msg(&mut self, msg: &FirewallMessage) -> ah::Result<()> {
+        msg.send(&mut self.stream).await
+    }
+
+    /// Handle one request on the admin socket.
+    pub async fn handle_message(
+        &mut self,
+        conf: &Config,
+        fw: Arc<Mutex<impl FirewallAdmin>>,
+    ) -> ah::Result<()> {
+        let Some(msg) = FirewallMessage::recv(&mut self.stream).await? else {
+            return Err(err!("Disconnected."));
+        };
+        match msg.operation() {
+            FirewallOperation::ListLeases => {
+                let leases = {
+                    let mut fw = fw.lock().await;
+                    fw.list_leases(conf).await
+                };
+                let leases = match leases {
+                    Ok(leases) => leases,
+                    Err(e) => {
+                        self.send_msg(&FirewallMessage::new_nack()).await?;
+                        return Err(e);
+                    }
+                };
+                for lease in leases {
+                    let (port_type, port) = lease_port_type(lease.port);
+                    let counters = lease.counters.unwrap_or_default();
+                    let msg = FirewallMessage::new_lease(
+                        lease.addr,
+                        port_type,
+                        port,
+                        lease.remaining.as_secs().try_into().unwrap_or(u32::MAX),
+                        counters.packets,
+                        counters.bytes,
+                    );
+                    self.send_msg(&msg).await?;
+                }
+                self.send_msg(&FirewallMessage::new_ack()).await?;
+            }
+            FirewallOperation::Revoke | FirewallOperation::RevokeAddr => {
+                // Get the address from the socket message.
+                let Some(addr) = msg.addr() else {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(err!("No addr."));
+                };
+
+                // Check if addr is valid.
+                if !addr_check(&addr) {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(err!("Invalid addr."));
+                }
+
+                // The port type is ignored. All leases for the port number are revoked.
+                let port = msg.port().map(|(_, port)| port);
+
+                let count = {
+                    let mut fw = fw.lock().await;
+                    fw.revoke_leases(conf, addr, port).await
+                };
+                match count {
+                    Ok(count) => {
+                        println!("Admin: Revoked {count} lease(s) of {addr}.");
+                        self.send_msg(&FirewallMessage::new_ack()).await?;
+                    }
+                    Err(e) => {
+                        self.send_msg(&FirewallMessage::new_nack()).await?;
+                        return Err(e);
+                    }
+                }
+            }
+            FirewallOperation::Open
+            | FirewallOperation::Close
+            | FirewallOperation::Ban
+            | FirewallOperation::Lease
+            | FirewallOperation::Ack
+            | FirewallOperation::Nack
+            | FirewallOperation::Challenge => {
+                self.send_msg(&FirewallMessage::new_nack()).await?;
+                return Err(err!("Received invalid message"));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct AdminServer {
+    listener: UnixListener,
+}
+
+impl AdminServer {
+    /// Create the admin Unix socket.
+    /// The socket is only accessible by root.
+    pub async fn new(opts: &Opts) -> ah::Result<Self> {
+        let sock_path = admin_sock_path(&opts.rundir);
+        remove_socket_file(&sock_path)?;
+
+        let listener = UnixListener::bind(&sock_path).context("Bind admin socket")?;
+        if !opts.test_mode() {
+            set_owner_mode(&sock_path, 0 /* root */, 0 /* root */, 0o600)
+                .context("Set admin unix socket owner and mode")?;
+        }
+
+        Ok(Self { listener })
+    }
+
+    /// Accept a connection on the admin Unix socket.
+    pub async fn accept(&self, opts: &Opts) -> ah::Result<AdminConnection> {
+        let (stream, _addr) = self.listener.accept().await?;
+
+        if !opts.test_mode() {
+            // Only root is allowed to administrate the leases.
+            // This is an additional check that is not strictly needed for the security
+            // concept. The socket is only accessible by root.
+            let cred = stream
+                .peer_cred()
+                .context("Get Unix socket peer credentials")?;
+            if cred.uid() != 0 {
+                return Err(err!(
+                    "The connected uid {} is not root. Rejecting.",
+                    cred.uid()
+                ));
+            }
+        }
+
+        Ok(AdminConnection { stream })
+    }
+}
+
+/// Admin client connection to the running daemon.
+pub struct AdminClient {
+    stream: UnixStream,
+}
+
+impl AdminClient {
+    /// Connect to the admin socket of the running daemon.
+    pub async fn new(rundir: &Path) -> ah::Result<Self> {
+        let stream = UnixStream::connect(admin_sock_path(rundir))
+            .await
+            .context("Connect to the letmeinfwd admin socket")?;
+        Ok(Self { stream })
+    }
+
+    /// Receive one reply message.
+    async fn recv_msg(&mut self) -> ah::Result<FirewallMessage> {
+        FirewallMessage::recv(&mut self.stream)
+            .await
+            .context("Receive reply")?
+            .ok_or_else(|| err!("Connection terminated"))
+    }
+
+    /// Print the list of all active leases.
+    pub async fn list_leases(&mut self) -> ah::Result<()> {
+        FirewallMessage::new_list_leases()
+            .send(&mut self.stream)
+            .await
+            .context("Send lease-list message")?;
+        let mut count = 0;
+        loop {
+            let msg = self.recv_msg().await?;
+            match msg.operation() {
+                FirewallOperation::Lease => {
+                    let (
+                        Some(addr),
+                        Some((port_type, port)),
+                        Some(timeout),
+                        Some((packets, bytes)),
+                    ) = (msg.addr(), msg.port(), msg.timeout(), msg.counters())
+                    else {
+                        return Err(err!("Received invalid lease message"));
+                    };
+                    let port = match port_type {
+                        PortType::Tcp => LeasePort::Tcp(port),
+                        PortType::Udp => LeasePort::Udp(port),
+                        PortType::TcpUdp => LeasePort::TcpUdp(port),
+                    };
+                    let counters = LeaseCounters { packets, bytes };
+                    println!("{addr} {port} expires in {timeout} s, {counters}");
+                    count += 1;
+                }
+                FirewallOperation::Ack => break,
+                FirewallOperation::Nack => {
+                    return Err(err!("The firewall rejected the lease-list request"));
+                }
+                _ => return Err(err!("Received invalid reply")),
+            }
+        }
+        println!("{count} active lease(s).");
+        Ok(())
+    }
+
+    /// Revoke the leases of `addr`, optionally restricted to `port`.
+    pub async fn revoke(&mut self, addr: IpAddr, port: Option<u16>) -> ah::Result<()> {
+        let msg = match port {
+            // The port type is ignored by the daemon.
+            Some(port) => FirewallMessage::new_revoke(addr, PortType::TcpUdp, port),
+            None => FirewallMessage::new_revoke_addr(addr),
+        };
+        msg.send(&mut self.stream)
+            .await
+            .context("Send revoke message")?;
+        match self.recv_msg().await?.operation() {
+            FirewallOperation::Ack => Ok(()),
+            FirewallOperation::Nack => Err(err!("The firewall rejected the revoke request")),
+            _ => Err(err!("Received invalid reply")),
+        }
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -10,10 +10,15 @@ pub mod nftables;
 
 use anyhow as ah;
 use letmein_conf::Config;
-use std::{collections::HashMap, net::IpAddr, time::Instant};
+use letmein_proto::{ResourceId, UserId};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 /// TCP and/or UDP port number.
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum LeasePort {
     /// TCP port.
     Tcp(u16),
@@ -23,6 +28,15 @@ pub enum LeasePort {
     TcpUdp(u16),
 }
 
+impl LeasePort {
+    /// Get the port number.
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Tcp(p) | Self::Udp(p) | Self::TcpUdp(p) => *p,
+        }
+    }
+}
+
 impl std::fmt::Display for LeasePort {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
@@ -35,7 +49,7 @@ impl std::fmt::Display for LeasePort {
 
 /// TCP or UDP port number.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum SingleLeasePort {
+pub enum SingleLeasePort {
     /// TCP port.
     Tcp(u16),
     /// UDP port.
@@ -51,30 +65,69 @@ impl std::fmt::Display for SingleLeasePort {
     }
 }
 
+/// Packet and byte counters of the firewall rule(s) of a lease.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct LeaseCounters {
+    /// Number of packets that matched the lease rule(s).
+    pub packets: u64,
+    /// Number of bytes that matched the lease rule(s).
+    pub bytes: u64,
+}
+
+impl std::ops::AddAssign for LeaseCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.packets = self.packets.saturating_add(other.packets);
+        self.bytes = self.bytes.saturating_add(other.bytes);
+    }
+}
+
+impl std::fmt::Display for LeaseCounters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        const KIB: u64 = 1024;
+        const MIB: u64 = 1024 * KIB;
+        const GIB: u64 = 1024 * MIB;
+        write!(f, "{} packets/", self.packets)?;
+        match self.bytes {
+            b if b >= GIB => write!(f, "{} GiB", b / GIB),
+            b if b >= MIB => write!(f, "{} MiB", b / MIB),
+            b if b >= KIB => write!(f, "{} KiB", b / KIB),
+            b => write!(f, "{b} B"),
+        }
+    }
+}
+
 /// Dynamic port/address lease.
 #[derive(Clone)]
-struct Lease {
+pub struct Lease {
     addr: IpAddr,
     port: LeasePort,
+    user: Option<UserId>,
+    resource: Option<ResourceId>,
+    created: Instant,
     timeout: Instant,
 }
 
 impl Lease {
     /// Create a new lease with maximum timeout.
-    pub fn new(conf: &Config, addr: IpAddr, port: LeasePort) -> Self {
+    /// `user` is the user that requested the lease and `resource` is
+    /// the resource of the port, if known.
+    pub fn new(
+        conf: &Config,
+        addr: IpAddr,
+        port: LeasePort,
+        user: Option<UserId>,
+        resource: Option<ResourceId>,
+    ) -> Self {
         // The upper layers must never give us a lease request for the control port.
-        assert_ne!(
-            conf.port().port,
-            match port {
-                LeasePort::Tcp(p) => p,
-                LeasePort::Udp(p) => p,
-                LeasePort::TcpUdp(p) => p,
-            }
-        );
-        let timeout = Instant::now() + conf.nft_timeout();
+        assert!(!conf.is_control_port(port.port()));
+        let created = Instant::now();
+        let timeout = created + conf.nft_timeout();
         Self {
             addr,
             port,
+            user,
+            resource,
+            created,
             timeout,
         }
     }
@@ -84,11 +137,26 @@ impl Lease {
         self.timeout = Instant::now() + conf.nft_timeout();
     }
 
+    /// Shorten the timeout, so that the lease times out after at most `max`.
+    pub fn limit_timeout(&mut self, max: Duration) {
+        self.timeout = self.timeout.min(Instant::now() + max);
+    }
+
     /// Check if this lease has timed out.
     pub fn is_timed_out(&self, now: Instant) -> bool {
         now >= self.timeout
     }
 
+    /// Get the time that is left until this lease times out.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.timeout.saturating_duration_since(now)
+    }
+
+    /// Get the time that has passed since this lease has been created.
+    pub fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.created)
+    }
+
     /// Get the IP address of this lease.
     pub fn addr(&self) -> IpAddr {
         self.addr
@@ -98,19 +166,90 @@ impl Lease {
     pub fn port(&self) -> LeasePort {
         self.port
     }
+
+    /// Get the user that requested this lease, if known.
+    pub fn user(&self) -> Option<UserId> {
+        self.user
+    }
+
+    /// Set the user that requested this lease.
+    pub fn set_user(&mut self, user: Option<UserId>) {
+        self.user = user;
+    }
+
+    /// Get the resource of this lease, if known.
+    pub fn resource(&self) -> Option<ResourceId> {
+        self.resource
+    }
+
+    /// Set the resource of this lease.
+    pub fn set_resource(&mut self, resource: Option<ResourceId>) {
+        self.resource = resource;
+    }
+
+    /// Get the resource of this lease.
+    /// If the resource is not known, then it is looked up by the port number.
+    pub fn resource_id(&self, conf: &Config) -> Option<ResourceId> {
+        self.resource
+            .or_else(|| conf.resource_id_by_port(self.port.port(), None))
+    }
 }
 
 impl std::fmt::Display for Lease {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        write!(f, "Lease(addr={}, port={})", self.addr(), self.port())
+        write!(f, "Lease(addr={}, port={}", self.addr(), self.port())?;
+        if let Some(user) = self.user() {
+            write!(f, ", user={user}")?;
+        }
+        if let Some(resource) = self.resource() {
+            write!(f, ", resource={resource}")?;
+        }
+        write!(f, ")")
     }
 }
 
+/// The reason for removing a [Lease] from the firewall.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum LeaseEnd {
+    /// The lease timed out.
+    Expired,
+    /// The lease was explicitly closed.
+    Closed,
+    /// The lease was revoked by the administrator.
+    Revoked,
+    /// The lease was removed to make room for a new lease within the lease quotas.
+    Evicted,
+}
+
+impl std::fmt::Display for LeaseEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Expired => write!(f, "expired"),
+            Self::Closed => write!(f, "closed"),
+            Self::Revoked => write!(f, "revoked"),
+            Self::Evicted => write!(f, "evicted"),
+        }
+    }
+}
+
+/// Information about an active [Lease].
+#[derive(Clone)]
+pub struct LeaseInfo {
+    /// The IP address of the lease.
+    pub addr: IpAddr,
+    /// The port of the lease.
+    pub port: LeasePort,
+    /// The time that is left until the lease times out.
+    pub remaining: Duration,
+    /// The firewall counters of the lease, if available.
+    pub counters: Option<LeaseCounters>,
+}
+
 /// Key in the lease map.
-type LeaseId = (IpAddr, LeasePort);
+pub type LeaseId = (IpAddr, LeasePort);
 
 /// A map of [Lease]s.
-type LeaseMap = HashMap<LeaseId, Lease>;
+pub type LeaseMap = HashMap<LeaseId, Lease>;
 
 /// Prune (remove) all leases that have timed out.
 ///
@@ -133,6 +272,79 @@ fn prune_all_lease_timeouts(conf: &Config, leases: &mut LeaseMap) -> Vec<Lease>
     pruned
 }
 
+/// Check a new lease against the `max-leases-per-user` and `max-leases-per-resource` quotas.
+///
+/// `id`, `user` and `resource` are the new lease.
+/// An existing lease with the same `id` is not counted.
+///
+/// Returns the names of the exceeded quotas and the leases that have to be removed,
+/// so that the new lease fits into the quotas. The oldest leases are selected first.
+pub fn lease_quota_excess(
+    conf: &Config,
+    leases: &LeaseMap,
+    id: &LeaseId,
+    user: Option<UserId>,
+    resource: Option<ResourceId>,
+) -> (Vec<&'static str>, Vec<LeaseId>) {
+    let mut exceeded = vec![];
+    let mut excess: Vec<LeaseId> = vec![];
+    let mut check = |name: &'static str, max: u32, matches: &dyn Fn(&Lease) -> bool| {
+        if max == 0 {
+            return; // unlimited
+        }
+        let mut counted: Vec<(&LeaseId, &Lease)> = leases
+            .iter()
+            .filter(|(lid, lease)| *lid != id && !excess.contains(lid) && matches(lease))
+            .collect();
+        let max = usize::try_from(max).unwrap_or(usize::MAX);
+        if counted.len() >= max {
+            exceeded.push(name);
+            counted.sort_by_key(|(_, lease)| lease.created);
+            let remove = counted.len() - max + 1;
+            excess.extend(counted[..remove].iter().map(|(lid, _)| **lid));
+        }
+    };
+    if let Some(user) = user {
+        check(
+            "max-leases-per-user",
+            conf.max_leases_per_user(),
+            &|lease| lease.user() == Some(user),
+        );
+    }
+    check(
+        "max-leases-per-resource",
+        conf.max_leases_per_resource(),
+        &|lease| match resource {
+            Some(resource) => lease.resource_id(conf) == Some(resource),
+            None => lease.port().port() == id.1.port(),
+        },
+    );
+    (exceeded, excess)
+}
+
+/// A map of banned addresses and the time their ban ends.
+type BanMap = HashMap<IpAddr, Instant>;
+
+/// Prune (remove) all bans that have timed out.
+///
+/// Returns a `Vec` of the addresses that are not banned anymore.
+#[must_use]
+fn prune_all_ban_timeouts(conf: &Config, bans: &mut BanMap) -> Vec<IpAddr> {
+    let mut pruned = vec![];
+    let now = Instant::now();
+    bans.retain(|addr, until| {
+        let timed_out = now >= *until;
+        if timed_out {
+            pruned.push(*addr);
+            if conf.debug() {
+                println!("firewall: Ban of {addr} timed out");
+            }
+        }
+        !timed_out
+    });
+    pruned
+}
+
 /// Firewall maintenance operations.
 pub trait FirewallMaintain {
     /// Delete all leases from the firewall.
@@ -140,8 +352,14 @@ pub trait FirewallMaintain {
 
     /// Run periodic maintenance.
     /// This shall be called in regular intervals every couple of seconds.
-    /// This operation shall remove all timed-out leases.
+    /// This operation shall remove all timed-out leases and bans.
     async fn maintain(&mut self, conf: &Config) -> ah::Result<()>;
+
+    /// Switch from the `old` to the `new` configuration.
+    /// This operation shall remove all leases that are not valid in
+    /// the `new` configuration anymore and update the kernel rules.
+    /// On error the firewall shall stay in the `old` configuration.
+    async fn reload(&mut self, old: &Config, new: &Config) -> ah::Result<()>;
 }
 
 /// Firewall knock-open operations.
@@ -149,11 +367,17 @@ pub trait FirewallOpen {
     /// Add a rule to open the specified `port` for the specified `remote_addr`.
     /// This operation shall handle the case where there already is such
     /// a rule present gracefully.
+    /// `user` is the user that requested the lease and `resource` is the resource of the port.
+    /// They are recorded in the lease and used for the lease quotas.
+    /// If `max_timeout` is given, then the lease times out after at most this duration.
     async fn open_port(
         &mut self,
         conf: &Config,
         remote_addr: IpAddr,
         port: LeasePort,
+        user: Option<UserId>,
+        resource: Option<ResourceId>,
+        max_timeout: Option<Duration>,
     ) -> ah::Result<()>;
 
     /// Remove a rule that opens the specified `port` for the specified `remote_addr`.
@@ -166,4 +390,149 @@ pub trait FirewallOpen {
     ) -> ah::Result<()>;
 }
 
+/// Firewall ban operations.
+pub trait FirewallBan {
+    /// Add a rule that drops all packets from `addr` for the configured `ban-duration`.
+    /// If `addr` is already banned, then the ban is extended.
+    async fn ban_addr(&mut self, conf: &Config, addr: IpAddr) -> ah::Result<()>;
+}
+
+/// Firewall statistics.
+pub trait FirewallStats {
+    /// Get the number of active leases.
+    fn nr_leases(&self) -> usize;
+
+    /// Get the number of active bans.
+    fn nr_bans(&self) -> usize;
+}
+
+/// Firewall lease administration operations.
+pub trait FirewallAdmin {
+    /// Get information about all active leases.
+    async fn list_leases(&mut self, conf: &Config) -> ah::Result<Vec<LeaseInfo>>;
+
+    /// Revoke all leases of `addr`.
+    /// If `port` is given, then only the leases for this port number are revoked.
+    ///
+    /// Returns the number of revoked leases.
+    async fn revoke_leases(
+        &mut self,
+        conf: &Config,
+        addr: IpAddr,
+        port: Option<u16>,
+    ) -> ah::Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    const RESOURCES: &str = "[RESOURCES]\n00000001 = port: 2000\n00000002 = port: 3000\n";
+
+    fn add_lease(conf: &Config, leases: &mut LeaseMap, addr: &str, port: u16, user: u32) {
+        let resource = conf.resource_id_by_port(port, None);
+        let port = LeasePort::Tcp(port);
+        let mut lease = Lease::new(
+            conf,
+            addr.parse().unwrap(),
+            port,
+            Some(user.into()),
+            resource,
+        );
+        // Make the creation order unambiguous.
+        lease.created += Duration::from_secs(leases.len() as u64);
+        leases.insert((lease.addr(), port), lease);
+    }
+
+    fn id(addr: &str, port: u16) -> LeaseId {
+        (addr.parse().unwrap(), LeasePort::Tcp(port))
+    }
+
+    fn res(port: u16) -> Option<ResourceId> {
+        Some((u32::from(port) / 1000 - 1).into())
+    }
+
+    #[test]
+    fn test_lease_quota_excess() {
+        let conf = test_config(&format!(
+            "[GENERAL]\nmax-leases-per-user = 2\nmax-leases-per-resource = 3\n{RESOURCES}"
+        ));
+        let mut leases = LeaseMap::new();
+        add_lease(&conf, &mut leases, "10.0.0.1", 2000, 1);
+        add_lease(&conf, &mut leases, "10.0.0.2", 2000, 2);
+        add_lease(&conf, &mut leases, "10.0.0.3", 3000, 1);
+
+        // Within the quotas.
+        let (exceeded, excess) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.4", 2000),
+            Some(2.into()),
+            res(2000),
+        );
+        assert!(exceeded.is_empty());
+        assert!(excess.is_empty());
+
+        // User 1 already has two leases. The oldest one has to go.
+        let (exceeded, excess) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.4", 3000),
+            Some(1.into()),
+            res(3000),
+        );
+        assert_eq!(exceeded, ["max-leases-per-user"]);
+        assert_eq!(excess, [id("10.0.0.1", 2000)]);
+
+        // Refreshing an existing lease is not counted.
+        let (exceeded, _) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.3", 3000),
+            Some(1.into()),
+            res(3000),
+        );
+        assert!(exceeded.is_empty());
+
+        // Resource 2000 is full. The lease removed for the user quota also frees the resource.
+        add_lease(&conf, &mut leases, "10.0.0.5", 2000, 3);
+        let (exceeded, excess) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.6", 2000),
+            Some(1.into()),
+            res(2000),
+        );
+        assert_eq!(exceeded, ["max-leases-per-user"]);
+        assert_eq!(excess, [id("10.0.0.1", 2000)]);
+        let (exceeded, excess) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.6", 2000),
+            Some(4.into()),
+            res(2000),
+        );
+        assert_eq!(exceeded, ["max-leases-per-resource"]);
+        assert_eq!(excess, [id("10.0.0.1", 2000)]);
+
+        // An unknown resource is looked up by the port number.
+        let (exceeded, _) =
+            lease_quota_excess(&conf, &leases, &id("10.0.0.6", 2000), Some(4.into()), None);
+        assert_eq!(exceeded, ["max-leases-per-resource"]);
+
+        // No quotas.
+        let conf = test_config(RESOURCES);
+        let (exceeded, excess) = lease_quota_excess(
+            &conf,
+            &leases,
+            &id("10.0.0.6", 2000),
+            Some(1.into()),
+            res(2000),
+        );
+        assert!(exceeded.is_empty());
+        assert!(excess.is_empty());
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
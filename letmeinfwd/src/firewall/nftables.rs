@@ -6,21 +6,31 @@
 // or the MIT license, at your option.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use crate::firewall::{
-    prune_all_lease_timeouts, FirewallMaintain, FirewallOpen, Lease, LeaseMap, LeasePort,
-    SingleLeasePort,
+use crate::{
+    firewall::{
+        lease_quota_excess, prune_all_ban_timeouts, prune_all_lease_timeouts, BanMap,
+        FirewallAdmin, FirewallBan, FirewallMaintain, FirewallOpen, FirewallStats, Lease,
+        LeaseCounters, LeaseEnd, LeaseId, LeaseInfo, LeaseMap, LeasePort, SingleLeasePort,
+    },
+    hooks::{run_hook, HookEvent},
 };
 use anyhow::{self as ah, format_err as err, Context as _};
-use letmein_conf::Config;
+use letmein_conf::{Config, LeaseQuotaPolicy, RateLimit, Resource};
+use letmein_proto::{ResourceId, UserId};
 use nftables::{
     batch::Batch,
     expr::{Expression, NamedExpression, Payload, PayloadField},
     helper::{apply_ruleset_with_args_async, get_current_ruleset_with_args_async, DEFAULT_ARGS},
     schema::{Chain, FlushObject, NfCmd, NfListObject, NfObject, Rule},
-    stmt::{Match, Operator, Statement},
+    stmt::{AnonymousCounter, Counter, Limit, Log, Match, Operator, Statement},
     types::NfFamily,
 };
-use std::{borrow::Cow, fmt::Write as _, net::IpAddr};
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
 
 struct NftNames<'a> {
     family: NfFamily,
@@ -115,11 +125,41 @@ fn statement_match_dport<'a>(port: SingleLeasePort) -> Statement<'a> {
     })
 }
 
+/// Create an nftables anonymous `counter` statement.
+fn statement_counter<'a>() -> Statement<'a> {
+    Statement::Counter(Counter::Anonymous(None))
+}
+
+/// Create an nftables `limit rate` statement.
+fn statement_limit<'a>(limit: &RateLimit) -> Statement<'a> {
+    Statement::Limit(Limit {
+        rate: limit.rate,
+        rate_unit: None, // packets
+        per: Some(Cow::Owned(limit.per.to_string())),
+        burst: limit.burst,
+        burst_unit: None,
+        inv: None,
+    })
+}
+
+/// Create an nftables `log prefix` statement.
+fn statement_log(prefix: &str) -> Statement<'_> {
+    Statement::Log(Some(Log {
+        prefix: Some(Cow::Borrowed(prefix)),
+        ..Log::new(None)
+    }))
+}
+
 /// Create an nftables `accept` statement.
 fn statement_accept<'a>() -> Statement<'a> {
     Statement::Accept(None)
 }
 
+/// Create an nftables `drop` statement.
+fn statement_drop<'a>() -> Statement<'a> {
+    Statement::Drop(None)
+}
+
 /// Comment string for a `Rule`.
 /// It can be used as unique identifier for lease rules.
 fn gen_rule_comment(addr: Option<IpAddr>, port: SingleLeasePort) -> ah::Result<String> {
@@ -133,19 +173,79 @@ fn gen_rule_comment(addr: Option<IpAddr>, port: SingleLeasePort) -> ah::Result<S
     Ok(comment)
 }
 
+/// Comment string for a ban `Rule`.
+/// It can be used as unique identifier for ban rules.
+fn gen_ban_comment(addr: IpAddr) -> String {
+    format!("{addr}/drop/letmein/GENERATED")
+}
+
+/// Parse a comment string generated by [gen_rule_comment].
+///
+/// Returns `None`, if the comment has not been generated by letmein.
+fn parse_rule_comment(comment: &str) -> Option<(Option<IpAddr>, SingleLeasePort)> {
+    let comment = comment.strip_suffix("/accept/letmein/GENERATED")?;
+    let mut fields = comment.split('/');
+    let (Some(addr), Some(port), Some(proto), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return None;
+    };
+    let addr = match addr {
+        "any" => None,
+        addr => Some(addr.parse().ok()?),
+    };
+    let port = port.parse().ok()?;
+    let port = match proto {
+        "TCP" => SingleLeasePort::Tcp(port),
+        "UDP" => SingleLeasePort::Udp(port),
+        _ => return None,
+    };
+    Some((addr, port))
+}
+
+/// Get the anonymous counter values from the statements of a rule.
+fn rule_counters(expr: &[Statement<'_>]) -> Option<LeaseCounters> {
+    expr.iter().find_map(|stmt| {
+        if let Statement::Counter(Counter::Anonymous(Some(AnonymousCounter { packets, bytes }))) =
+            stmt
+        {
+            Some(LeaseCounters {
+                packets: packets.unwrap_or(0) as u64,
+                bytes: bytes.unwrap_or(0) as u64,
+            })
+        } else {
+            None
+        }
+    })
+}
+
 /// Generate a nftables add-rule for this addr/port.
 /// This rule will open the port for the IP address.
-fn gen_add_lease_cmd(
-    conf: &Config,
+///
+/// The optional `log` and `limit` statements of the `resource` are added to the rule.
+fn gen_add_lease_cmd<'a>(
+    conf: &'a Config,
     addr: Option<IpAddr>,
     port: SingleLeasePort,
-) -> ah::Result<NfCmd> {
+    resource: Option<&'a Resource>,
+) -> ah::Result<NfCmd<'a>> {
     let names = NftNames::get(conf).context("Read configuration")?;
-    let mut expr = Vec::with_capacity(3);
+    let mut expr = Vec::with_capacity(6);
     if let Some(addr) = addr {
         expr.push(statement_match_saddr(names.family, addr)?);
     }
     expr.push(statement_match_dport(port));
+    if let Some(Resource::Port { log, limit, .. }) = resource {
+        if let Some(limit) = limit {
+            expr.push(statement_limit(limit));
+        }
+        expr.push(statement_counter());
+        if let Some(prefix) = log {
+            expr.push(statement_log(prefix));
+        }
+    } else {
+        expr.push(statement_counter());
+    }
     expr.push(statement_accept());
     let mut rule = Rule {
         family: names.family,
@@ -158,21 +258,124 @@ fn gen_add_lease_cmd(
     Ok(NfCmd::Add(NfListObject::Rule(rule)))
 }
 
+/// Generate a nftables rule that drops all packets from the banned address.
+///
+/// If `insert` is true, then an insert command is generated
+/// that puts the rule in front of all other rules in the chain.
+fn gen_add_ban_cmd(conf: &Config, addr: IpAddr, insert: bool) -> ah::Result<NfCmd<'_>> {
+    let names = NftNames::get(conf).context("Read configuration")?;
+    let expr = vec![
+        statement_match_saddr(names.family, addr)?,
+        statement_counter(),
+        statement_drop(),
+    ];
+    let rule = Rule {
+        family: names.family,
+        table: Cow::Borrowed(names.table),
+        chain: Cow::Borrowed(names.chain_input),
+        expr: Cow::Owned(expr),
+        comment: Some(Cow::Owned(gen_ban_comment(addr))),
+        ..Default::default()
+    };
+    if conf.debug() {
+        println!("nftables: Adding ban rule for {addr}");
+    }
+    if insert {
+        Ok(NfCmd::Insert(NfListObject::Rule(rule)))
+    } else {
+        Ok(NfCmd::Add(NfListObject::Rule(rule)))
+    }
+}
+
+/// Check whether the nftables family, table or chain differ between `old` and `new`.
+fn nft_names_changed(old: &Config, new: &Config) -> bool {
+    old.nft_family() != new.nft_family()
+        || old.nft_table() != new.nft_table()
+        || old.nft_chain_input() != new.nft_chain_input()
+}
+
+/// Get the distinct ports of all letmeind listeners.
+fn control_ports(conf: &Config) -> Vec<SingleLeasePort> {
+    let mut ports = vec![];
+    for listener in conf.listeners() {
+        let port = listener.port.port;
+        for (enabled, p) in [
+            (listener.port.tcp, SingleLeasePort::Tcp(port)),
+            (listener.port.udp, SingleLeasePort::Udp(port)),
+        ] {
+            if enabled && !ports.contains(&p) {
+                ports.push(p);
+            }
+        }
+    }
+    ports
+}
+
+/// Get the configured resource of the `lease`.
+///
+/// Returns `None`, if there is no such resource, if its port or protocol doesn't match
+/// or if the user of the lease is not allowed to use the resource anymore.
+fn lease_resource<'a>(conf: &'a Config, lease: &Lease) -> Option<&'a Resource> {
+    let port = lease.port();
+    if conf.is_control_port(port.port()) {
+        return None;
+    }
+    let res = lease.resource_id(conf).and_then(|id| conf.resource(id))?;
+    let Resource::Port {
+        port: res_port,
+        tcp,
+        udp,
+        ..
+    } = res;
+    let res_port = match (tcp, udp) {
+        (true, true) => LeasePort::TcpUdp(*res_port),
+        (false, true) => LeasePort::Udp(*res_port),
+        _ => LeasePort::Tcp(*res_port),
+    };
+    if let Some(user) = lease.user() {
+        if !res.contains_user(user) {
+            return None;
+        }
+    }
+    (res_port == port).then_some(res)
+}
+
 /// Generate the nftables add-rule commands for this lease.
 /// These commands will open the port(s) for the IP address.
 fn gen_add_lease_cmds<'a>(conf: &'a Config, lease: &Lease) -> ah::Result<Vec<NfCmd<'a>>> {
     let mut cmds = Vec::with_capacity(2);
     let addr = Some(lease.addr());
+    let res = lease.resource_id(conf).and_then(|id| conf.resource(id));
     match lease.port() {
         LeasePort::Tcp(port) => {
-            cmds.push(gen_add_lease_cmd(conf, addr, SingleLeasePort::Tcp(port))?);
+            cmds.push(gen_add_lease_cmd(
+                conf,
+                addr,
+                SingleLeasePort::Tcp(port),
+                res,
+            )?);
         }
         LeasePort::Udp(port) => {
-            cmds.push(gen_add_lease_cmd(conf, addr, SingleLeasePort::Udp(port))?);
+            cmds.push(gen_add_lease_cmd(
+                conf,
+                addr,
+                SingleLeasePort::Udp(port),
+                res,
+            )?);
         }
         LeasePort::TcpUdp(port) => {
-            cmds.push(gen_add_lease_cmd(conf, addr, SingleLeasePort::Tcp(port))?);
-            cmds.push(gen_add_lease_cmd(conf, addr, SingleLeasePort::Udp(port))?);
+            cmds.push(gen_add_lease_cmd(
+                conf,
+                addr,
+                SingleLeasePort::Tcp(port),
+                res,
+            )?);
+            cmds.push(gen_add_lease_cmd(
+                conf,
+                addr,
+                SingleLeasePort::Udp(port),
+                res,
+            )?);
         }
     }
     if conf.debug() {
@@ -181,6 +384,184 @@ fn gen_add_lease_cmds<'a>(conf: &'a Config, lease: &Lease) -> ah::Result<Vec<NfC
     Ok(cmds)
 }
 
+/// Generate a batch that flushes our chain and then adds the ban rules for all `bans`,
+/// the control port rules and the rules for all `leases`.
+///
+/// If `flush_only` is true, then only the flush command is generated.
+///
+/// Returns the batch and the number of generated control port rules.
+fn gen_full_rebuild_batch<'a, 'b>(
+    conf: &'a Config,
+    bans: impl Iterator<Item = &'b IpAddr>,
+    leases: impl Iterator<Item = &'b Lease>,
+    flush_only: bool,
+) -> ah::Result<(Batch<'a>, u8)> {
+    let names = NftNames::get(conf).context("Read configuration")?;
+
+    let mut batch = Batch::new();
+
+    // Remove all rules from our chain.
+    batch.add_cmd(NfCmd::Flush(FlushObject::Chain(Chain {
+        family: names.family,
+        table: Cow::Borrowed(names.table),
+        name: Cow::Borrowed(names.chain_input),
+        ..Default::default()
+    })));
+    if conf.debug() {
+        println!("nftables: Chain flushed");
+    }
+
+    let mut num_ctrl_rules = 0;
+    if !flush_only {
+        // Drop all packets from banned addresses.
+        for addr in bans {
+            batch.add_cmd(gen_add_ban_cmd(conf, *addr, false)?);
+        }
+
+        // Open the ports letmeind is listening on.
+        for p in control_ports(conf) {
+            batch.add_cmd(gen_add_lease_cmd(conf, None, p, None)?);
+            if conf.debug() {
+                println!("nftables: Adding control port rule for port={p}");
+            }
+            num_ctrl_rules += 1;
+        }
+
+        // Open all lease ports, restricted to the peer addresses.
+        for lease in leases {
+            for cmd in gen_add_lease_cmds(conf, lease)? {
+                batch.add_cmd(cmd);
+            }
+        }
+    }
+
+    Ok((batch, num_ctrl_rules))
+}
+
+/// Get the `nft` script keyword for a family.
+fn render_family(family: NfFamily) -> &'static str {
+    match family {
+        NfFamily::IP => "ip",
+        NfFamily::IP6 => "ip6",
+        NfFamily::INet => "inet",
+        NfFamily::ARP => "arp",
+        NfFamily::Bridge => "bridge",
+        NfFamily::NetDev => "netdev",
+    }
+}
+
+/// Render one of our generated statements as `nft` script text.
+fn render_statement(stmt: &Statement<'_>) -> ah::Result<String> {
+    Ok(match stmt {
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(field))),
+            right,
+            op: Operator::EQ,
+        }) => {
+            let right = match right {
+                Expression::String(s) => s.to_string(),
+                Expression::Number(n) => n.to_string(),
+                _ => return Err(err!("Unsupported match value: {right:?}")),
+            };
+            format!("{} {} {right}", field.protocol, field.field)
+        }
+        Statement::Counter(Counter::Anonymous(None)) => "counter".to_string(),
+        Statement::Limit(Limit {
+            rate,
+            rate_unit: None,
+            per: Some(per),
+            burst,
+            burst_unit: None,
+            inv: None,
+        }) => {
+            let mut text = format!("limit rate {rate}/{per}");
+            if let Some(burst) = burst {
+                write!(&mut text, " burst {burst} packets")?;
+            }
+            text
+        }
+        Statement::Log(None) => "log".to_string(),
+        Statement::Log(Some(Log {
+            prefix: Some(prefix),
+            ..
+        })) => format!("log prefix \"{prefix}\""),
+        Statement::Accept(None) => "accept".to_string(),
+        _ => return Err(err!("Unsupported statement: {stmt:?}")),
+    })
+}
+
+/// Render a batch of our generated commands as `nft` script text.
+fn render_batch_text(batch: Batch<'_>) -> ah::Result<String> {
+    let mut text = String::with_capacity(4096);
+    for obj in batch.to_nftables().objects.iter() {
+        match obj {
+            NfObject::CmdObject(NfCmd::Flush(FlushObject::Chain(chain))) => {
+                writeln!(
+                    &mut text,
+                    "flush chain {} {} {}",
+                    render_family(chain.family),
+                    chain.table,
+                    chain.name
+                )?;
+            }
+            NfObject::CmdObject(NfCmd::Add(NfListObject::Rule(rule))) => {
+                write!(
+                    &mut text,
+                    "add rule {} {} {}",
+                    render_family(rule.family),
+                    rule.table,
+                    rule.chain
+                )?;
+                for stmt in rule.expr.iter() {
+                    write!(&mut text, " {}", render_statement(stmt)?)?;
+                }
+                if let Some(comment) = &rule.comment {
+                    write!(&mut text, " comment \"{comment}\"")?;
+                }
+                writeln!(&mut text)?;
+            }
+            _ => return Err(err!("Unsupported nftables command: {obj:?}")),
+        }
+    }
+    Ok(text)
+}
+
+/// Render the complete ruleset for the control port and the given `leases`
+/// without applying it to the kernel.
+///
+/// The result is either `nft` script text or the nftables JSON
+/// that would be passed to `nft -j -f -`.
+pub fn render_ruleset(conf: &Config, leases: &[Lease], json: bool) -> ah::Result<String> {
+    let (batch, _) = gen_full_rebuild_batch(conf, std::iter::empty(), leases.iter(), false)?;
+    if json {
+        let mut text = serde_json::to_string_pretty(&batch.to_nftables())
+            .context("Serialize nftables JSON")?;
+        text.push('\n');
+        Ok(text)
+    } else {
+        render_batch_text(batch)
+    }
+}
+
+/// A letmein generated rule in the kernel ruleset.
+pub struct GeneratedRule {
+    /// The source address of the rule. `None` for the control port rules.
+    pub addr: Option<IpAddr>,
+    /// The port of the rule.
+    pub port: SingleLeasePort,
+    /// The nftables handle of the rule.
+    pub handle: Option<u32>,
+    /// The packet and byte counters of the rule.
+    pub counters: Option<LeaseCounters>,
+}
+
+/// Get all letmein generated rules in the configured family/table/chain from the kernel.
+pub async fn list_generated_rules(conf: &Config) -> ah::Result<Vec<GeneratedRule>> {
+    ListedRuleset::from_kernel(conf)
+        .await?
+        .generated_rules(conf)
+}
+
 struct ListedRuleset<'a> {
     objs: Cow<'a, [NfObject<'static>]>,
 }
@@ -188,45 +569,23 @@ struct ListedRuleset<'a> {
 impl ListedRuleset<'_> {
     /// Get the active ruleset from the kernel.
     pub async fn from_kernel(conf: &Config) -> ah::Result<Self> {
-        println!("firewall: Retrieving current ruleset from kernel...");
-        println!("firewall: Using nft executable: {}", conf.nft_exe().display());
-        println!("firewall: Using args: {:?}", DEFAULT_ARGS);
-        
-        // Try to execute manually first for debugging
-        use std::process::Command;
-        let output = Command::new(conf.nft_exe())
-            .arg("list")
-            .arg("ruleset")
-            .output();
-            
-        match output {
-            Ok(out) => {
-                println!("firewall: Manual nft list ruleset result:");
-                println!("  Status: {}", out.status);
-                println!("  stdout: {}", String::from_utf8_lossy(&out.stdout));
-                println!("  stderr: {}", String::from_utf8_lossy(&out.stderr));
-            },
-            Err(e) => {
-                println!("firewall: Manual nft command error: {}", e);
-            }
-        }
-        
-        // Now try with the library function
-        match get_current_ruleset_with_args_async(
+        let ruleset = get_current_ruleset_with_args_async(
             Some(conf.nft_exe()), // program
             DEFAULT_ARGS,         // args
-        ).await {
-            Ok(ruleset) => {
-                println!("firewall: Retrieved {} objects from kernel", ruleset.objects.len());
-                Ok(Self {
-                    objs: ruleset.objects,
-                })
-            },
-            Err(e) => {
-                println!("firewall: Error getting ruleset from kernel: {:?}", e);
-                Err(err!("\"nft\" did not return successfully while getting the current ruleset"))
-            }
+        )
+        .await
+        .map_err(|e| {
+            err!("\"nft\" did not return successfully while getting the current ruleset: {e}")
+        })?;
+        if conf.debug() {
+            println!(
+                "nftables: Retrieved {} objects from the kernel",
+                ruleset.objects.len()
+            );
         }
+        Ok(Self {
+            objs: ruleset.objects,
+        })
     }
 
     /// Get the nftables handle corresponding to the lease.
@@ -277,6 +636,144 @@ impl ListedRuleset<'_> {
         ))
     }
 
+    /// Get the nftables handle of the rule with the `comment`.
+    fn find_handle_by_comment(&self, names: &NftNames<'_>, comment: &str) -> Option<u32> {
+        self.objs.iter().find_map(|obj| match obj {
+            NfObject::ListObject(NfListObject::Rule(Rule {
+                family,
+                table,
+                chain,
+                handle: Some(handle),
+                comment: Some(rule_comment),
+                ..
+            })) if *family == names.family
+                && *table == names.table
+                && *chain == names.chain_input
+                && *rule_comment == comment =>
+            {
+                Some(*handle)
+            }
+            _ => None,
+        })
+    }
+
+    /// Generate the nftables delete-rule command for the ban rule of `addr`.
+    pub fn gen_delete_ban_cmd<'a>(
+        &self,
+        conf: &'a Config,
+        addr: IpAddr,
+    ) -> ah::Result<Option<NfCmd<'a>>> {
+        let names = NftNames::get(conf).context("Read configuration")?;
+        let Some(handle) = self.find_handle_by_comment(&names, &gen_ban_comment(addr)) else {
+            return Ok(None);
+        };
+        if conf.debug() {
+            println!("nftables: Deleting ban rule for {addr}");
+        }
+        Ok(Some(NfCmd::Delete(NfListObject::Rule(Rule {
+            family: names.family,
+            table: Cow::Borrowed(names.table),
+            chain: Cow::Borrowed(names.chain_input),
+            expr: Cow::Owned(vec![]),
+            handle: Some(handle),
+            ..Default::default()
+        }))))
+    }
+
+    /// Get the packet and byte counters of the rule for the addr/port.
+    fn find_counters(
+        &self,
+        family: NfFamily,
+        table: &str,
+        chain_input: &str,
+        addr: IpAddr,
+        port: SingleLeasePort,
+    ) -> ah::Result<Option<LeaseCounters>> {
+        let comment = gen_rule_comment(Some(addr), port)?;
+        for obj in &*self.objs {
+            if let NfObject::ListObject(NfListObject::Rule(Rule {
+                family: rule_family,
+                table: rule_table,
+                chain: rule_chain,
+                expr: rule_expr,
+                comment: Some(rule_comment),
+                ..
+            })) = obj
+            {
+                if *rule_family == family
+                    && *rule_table == table
+                    && *rule_chain == chain_input
+                    && *rule_comment == comment
+                {
+                    return Ok(rule_counters(rule_expr));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get all letmein generated rules from our chain.
+    fn generated_rules(&self, conf: &Config) -> ah::Result<Vec<GeneratedRule>> {
+        let names = NftNames::get(conf).context("Read configuration")?;
+        let mut rules = vec![];
+        for obj in &*self.objs {
+            if let NfObject::ListObject(NfListObject::Rule(Rule {
+                family: rule_family,
+                table: rule_table,
+                chain: rule_chain,
+                expr: rule_expr,
+                handle: rule_handle,
+                comment: Some(rule_comment),
+                ..
+            })) = obj
+            {
+                if *rule_family == names.family
+                    && *rule_table == names.table
+                    && *rule_chain == names.chain_input
+                {
+                    if let Some((addr, port)) = parse_rule_comment(rule_comment) {
+                        rules.push(GeneratedRule {
+                            addr,
+                            port,
+                            handle: *rule_handle,
+                            counters: rule_counters(rule_expr),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Get the sum of the packet and byte counters of all rules of this lease.
+    ///
+    /// Returns `None`, if no counter could be found in the kernel ruleset.
+    pub fn lease_counters(
+        &self,
+        conf: &Config,
+        lease: &Lease,
+    ) -> ah::Result<Option<LeaseCounters>> {
+        let names = NftNames::get(conf).context("Read configuration")?;
+        let ports = match lease.port() {
+            LeasePort::Tcp(port) => vec![SingleLeasePort::Tcp(port)],
+            LeasePort::Udp(port) => vec![SingleLeasePort::Udp(port)],
+            LeasePort::TcpUdp(port) => vec![SingleLeasePort::Tcp(port), SingleLeasePort::Udp(port)],
+        };
+        let mut sum: Option<LeaseCounters> = None;
+        for port in ports {
+            if let Some(counters) = self.find_counters(
+                names.family,
+                names.table,
+                names.chain_input,
+                lease.addr(),
+                port,
+            )? {
+                *sum.get_or_insert_with(Default::default) += counters;
+            }
+        }
+        Ok(sum)
+    }
+
     /// Generate nftables delete-rules for this lease.
     /// These rules will close the ports for the IP address.
     pub fn gen_delete_lease_cmds<'a>(
@@ -355,6 +852,7 @@ impl ListedRuleset<'_> {
 
 pub struct NftFirewall {
     leases: LeaseMap,
+    bans: BanMap,
     shutdown: bool,
     num_ctrl_rules: u8,
 }
@@ -378,6 +876,7 @@ impl NftFirewall {
 
         let mut this = Self {
             leases: LeaseMap::new(),
+            bans: BanMap::new(),
             shutdown: false,
             num_ctrl_rules: 0,
         };
@@ -394,6 +893,7 @@ impl NftFirewall {
     fn print_total_rule_count(&self, conf: &Config) {
         if conf.debug() {
             let mut count: usize = self.num_ctrl_rules.into();
+            count += self.bans.len();
             for lease in self.leases.values() {
                 count += match lease.port() {
                     LeasePort::Tcp(_) | LeasePort::Udp(_) => 1,
@@ -419,48 +919,9 @@ impl NftFirewall {
 
     /// Generate all nftables rules and apply them to the kernel after flushing the chain.
     async fn nftables_full_rebuild(&mut self, conf: &Config) -> ah::Result<()> {
-        let names = NftNames::get(conf).context("Read configuration")?;
-
-        let mut batch = Batch::new();
-
-        // Remove all rules from our chain.
-        batch.add_cmd(NfCmd::Flush(FlushObject::Chain(Chain {
-            family: names.family,
-            table: Cow::Borrowed(names.table),
-            name: Cow::Borrowed(names.chain_input),
-            ..Default::default()
-        })));
-        if conf.debug() {
-            println!("nftables: Chain flushed");
-        }
-
-        self.num_ctrl_rules = 0;
-        if !self.shutdown {
-            // Open the port letmeind is listening on.
-            if conf.port().tcp {
-                let p = SingleLeasePort::Tcp(conf.port().port);
-                batch.add_cmd(gen_add_lease_cmd(conf, None, p)?);
-                if conf.debug() {
-                    println!("nftables: Adding control port rule for port={p}");
-                }
-                self.num_ctrl_rules += 1;
-            }
-            if conf.port().udp {
-                let p = SingleLeasePort::Udp(conf.port().port);
-                batch.add_cmd(gen_add_lease_cmd(conf, None, p)?);
-                if conf.debug() {
-                    println!("nftables: Adding control port rule for port={p}");
-                }
-                self.num_ctrl_rules += 1;
-            }
-
-            // Open all lease ports, restricted to the peer addresses.
-            for lease in self.leases.values() {
-                for cmd in gen_add_lease_cmds(conf, lease)? {
-                    batch.add_cmd(cmd);
-                }
-            }
-        }
+        let (batch, num_ctrl_rules) =
+            gen_full_rebuild_batch(conf, self.bans.keys(), self.leases.values(), self.shutdown)?;
+        self.num_ctrl_rules = num_ctrl_rules;
 
         // Apply all batch commands to the kernel.
         self.nftables_apply_batch(conf, batch).await
@@ -478,8 +939,47 @@ impl NftFirewall {
         self.nftables_apply_batch(conf, batch).await
     }
 
+    /// Print the usage statistics of leases that are about to be removed.
+    fn print_lease_usage(
+        conf: &Config,
+        ruleset: &ListedRuleset<'_>,
+        leases: &[Lease],
+        end: LeaseEnd,
+    ) {
+        let now = Instant::now();
+        for lease in leases {
+            let age = lease.age(now).as_secs();
+            match ruleset.lease_counters(conf, lease) {
+                Ok(Some(counters)) => {
+                    println!("firewall: {lease} {end} after {age}s, {counters}");
+                }
+                Ok(None) | Err(_) => {
+                    println!("firewall: {lease} {end} after {age}s, no counters available");
+                }
+            }
+        }
+    }
+
+    /// Run the hooks of the leases that have been removed.
+    fn run_end_hooks(conf: &Config, leases: &[Lease], end: LeaseEnd) {
+        let event = match end {
+            LeaseEnd::Expired => HookEvent::Expire,
+            LeaseEnd::Closed => HookEvent::Close,
+            LeaseEnd::Revoked => HookEvent::Revoke,
+            LeaseEnd::Evicted => HookEvent::Evict,
+        };
+        for lease in leases {
+            run_hook(conf, event, lease);
+        }
+    }
+
     /// Remove an existing lease rule from the kernel.
-    async fn nftables_remove_leases(&mut self, conf: &Config, leases: &[Lease]) -> ah::Result<()> {
+    async fn nftables_remove_leases(
+        &mut self,
+        conf: &Config,
+        leases: &[Lease],
+        end: LeaseEnd,
+    ) -> ah::Result<()> {
         if !leases.is_empty() {
             // Get the active ruleset from the kernel.
             let ruleset = match ListedRuleset::from_kernel(conf).await {
@@ -496,6 +996,9 @@ impl NftFirewall {
                 }
             };
 
+            // Report the usage of the leases before the counters are gone.
+            Self::print_lease_usage(conf, &ruleset, leases, end);
+
             // Add delete commands to remove the lease ports.
             let mut batch = Batch::new();
             for lease in leases {
@@ -517,6 +1020,103 @@ impl NftFirewall {
         }
         Ok(())
     }
+
+    /// Remove the ban rules of the addresses from the kernel.
+    async fn nftables_remove_bans(&mut self, conf: &Config, addrs: &[IpAddr]) -> ah::Result<()> {
+        let ruleset = ListedRuleset::from_kernel(conf).await?;
+        let mut batch = Batch::new();
+        for addr in addrs {
+            if let Some(cmd) = ruleset.gen_delete_ban_cmd(conf, *addr)? {
+                batch.add_cmd(cmd);
+            }
+        }
+        self.nftables_apply_batch(conf, batch).await
+    }
+
+    /// Make room for a new lease `id` of `user` and `resource` within the lease quotas.
+    ///
+    /// Depending on the `lease-quota-policy`, this either fails
+    /// or removes the oldest leases that exceed the quotas.
+    async fn enforce_lease_quotas(
+        &mut self,
+        conf: &Config,
+        id: &LeaseId,
+        user: Option<UserId>,
+        resource: Option<ResourceId>,
+    ) -> ah::Result<()> {
+        let (exceeded, excess) = lease_quota_excess(conf, &self.leases, id, user, resource);
+        if exceeded.is_empty() {
+            return Ok(());
+        }
+        let (addr, port) = id;
+        let user = user.map(|u| u.to_string()).unwrap_or_default();
+        match conf.lease_quota_policy() {
+            LeaseQuotaPolicy::Reject => {
+                let e = err!(
+                    "Lease for {addr} port {port} user {user} rejected: {} exceeded",
+                    exceeded.join(" and ")
+                );
+                println!("firewall: {e}");
+                Err(e)
+            }
+            LeaseQuotaPolicy::EvictOldest => {
+                let evicted: Vec<Lease> = excess
+                    .iter()
+                    .filter_map(|lid| self.leases.remove(lid))
+                    .collect();
+                for lease in &evicted {
+                    println!(
+                        "firewall: {lease} evicted for {addr} port {port} user {user}: {} exceeded",
+                        exceeded.join(" and ")
+                    );
+                }
+                if let Err(e) = self
+                    .nftables_remove_leases(conf, &evicted, LeaseEnd::Evicted)
+                    .await
+                {
+                    eprintln!("WARNING: Failed to remove lease(s): '{e}'.");
+                    eprintln!("Trying full rebuild.");
+                    self.nftables_full_rebuild(conf).await?;
+                }
+                self.print_total_rule_count(conf);
+                Self::run_end_hooks(conf, &evicted, LeaseEnd::Evicted);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check whether switching from the `old` to the `new` configuration
+    /// changes the rules of the control port or of the remaining leases.
+    fn reload_needs_rebuild(&self, old: &Config, new: &Config) -> bool {
+        nft_names_changed(old, new)
+            || control_ports(old) != control_ports(new)
+            || self
+                .leases
+                .values()
+                .any(|lease| lease_resource(old, lease) != lease_resource(new, lease))
+    }
+
+    /// Remove all rules from the chain of the `old` configuration and
+    /// build the rules of the `new` configuration.
+    async fn reload_rebuild(&mut self, old: &Config, new: &Config) -> ah::Result<()> {
+        let chain_changed = nft_names_changed(old, new);
+        if chain_changed {
+            let (batch, _) = gen_full_rebuild_batch(old, [].iter(), [].iter(), true)?;
+            self.nftables_apply_batch(old, batch)
+                .await
+                .context("Flush old chain")?;
+        }
+        if let Err(e) = self.nftables_full_rebuild(new).await {
+            if chain_changed {
+                // Try to restore the old rules.
+                if let Err(e) = self.nftables_full_rebuild(old).await {
+                    eprintln!("WARNING: Failed to restore the old rules: '{e}'.");
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
 }
 
 impl FirewallMaintain for NftFirewall {
@@ -525,6 +1125,7 @@ impl FirewallMaintain for NftFirewall {
         assert!(!self.shutdown);
         self.shutdown = true;
         self.leases.clear();
+        self.bans.clear();
         self.nftables_full_rebuild(conf).await?;
         self.print_total_rule_count(conf);
         Ok(())
@@ -536,15 +1137,152 @@ impl FirewallMaintain for NftFirewall {
         assert!(!self.shutdown);
         let pruned = prune_all_lease_timeouts(conf, &mut self.leases);
         if !pruned.is_empty() {
-            if let Err(e) = self.nftables_remove_leases(conf, &pruned).await {
+            if let Err(e) = self
+                .nftables_remove_leases(conf, &pruned, LeaseEnd::Expired)
+                .await
+            {
                 eprintln!("WARNING: Failed to remove lease(s): '{e}'.");
                 eprintln!("Trying full rebuild.");
                 self.nftables_full_rebuild(conf).await?;
             }
             self.print_total_rule_count(conf);
+            Self::run_end_hooks(conf, &pruned, LeaseEnd::Expired);
+        }
+        let unbanned = prune_all_ban_timeouts(conf, &mut self.bans);
+        if !unbanned.is_empty() {
+            if let Err(e) = self.nftables_remove_bans(conf, &unbanned).await {
+                eprintln!("WARNING: Failed to remove ban(s): '{e}'.");
+                eprintln!("Trying full rebuild.");
+                self.nftables_full_rebuild(conf).await?;
+            }
+            self.print_total_rule_count(conf);
         }
         Ok(())
     }
+
+    /// Switch to the `new` configuration.
+    /// Leases whose resource has been removed, changed its port or protocol
+    /// or doesn't allow the user of the lease anymore are revoked.
+    async fn reload(&mut self, old: &Config, new: &Config) -> ah::Result<()> {
+        assert!(!self.shutdown);
+        let mut removed = vec![];
+        self.leases.retain(|_, lease| {
+            let keep = lease_resource(new, lease).is_some();
+            if !keep {
+                removed.push(lease.clone());
+            }
+            keep
+        });
+
+        if self.reload_needs_rebuild(old, new) {
+            if let Err(e) = self.reload_rebuild(old, new).await {
+                // Keep the old state.
+                for lease in removed {
+                    self.leases.insert((lease.addr(), lease.port()), lease);
+                }
+                return Err(e);
+            }
+        } else if !removed.is_empty() {
+            if let Err(e) = self
+                .nftables_remove_leases(new, &removed, LeaseEnd::Revoked)
+                .await
+            {
+                eprintln!("WARNING: Failed to remove lease(s): '{e}'.");
+                eprintln!("Trying full rebuild.");
+                self.nftables_full_rebuild(new).await?;
+            }
+        }
+        for lease in &removed {
+            println!("firewall: {lease} revoked by the new configuration.");
+        }
+        Self::run_end_hooks(new, &removed, LeaseEnd::Revoked);
+        self.print_total_rule_count(new);
+        Ok(())
+    }
+}
+
+impl FirewallBan for NftFirewall {
+    /// Ban the address by inserting a drop rule in front of all other rules.
+    async fn ban_addr(&mut self, conf: &Config, addr: IpAddr) -> ah::Result<()> {
+        assert!(!self.shutdown);
+        let until = Instant::now() + conf.ban_duration();
+        if let Some(ban) = self.bans.get_mut(&addr) {
+            *ban = until;
+        } else {
+            let mut batch = Batch::new();
+            batch.add_cmd(gen_add_ban_cmd(conf, addr, true)?);
+            self.nftables_apply_batch(conf, batch).await?;
+            self.bans.insert(addr, until);
+            self.print_total_rule_count(conf);
+        }
+        Ok(())
+    }
+}
+
+impl FirewallAdmin for NftFirewall {
+    /// Get information about all active leases.
+    /// The counters are read from the kernel ruleset.
+    async fn list_leases(&mut self, conf: &Config) -> ah::Result<Vec<LeaseInfo>> {
+        assert!(!self.shutdown);
+        if self.leases.is_empty() {
+            return Ok(vec![]);
+        }
+        let ruleset = ListedRuleset::from_kernel(conf).await?;
+        let now = Instant::now();
+        let mut infos = Vec::with_capacity(self.leases.len());
+        for lease in self.leases.values() {
+            infos.push(LeaseInfo {
+                addr: lease.addr(),
+                port: lease.port(),
+                remaining: lease.remaining(now),
+                counters: ruleset.lease_counters(conf, lease)?,
+            });
+        }
+        infos.sort_by_key(|info| (info.addr, info.port.port()));
+        Ok(infos)
+    }
+
+    /// Remove the matching leases and close their ports.
+    async fn revoke_leases(
+        &mut self,
+        conf: &Config,
+        addr: IpAddr,
+        port: Option<u16>,
+    ) -> ah::Result<usize> {
+        assert!(!self.shutdown);
+        let mut revoked = vec![];
+        self.leases.retain(|_, lease| {
+            let matches =
+                lease.addr() == addr && (port.is_none() || port == Some(lease.port().port()));
+            if matches {
+                revoked.push(lease.clone());
+            }
+            !matches
+        });
+        if !revoked.is_empty() {
+            if let Err(e) = self
+                .nftables_remove_leases(conf, &revoked, LeaseEnd::Revoked)
+                .await
+            {
+                eprintln!("WARNING: Failed to remove lease(s): '{e}'.");
+                eprintln!("Trying full rebuild.");
+                self.nftables_full_rebuild(conf).await?;
+            }
+            self.print_total_rule_count(conf);
+            Self::run_end_hooks(conf, &revoked, LeaseEnd::Revoked);
+        }
+        Ok(revoked.len())
+    }
+}
+
+impl FirewallStats for NftFirewall {
+    fn nr_leases(&self) -> usize {
+        self.leases.len()
+    }
+
+    fn nr_bans(&self) -> usize {
+        self.bans.len()
+    }
 }
 
 impl FirewallOpen for NftFirewall {
@@ -556,14 +1294,37 @@ impl FirewallOpen for NftFirewall {
         conf: &Config,
         remote_addr: IpAddr,
         port: LeasePort,
+        user: Option<UserId>,
+        resource: Option<ResourceId>,
+        max_timeout: Option<Duration>,
     ) -> ah::Result<()> {
         assert!(!self.shutdown);
         let id = (remote_addr, port);
+
+        // Refreshing the own lease does not count against the quotas.
+        if self
+            .leases
+            .get(&id)
+            .map(|lease| (lease.user(), lease.resource()))
+            != Some((user, resource))
+        {
+            self.enforce_lease_quotas(conf, &id, user, resource).await?;
+        }
+
         if let Some(lease) = self.leases.get_mut(&id) {
+            lease.set_user(user);
+            lease.set_resource(resource);
             lease.refresh_timeout(conf);
+            if let Some(max_timeout) = max_timeout {
+                lease.limit_timeout(max_timeout);
+            }
         } else {
-            let lease = Lease::new(conf, remote_addr, port);
+            let mut lease = Lease::new(conf, remote_addr, port, user, resource);
+            if let Some(max_timeout) = max_timeout {
+                lease.limit_timeout(max_timeout);
+            }
             self.nftables_add_lease(conf, &lease).await?;
+            run_hook(conf, HookEvent::Open, &lease);
             self.leases.insert(id, lease);
             self.print_total_rule_count(conf);
         }
@@ -595,20 +1356,21 @@ impl FirewallOpen for NftFirewall {
             let leases = vec![lease];
             
             // Remove lease from kernel (error handling is done in nftables_remove_leases)
-            self.nftables_remove_leases(conf, &leases).await?;
+            self.nftables_remove_leases(conf, &leases, LeaseEnd::Closed).await?;
             self.print_total_rule_count(conf);
+            Self::run_end_hooks(conf, &leases, LeaseEnd::Closed);
             println!("firewall: Successfully removed lease for {remote_addr} port {port}");
         } else {
             println!("firewall: No lease found in memory for {remote_addr} port {port}");
             
             // Try to remove from kernel anyway
             println!("firewall: Attempting to remove directly from kernel without lease in memory");
-            let fake_lease = Lease::new(conf, remote_addr, port);
+            let fake_lease = Lease::new(conf, remote_addr, port, None, None);
             let leases = vec![fake_lease];
             
             // Attempt to remove from kernel, but don't fail if kernel operation fails in test mode
             // Remove from kernel directly (error handling is done in nftables_remove_leases)
-            self.nftables_remove_leases(conf, &leases).await?;
+            self.nftables_remove_leases(conf, &leases, LeaseEnd::Closed).await?;
             self.print_total_rule_count(conf);
             println!("firewall: Successfully removed rule directly from kernel for {remote_addr} port {port}");
         }
@@ -616,4 +1378,201 @@ impl FirewallOpen for NftFirewall {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use serde_json::json;
+
+    /// The nftables options. The test resources follow this header.
+    const NFTABLES: &str =
+        "[NFTABLES]\nfamily = inet\ntable = filter\nchain-input = LETMEIN-INPUT\n[RESOURCES]\n";
+
+    fn gen_rule_exprs(conf: &Config, lease: &Lease) -> serde_json::Value {
+        let cmds = gen_add_lease_cmds(conf, lease).unwrap();
+        assert_eq!(cmds.len(), 1);
+        let value = serde_json::to_value(&cmds[0]).unwrap();
+        value["add"]["rule"]["expr"].clone()
+    }
+
+    #[test]
+    fn test_lease_resource() {
+        let conf = test_config(&format!(
+            "{NFTABLES}00000001 = port: 2000\n00000002 = port: 3000 / tcp,udp\n"
+        ));
+        let lease = |port, user: Option<u32>, resource: Option<u32>| {
+            let addr = "10.0.0.1".parse().unwrap();
+            Lease::new(
+                &conf,
+                addr,
+                port,
+                user.map(Into::into),
+                resource.map(Into::into),
+            )
+        };
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(2000), None, None)).is_some());
+        assert!(lease_resource(&conf, &lease(LeasePort::Udp(2000), None, None)).is_none());
+        assert!(lease_resource(&conf, &lease(LeasePort::TcpUdp(3000), None, None)).is_some());
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(3000), None, None)).is_none());
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(4000), None, None)).is_none());
+
+        // The recorded resource of the lease is used.
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(2000), None, Some(1))).is_some());
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(2000), None, Some(2))).is_none());
+        assert!(lease_resource(&conf, &lease(LeasePort::Tcp(2000), None, Some(3))).is_none());
+
+        let new_conf = test_config(&format!(
+            "{NFTABLES}00000001 = port: 2000 / log: ssh-knock / users: 5\n"
+        ));
+        let old_lease = lease(LeasePort::Tcp(2000), Some(5), Some(1));
+        assert_ne!(
+            lease_resource(&conf, &old_lease),
+            lease_resource(&new_conf, &old_lease)
+        );
+        assert!(lease_resource(&new_conf, &lease(LeasePort::TcpUdp(3000), None, None)).is_none());
+
+        // The user of the lease is not allowed to use the resource anymore.
+        let user_lease = lease(LeasePort::Tcp(2000), Some(6), Some(1));
+        assert!(lease_resource(&conf, &user_lease).is_some());
+        assert!(lease_resource(&new_conf, &user_lease).is_none());
+    }
+
+    #[test]
+    fn test_lease_rule_plain() {
+        let conf = test_config(&format!("{NFTABLES}00000001 = port: 2000\n"));
+        let lease = Lease::new(
+            &conf,
+            "10.0.0.1".parse().unwrap(),
+            LeasePort::Tcp(2000),
+            None,
+            None,
+        );
+        let expr = gen_rule_exprs(&conf, &lease);
+        assert_eq!(expr.as_array().unwrap().len(), 4);
+        assert_eq!(expr[2], json!({"counter": null}));
+        assert_eq!(expr[3], json!({"accept": null}));
+    }
+
+    #[test]
+    fn test_lease_rule_log_limit() {
+        let conf = test_config(&format!(
+            "{NFTABLES}00000001 = port: 2000 / log: ssh-knock / limit: 3 per minute / burst: 5\n"
+        ));
+        let lease = Lease::new(
+            &conf,
+            "10.0.0.1".parse().unwrap(),
+            LeasePort::Tcp(2000),
+            None,
+            None,
+        );
+        let expr = gen_rule_exprs(&conf, &lease);
+        assert_eq!(expr.as_array().unwrap().len(), 6);
+        assert_eq!(
+            expr[2],
+            json!({"limit": {"rate": 3, "per": "minute", "burst": 5}})
+        );
+        assert_eq!(expr[3], json!({"counter": null}));
+        assert_eq!(expr[4], json!({"log": {"prefix": "ssh-knock"}}));
+        assert_eq!(expr[5], json!({"accept": null}));
+    }
+
+    #[test]
+    fn test_lease_rule_default_log_prefix() {
+        let conf = test_config(&format!("{NFTABLES}00000001 = port: 2000 / log\n"));
+        let lease = Lease::new(
+            &conf,
+            "10.0.0.1".parse().unwrap(),
+            LeasePort::Udp(2000),
+            None,
+            None,
+        );
+        let expr = gen_rule_exprs(&conf, &lease);
+        assert_eq!(expr.as_array().unwrap().len(), 5);
+        assert_eq!(expr[3], json!({"log": {"prefix": "letmein: "}}));
+    }
+
+    #[test]
+    fn test_ban_rule() {
+        let conf = test_config(NFTABLES);
+        let cmd = gen_add_ban_cmd(&conf, "10.0.0.2".parse().unwrap(), true).unwrap();
+        let value = serde_json::to_value(&cmd).unwrap();
+        let rule = &value["insert"]["rule"];
+        assert_eq!(rule["comment"], json!("10.0.0.2/drop/letmein/GENERATED"));
+        let expr = rule["expr"].as_array().unwrap();
+        assert_eq!(expr.len(), 3);
+        assert_eq!(expr[1], json!({"counter": null}));
+        assert_eq!(expr[2], json!({"drop": null}));
+    }
+
+    #[test]
+    fn test_control_ports() {
+        let conf = test_config(&format!(
+            "[GENERAL]\nlisten = 192.0.2.1:5800, 10.0.0.1:5810, 10.0.0.1:5810/udp, [::1]:5800\n\
+            {NFTABLES}"
+        ));
+        assert_eq!(
+            control_ports(&conf),
+            [
+                SingleLeasePort::Tcp(5800),
+                SingleLeasePort::Tcp(5810),
+                SingleLeasePort::Udp(5810),
+            ]
+        );
+        let (_, num_ctrl_rules) =
+            gen_full_rebuild_batch(&conf, [].iter(), [].iter(), false).unwrap();
+        assert_eq!(num_ctrl_rules, 3);
+    }
+
+    #[test]
+    fn test_render_text() {
+        let conf = test_config(&format!(
+            "{NFTABLES}00000001 = port: 2000 / log / limit: 3 per minute / burst: 5\n"
+        ));
+        let leases = [Lease::new(
+            &conf,
+            "10.0.0.1".parse().unwrap(),
+            LeasePort::Tcp(2000),
+            None,
+            None,
+        )];
+        let text = render_ruleset(&conf, &leases, false).unwrap();
+        assert_eq!(
+            text,
+            "flush chain inet filter LETMEIN-INPUT\n\
+            add rule inet filter LETMEIN-INPUT tcp dport 5800 counter accept \
+            comment \"any/5800/TCP/accept/letmein/GENERATED\"\n\
+            add rule inet filter LETMEIN-INPUT ip saddr 10.0.0.1 tcp dport 2000 \
+            limit rate 3/minute burst 5 packets counter log prefix \"letmein: \" accept \
+            comment \"10.0.0.1/2000/TCP/accept/letmein/GENERATED\"\n"
+        );
+    }
+
+    #[test]
+    fn test_rule_comment() {
+        let addr: IpAddr = "fe80::1".parse().unwrap();
+        let port = SingleLeasePort::Udp(2000);
+        let comment = gen_rule_comment(Some(addr), port).unwrap();
+        assert_eq!(comment, "fe80::1/2000/UDP/accept/letmein/GENERATED");
+        assert_eq!(parse_rule_comment(&comment), Some((Some(addr), port)));
+
+        let port = SingleLeasePort::Tcp(5800);
+        let comment = gen_rule_comment(None, port).unwrap();
+        assert_eq!(parse_rule_comment(&comment), Some((None, port)));
+
+        assert_eq!(parse_rule_comment("some other rule"), None);
+        assert_eq!(
+            parse_rule_comment("any/5800/ICMP/accept/letmein/GENERATED"),
+            None
+        );
+        assert_eq!(
+            parse_rule_comment("foo/5800/TCP/accept/letmein/GENERATED"),
+            None
+        );
+        assert_eq!(
+            parse_rule_comment("any/x/1/TCP/accept/letmein/GENERATED"),
+            None
+        );
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,243 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Execution of the `[HOOKS]` commands on lease events.
+
+use crate::firewall::{Lease, LeasePort};
+use anyhow::{self as ah, format_err as err, Context as _};
+use letmein_conf::Config;
+use std::{process::Stdio, time::Duration};
+use tokio::{process::Command, task, time};
+
+/// The `PATH` environment variable of the hook commands.
+const HOOK_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// The lease event that triggers a hook.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HookEvent {
+    /// A new lease has been opened.
+    Open,
+    /// The lease has been closed by the client.
+    Close,
+    /// The lease has been revoked by the administrator.
+    Revoke,
+    /// The lease has been removed to make room for a new lease within the lease quotas.
+    Evict,
+    /// The lease timed out.
+    Expire,
+}
+
+impl HookEvent {
+    /// Get the configured command for this event.
+    fn command(self, conf: &Config) -> Option<&str> {
+        match self {
+            Self::Open => conf.hook_on_open(),
+            Self::Close | Self::Revoke | Self::Evict => conf.hook_on_close(),
+            Self::Expire => conf.hook_on_expire(),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Close | Self::Revoke | Self::Evict => "close",
+            Self::Expire => "expire",
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            Self::Open => "opened",
+            Self::Close => "closed",
+            Self::Revoke => "revoked",
+            Self::Evict => "evicted",
+            Self::Expire => "expired",
+        }
+    }
+}
+
+/// Generate the environment variables of a hook command.
+fn hook_env(conf: &Config, event: HookEvent, lease: &Lease) -> Vec<(&'static str, String)> {
+    let port = lease.port();
+    let protocol = match port {
+        LeasePort::Tcp(_) => "tcp",
+        LeasePort::Udp(_) => "udp",
+        LeasePort::TcpUdp(_) => "tcp,udp",
+    };
+    let resource = lease
+        .resource_id(conf)
+        .map(|r| r.to_string())
+        .unwrap_or_default();
+    vec![
+        ("LETMEIN_EVENT", event.as_str().to_string()),
+        ("LETMEIN_REASON", event.reason().to_string()),
+        ("LETMEIN_ADDR", lease.addr().to_string()),
+        ("LETMEIN_PORT", port.port().to_string()),
+        ("LETMEIN_PROTOCOL", protocol.to_string()),
+        ("LETMEIN_RESOURCE", resource),
+        (
+            "LETMEIN_USER",
+            lease.user().map(|u| u.to_string()).unwrap_or_default(),
+        ),
+    ]
+}
+
+/// Run one hook command and wait for it to finish.
+async fn run_command(
+    command: &str,
+    env: Vec<(&'static str, String)>,
+    timeout: Duration,
+) -> ah::Result<()> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env_clear()
+        .env("PATH", HOOK_PATH)
+        .envs(env)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Spawn hook command")?;
+    match time::timeout(timeout, child.wait()).await {
+        Ok(status) => {
+            let status = status.context("Wait for hook command")?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(err!("Hook command failed: {status}"))
+            }
+        }
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(err!("Hook command timed out after {timeout:?}"))
+        }
+    }
+}
+
+/// Run the configured hook command for `event` of `lease` in the background.
+///
+/// This does not wait for the command to finish.
+pub fn run_hook(conf: &Config, event: HookEvent, lease: &Lease) {
+    let Some(command) = event.command(conf) else {
+        return;
+    };
+    let command = command.to_string();
+    let env = hook_env(conf, event, lease);
+    let timeout = conf.hook_timeout();
+    let debug = conf.debug();
+    task::spawn(async move {
+        if debug {
+            println!("hooks: Running on-{} hook: {command}", event.as_str());
+        }
+        if let Err(e) = run_command(&command, env, timeout).await {
+            eprintln!("hooks: on-{} hook error: {e:?}", event.as_str());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+
+    fn get<'a>(env: &'a [(&'static str, String)], name: &str) -> &'a str {
+        &env.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn test_hook_env() {
+        let conf = test_config(
+            "[RESOURCES]\n\
+             00000001 = port: 2000\n\
+             00000002 = port: 3000 / udp\n\
+             00000003 = port: 4000 / tcp,udp\n",
+        );
+        let lease = |addr: &str, port| Lease::new(&conf, addr.parse().unwrap(), port, None, None);
+
+        let env = hook_env(
+            &conf,
+            HookEvent::Open,
+            &lease("10.0.0.1", LeasePort::Tcp(2000)),
+        );
+        let names: Vec<&str> = env.iter().map(|(n, _)| *n).collect();
+        assert_eq!(
+            names,
+            [
+                "LETMEIN_EVENT",
+                "LETMEIN_REASON",
+                "LETMEIN_ADDR",
+                "LETMEIN_PORT",
+                "LETMEIN_PROTOCOL",
+                "LETMEIN_RESOURCE",
+                "LETMEIN_USER",
+            ]
+        );
+        assert_eq!(get(&env, "LETMEIN_EVENT"), "open");
+        assert_eq!(get(&env, "LETMEIN_REASON"), "opened");
+        assert_eq!(get(&env, "LETMEIN_ADDR"), "10.0.0.1");
+        assert_eq!(get(&env, "LETMEIN_PORT"), "2000");
+        assert_eq!(get(&env, "LETMEIN_PROTOCOL"), "tcp");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "00000001");
+        assert_eq!(get(&env, "LETMEIN_USER"), "");
+
+        // A lease opened by a knocking user.
+        let user_lease = Lease::new(
+            &conf,
+            "10.0.0.1".parse().unwrap(),
+            LeasePort::Tcp(2000),
+            Some(0xA5.into()),
+            Some(1.into()),
+        );
+        let env = hook_env(&conf, HookEvent::Open, &user_lease);
+        assert_eq!(get(&env, "LETMEIN_USER"), "000000A5");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "00000001");
+
+        let env = hook_env(
+            &conf,
+            HookEvent::Close,
+            &lease("2001:db8::1", LeasePort::Udp(3000)),
+        );
+        assert_eq!(get(&env, "LETMEIN_EVENT"), "close");
+        assert_eq!(get(&env, "LETMEIN_REASON"), "closed");
+        assert_eq!(get(&env, "LETMEIN_ADDR"), "2001:db8::1");
+        assert_eq!(get(&env, "LETMEIN_PROTOCOL"), "udp");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "00000002");
+
+        let env = hook_env(
+            &conf,
+            HookEvent::Expire,
+            &lease("10.0.0.2", LeasePort::TcpUdp(4000)),
+        );
+        assert_eq!(get(&env, "LETMEIN_EVENT"), "expire");
+        assert_eq!(get(&env, "LETMEIN_REASON"), "expired");
+        assert_eq!(get(&env, "LETMEIN_PROTOCOL"), "tcp,udp");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "00000003");
+
+        // A revoked lease of a port without a resource.
+        let env = hook_env(
+            &conf,
+            HookEvent::Revoke,
+            &lease("10.0.0.3", LeasePort::Tcp(5000)),
+        );
+        assert_eq!(get(&env, "LETMEIN_EVENT"), "close");
+        assert_eq!(get(&env, "LETMEIN_REASON"), "revoked");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "");
+
+        // A lease evicted by the lease quotas.
+        let env = hook_env(
+            &conf,
+            HookEvent::Evict,
+            &lease("10.0.0.4", LeasePort::Tcp(2000)),
+        );
+        assert_eq!(get(&env, "LETMEIN_EVENT"), "close");
+        assert_eq!(get(&env, "LETMEIN_REASON"), "evicted");
+        assert_eq!(get(&env, "LETMEIN_RESOURCE"), "00000001");
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
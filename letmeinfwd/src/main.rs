@@ -11,24 +11,35 @@
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 std::compile_error!("letmeind server and letmeinfwd do not support non-Linux platforms.");
 
+mod admin;
 mod firewall;
+mod hooks;
+mod metrics;
 mod seccomp;
 mod server;
 mod uid_gid;
 mod verify;
 
 use crate::{
-    firewall::{nftables::NftFirewall, FirewallMaintain},
+    admin::{AdminClient, AdminServer},
+    firewall::{
+        nftables::{render_ruleset, NftFirewall},
+        FirewallMaintain, Lease, LeasePort,
+    },
+    metrics::{new_metrics, sample_metrics},
     seccomp::install_seccomp_rules,
     server::FirewallServer,
     uid_gid::{os_get_gid, os_get_uid},
+    verify::{verify_nft_rules, VerifyFilter, VerifyProtocol},
 };
 use anyhow::{self as ah, format_err as err, Context as _};
-use clap::{Parser, Subcommand};
-use letmein_conf::{Config, ConfigVariant, Seccomp};
+use clap::{ArgAction, Parser, Subcommand};
+use letmein_conf::{Config, ConfigVariant, MetricsListen, Resource, Seccomp, SharedConfig};
+use letmein_metrics::MetricsServer;
 use std::{
     fs::{create_dir_all, metadata, set_permissions, OpenOptions},
     io::Write as _,
+    net::{IpAddr, SocketAddr},
     os::unix::fs::{chown, MetadataExt as _, PermissionsExt as _},
     path::{Path, PathBuf},
     sync::{
@@ -142,6 +153,13 @@ struct Opts {
     #[arg(long)]
     seccomp: Option<Seccomp>,
 
+    /// Check the configuration file for inconsistencies and exit.
+    ///
+    /// Exit code: 0 if no errors were found, 1 if errors were found,
+    /// 2 if the configuration could not be loaded.
+    #[arg(long)]
+    check_config: bool,
+
     /// Show version information and exit.
     #[arg(long, short = 'v')]
     version: bool,
@@ -159,24 +177,74 @@ struct Opts {
 /// Subcommands for letmeinfwd
 #[derive(Debug, Clone, Subcommand)]
 enum Commands {
-    /// Verify if a firewall rule exists for testing
+    /// List the letmein generated rules in the kernel ruleset.
+    ///
+    /// All rules in the configured nftables family/table/chain are listed.
+    /// The listing can be restricted with `--address`, `--port` and `--protocol`.
+    ///
+    /// Exit code: 0 on success, 1 if the `--should-exist` check failed, 2 on error.
     Verify {
-        /// IP address to check
+        /// Only list the rules for this source IP address.
         #[arg(long)]
-        address: String,
-        
-        /// Port to check
+        address: Option<IpAddr>,
+
+        /// Only list the rules for this port.
+        #[arg(long)]
+        port: Option<u16>,
+
+        /// Only list the rules for this protocol.
+        #[arg(long, value_enum)]
+        protocol: Option<VerifyProtocol>,
+
+        /// Check if a matching rule exists (true) or is missing (false).
+        #[arg(long, action = ArgAction::Set, requires_all = ["address", "port"])]
+        should_exist: Option<bool>,
+    },
+
+    /// Manage the leases of the running letmeinfwd daemon.
+    ///
+    /// This talks to the daemon over the root-only admin socket.
+    Leases {
+        #[command(subcommand)]
+        command: LeasesCommands,
+    },
+
+    /// Print the nftables ruleset that letmeinfwd would install.
+    ///
+    /// The ruleset contains the control port rules and the rules
+    /// for the hypothetical leases given with `--lease`.
+    /// The kernel firewall is not touched.
+    Render {
+        /// Print the nftables JSON instead of an `nft` script.
         #[arg(long)]
-        port: u16,
-        
-        /// Protocol (tcp/udp)
-        #[arg(long, default_value = "tcp")]
-        protocol: String,
-        
-        /// Check if rule should exist (true) or be missing (false)
+        json: bool,
+
+        /// Add the rules for a lease of a knock from ADDR to PORT.
+        ///
+        /// The protocol is taken from the resource of PORT.
+        /// IPv6 addresses must be enclosed in brackets: [ADDR]:PORT
+        /// This option can be given multiple times.
+        #[arg(long, value_name = "ADDR:PORT")]
+        lease: Vec<SocketAddr>,
+    },
+}
+
+/// Subcommands for `letmeinfwd leases`
+#[derive(Debug, Clone, Subcommand)]
+enum LeasesCommands {
+    /// List all active leases with their expiry and counters.
+    List,
+
+    /// Revoke the leases of an address and close the ports.
+    Revoke {
+        /// The IP address of the leases to revoke.
+        address: IpAddr,
+
+        /// Only revoke the leases for this port.
+        /// If this option is not given, all leases of the address are revoked.
         #[arg(long)]
-        should_exist: bool,
-    }
+        port: Option<u16>,
+    },
 }
 
 impl Opts {
@@ -203,6 +271,59 @@ impl Opts {
     }
 }
 
+/// Read the letmeind.conf configuration file.
+fn load_config(opts: &Opts) -> ah::Result<Config> {
+    let mut conf = Config::new(ConfigVariant::Server);
+    conf.load(&opts.get_config())
+        .context("Configuration file")?;
+    for warning in conf.warnings() {
+        eprintln!("{warning}");
+    }
+    Ok(conf)
+}
+
+/// Load the configuration file again with the includes found at startup.
+fn reload_config_file(conf: &SharedConfig) -> ah::Result<Config> {
+    let new_conf = conf.get().reload().context("Configuration file")?;
+    for warning in new_conf.warnings() {
+        eprintln!("{warning}");
+    }
+    Ok(new_conf)
+}
+
+/// Reload the configuration file and apply it to the firewall.
+/// If the new configuration is invalid, then the old configuration stays active.
+async fn reload_config(conf: &SharedConfig, fw: &Mutex<NftFirewall>) {
+    let new_conf = match reload_config_file(conf) {
+        Ok(new_conf) => Arc::new(new_conf),
+        Err(e) => {
+            eprintln!("SIGHUP: Failed to reload the configuration: {e:#}");
+            eprintln!("SIGHUP: Keeping the old configuration.");
+            return;
+        }
+    };
+
+    // Hold the firewall lock, so that no firewall operation
+    // can run with the old configuration while switching.
+    let mut fw = fw.lock().await;
+    let old_conf = conf.get();
+    if let Err(e) = fw.reload(&old_conf, &new_conf).await {
+        eprintln!("SIGHUP: Failed to apply the configuration to the firewall: {e:#}");
+        eprintln!("SIGHUP: Keeping the old configuration.");
+        return;
+    }
+    if old_conf.seccomp() != new_conf.seccomp()
+        || old_conf.metrics_letmeinfwd() != new_conf.metrics_letmeinfwd()
+    {
+        eprintln!(
+            "SIGHUP: WARNING: Changes to the 'seccomp' and \
+             '[METRICS]' options require a restart of letmeinfwd."
+        );
+    }
+    conf.replace(new_conf);
+    eprintln!("SIGHUP: Configuration reloaded.");
+}
+
 async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     // Read and parse /etc/passwd and /etc/group.
     read_etc_passwd(&opts)?;
@@ -211,13 +332,10 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     make_run_subdir(&opts)?;
 
     // Read the letmeind.conf configuration file.
-    let mut conf = Config::new(ConfigVariant::Server);
-    conf.load(&opts.get_config())
-        .context("Configuration file")?;
-    let conf = Arc::new(conf);
+    let conf = Arc::new(SharedConfig::new(load_config(&opts)?));
 
     // Initialize access to the firewall.
-    let fw = Arc::new(Mutex::new(NftFirewall::new(&conf).await?));
+    let fw = Arc::new(Mutex::new(NftFirewall::new(&conf.get()).await?));
 
     // Register unix signal handlers.
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
@@ -233,30 +351,64 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
         .await
         .context("Firewall server init")?;
 
+    // Start the lease administration unix domain socket listener.
+    let admin_srv = AdminServer::new(&opts).await.context("Admin server init")?;
+
+    // Start the metrics listener.
+    let metrics = Arc::new(new_metrics());
+    let metrics_srv = MetricsServer::bind(conf.get().metrics_letmeinfwd())
+        .await
+        .context("Metrics server init")?;
+    if let MetricsListen::Unix(path) = conf.get().metrics_letmeinfwd() {
+        if !opts.test_mode() {
+            set_owner_mode(path, 0 /* root */, 0 /* root */, 0o600)
+                .context("Set metrics socket owner and mode")?;
+        }
+    }
+
     // Create the PID-file.
     make_pidfile(&opts.rundir)?;
 
     // Install `seccomp` rules, if required.
-    let seccomp = opts.seccomp.unwrap_or(conf.seccomp());
+    let seccomp = opts.seccomp.unwrap_or(conf.get().seccomp());
     install_seccomp_rules(seccomp)?;
 
+    // Spawn task: Metrics server.
+    if let Some(metrics_srv) = metrics_srv {
+        let metrics = Arc::clone(&metrics);
+        let fw = Arc::clone(&fw);
+        task::spawn(async move {
+            let result = metrics_srv
+                .serve(|| async {
+                    sample_metrics(&metrics, &fw).await;
+                    metrics.render()
+                })
+                .await;
+            if let Err(e) = result {
+                eprintln!("Metrics server error: {e}");
+            }
+        });
+    }
+
     // Spawn task: Unix socket handler.
     task::spawn({
         let conf = Arc::clone(&conf);
         let opts = Arc::clone(&opts);
         let fw = Arc::clone(&fw);
+        let metrics = Arc::clone(&metrics);
 
         async move {
             let conn_semaphore = Semaphore::new(opts.num_connections);
             loop {
-                let conf = Arc::clone(&conf);
                 let fw = Arc::clone(&fw);
+                let metrics = Arc::clone(&metrics);
                 match srv.accept(&opts).await {
                     Ok(mut conn) => {
+                        let conf = conf.get();
                         // Socket connection handler.
                         if let Ok(_permit) = conn_semaphore.acquire().await {
                             task::spawn(async move {
-                                if let Err(e) = conn.handle_message(&conf, fw).await {
+                                if let Err(e) = conn.handle_message(&conf, fw, &metrics).await {
                                     eprintln!("Client error: {e}");
                                 }
                             });
@@ -271,6 +423,32 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
         }
     });
 
+    // Spawn task: Admin unix socket handler.
+    task::spawn({
+        let conf = Arc::clone(&conf);
+        let opts = Arc::clone(&opts);
+        let fw = Arc::clone(&fw);
+
+        async move {
+            loop {
+                let fw = Arc::clone(&fw);
+                match admin_srv.accept(&opts).await {
+                    Ok(mut conn) => {
+                        let conf = conf.get();
+                        task::spawn(async move {
+                            if let Err(e) = conn.handle_message(&conf, fw).await {
+                                eprintln!("Admin client error: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        eprintln!("Admin socket error: {e}");
+                    }
+                }
+            }
+        }
+    });
+
     // Task: Firewall.
     task::spawn({
         let conf = Arc::clone(&conf);
@@ -281,7 +459,7 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
             loop {
                 interval.tick().await;
                 let mut fw = fw.lock().await;
-                if let Err(e) = fw.maintain(&conf).await {
+                if let Err(e) = fw.maintain(&conf.get()).await {
                     let _ = exit_fw_tx.send(Err(e)).await;
                     break;
                 }
@@ -303,7 +481,7 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
                 break;
             }
             _ = sighup.recv() => {
-                eprintln!("SIGHUP: Reloading is not supported. Please restart letmeinfwd instead.");
+                reload_config(&conf, &fw).await;
             }
             code = exit_sock_rx.recv() => {
                 exitcode = code.unwrap_or_else(|| Err(err!("Unknown error code.")));
@@ -320,7 +498,7 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     // Try to remove all firewall rules.
     {
         let mut fw = fw.lock().await;
-        if let Err(e) = fw.shutdown(&conf).await {
+        if let Err(e) = fw.shutdown(&conf.get()).await {
             eprintln!("WARNING: Failed to remove firewall rules: {e}");
             if exitcode.is_ok() {
                 exitcode = Err(err!("Failed to remove firewall rules"));
@@ -331,6 +509,65 @@ async fn async_main(opts: Arc<Opts>) -> ah::Result<()> {
     exitcode
 }
 
+/// List and check the generated rules in the kernel ruleset.
+fn verify(opts: &Opts, filter: &VerifyFilter, should_exist: Option<bool>) -> ah::Result<bool> {
+    let conf = load_config(opts)?;
+
+    runtime::Builder::new_current_thread()
+        .thread_keep_alive(Duration::from_millis(0))
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .context("Tokio runtime builder")?
+        .block_on(verify_nft_rules(&conf, filter, should_exist))
+}
+
+/// Run a lease administration command against the running daemon.
+fn leases(opts: &Opts, command: &LeasesCommands) -> ah::Result<()> {
+    runtime::Builder::new_current_thread()
+        .thread_keep_alive(Duration::from_millis(0))
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .context("Tokio runtime builder")?
+        .block_on(async {
+            let mut client = AdminClient::new(&opts.rundir).await?;
+            match command {
+                LeasesCommands::List => client.list_leases().await,
+                LeasesCommands::Revoke { address, port } => client.revoke(*address, *port).await,
+            }
+        })
+}
+
+/// Print the ruleset for the control port and the hypothetical `leases`.
+fn render(opts: &Opts, json: bool, leases: &[SocketAddr]) -> ah::Result<()> {
+    let conf = load_config(opts)?;
+
+    let leases = leases
+        .iter()
+        .map(|addr| {
+            let port = addr.port();
+            if conf.is_control_port(port) {
+                return Err(err!("Lease port {port} is the control port."));
+            }
+            let res_id = conf.resource_id_by_port(port, None);
+            let res = res_id.and_then(|id| conf.resource(id));
+            let Some(Resource::Port { tcp, udp, .. }) = res else {
+                return Err(err!("No resource configured for lease port {port}."));
+            };
+            let port = match (tcp, udp) {
+                (true, true) => LeasePort::TcpUdp(port),
+                (false, true) => LeasePort::Udp(port),
+                _ => LeasePort::Tcp(port),
+            };
+            Ok(Lease::new(&conf, addr.ip(), port, None, res_id))
+        })
+        .collect::<ah::Result<Vec<_>>>()?;
+
+    print!("{}", render_ruleset(&conf, &leases, json)?);
+    Ok(())
+}
+
 fn main() -> ah::Result<()> {
     let opts = Arc::new(Opts::parse());
 
@@ -339,42 +576,36 @@ fn main() -> ah::Result<()> {
         return Ok(());
     }
     
-    // Process verify command if specified
-    if let Some(Commands::Verify { address, port, protocol, should_exist }) = &opts.command {
-        // Read the configuration file
-        let mut conf = Config::new(ConfigVariant::Server);
-        conf.load(&opts.get_config())
-            .context("Configuration file")?;
-        
-        // Call the verification function
-        let result = runtime::Builder::new_current_thread()
-            .thread_keep_alive(Duration::from_millis(0))
-            .max_blocking_threads(1)
-            .enable_all()
-            .build()
-            .context("Tokio runtime builder")?
-            .block_on(crate::verify::verify_nft_rule(
-                &conf,
-                address,
-                *port,
-                protocol,
-                *should_exist
-            ));
-            
-        match result {
-            Ok(true) => {
-                println!("Verification successful: rule {} found as expected", 
-                    if *should_exist { "was" } else { "was not" });
-                return Ok(());
-            },
-            Ok(false) => {
-                eprintln!("Verification failed: rule {} found, which was not expected", 
-                    if *should_exist { "was not" } else { "was" });
-                std::process::exit(1);
-            },
+    if opts.check_config {
+        std::process::exit(Config::check_file_print(&opts.get_config()));
+    }
+
+    if let Some(Commands::Leases { command }) = &opts.command {
+        return leases(&opts, command);
+    }
+
+    if let Some(Commands::Render { json, lease }) = &opts.command {
+        return render(&opts, *json, lease);
+    }
+
+    if let Some(Commands::Verify {
+        address,
+        port,
+        protocol,
+        should_exist,
+    }) = &opts.command
+    {
+        let filter = VerifyFilter {
+            addr: *address,
+            port: *port,
+            protocol: *protocol,
+        };
+        match verify(&opts, &filter, *should_exist) {
+            Ok(true) => return Ok(()),
+            Ok(false) => std::process::exit(1),
             Err(e) => {
-                eprintln!("Error during verification: {}", e);
-                std::process::exit(1);
+                eprintln!("Error during verification: {e:#}");
+                std::process::exit(2);
             }
         }
     }
@@ -389,4 +620,14 @@ fn main() -> ah::Result<()> {
         .block_on(async_main(opts))
 }
 
+/// Load a server configuration from `content` for the unit tests.
+#[cfg(test)]
+fn test_config(content: &str) -> Config {
+    let mut ini = letmein_conf::Ini::new();
+    ini.parse_str(content).unwrap();
+    let mut conf = Config::new(ConfigVariant::Server);
+    conf.load_ini(&ini).unwrap();
+    conf
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,60 @@
+// -*- coding: utf-8 -*-
+//
+// Copyright (C) 2024 Michael Büsch <m@bues.ch>
+//
+// Licensed under the Apache License version 2.0
+// or the MIT license, at your option.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Metrics of the letmeinfwd daemon.
+
+use crate::firewall::FirewallStats;
+use letmein_fwproto::FirewallOperation;
+use letmein_metrics::{MetricKind, Metrics};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Requests from letmeind by operation.
+pub const REQUESTS: &str = "letmeinfwd_requests_total";
+/// Failed firewall operations by operation.
+pub const FIREWALL_ERRORS: &str = "letmeinfwd_firewall_errors_total";
+/// Number of currently active leases.
+pub const LEASES: &str = "letmeinfwd_leases";
+/// Number of currently active bans.
+pub const BANS: &str = "letmeinfwd_bans";
+
+/// Create the metrics registry of letmeinfwd.
+pub fn new_metrics() -> Metrics {
+    let m = Metrics::new();
+    m.register(REQUESTS, MetricKind::Counter, "Requests from letmeind.");
+    m.register(
+        FIREWALL_ERRORS,
+        MetricKind::Counter,
+        "Failed firewall operations.",
+    );
+    m.register(LEASES, MetricKind::Gauge, "Active leases.");
+    m.register(BANS, MetricKind::Gauge, "Active bans.");
+    m
+}
+
+/// Get the metrics label of a firewall operation.
+pub fn operation_label(operation: FirewallOperation) -> &'static str {
+    match operation {
+        FirewallOperation::Open => "open",
+        FirewallOperation::Close => "close",
+        FirewallOperation::Ban => "ban",
+        _ => "other",
+    }
+}
+
+/// Update the metrics that are sampled from the firewall.
+pub async fn sample_metrics(m: &Metrics, fw: &Arc<Mutex<impl FirewallStats>>) {
+    let (nr_leases, nr_bans) = {
+        let fw = fw.lock().await;
+        (fw.nr_leases(), fw.nr_bans())
+    };
+    m.set(LEASES, &[], nr_leases as f64);
+    m.set(BANS, &[], nr_bans as f64);
+}
+
+// vim: ts=4 sw=4 expandtab
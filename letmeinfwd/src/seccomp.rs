@@ -10,7 +10,7 @@ use anyhow::{self as ah, Context as _};
 use letmein_conf::Seccomp;
 use letmein_seccomp::{seccomp_supported, Action, Allow, Filter};
 
-const ALLOW_LIST: [Allow; 29] = [
+const ALLOW_LIST: [Allow; 30] = [
     Allow::Mmap,
     Allow::Mprotect,
     Allow::GetUidGid,
@@ -40,6 +40,7 @@ const ALLOW_LIST: [Allow; 29] = [
     Allow::Clone,
     Allow::Exec,
     Allow::Wait,
+    Allow::Kill,
     Allow::GetRlimit,
     Allow::Pidfd,
 ];
@@ -7,12 +7,15 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use crate::{
-    firewall::{FirewallOpen, LeasePort},
+    firewall::{FirewallBan, FirewallOpen, LeasePort},
+    metrics::{operation_label, FIREWALL_ERRORS, REQUESTS},
     set_owner_mode, Opts, LETMEIND_GID, LETMEIND_UID,
 };
 use anyhow::{self as ah, format_err as err, Context as _};
-use letmein_conf::Config;
-use letmein_fwproto::{FirewallMessage, FirewallOperation, PortType, SOCK_FILE};
+use letmein_conf::{Config, Resource};
+use letmein_fwproto::{FirewallMessage, FirewallOperation, MsgAuth, PortType, SOCK_FILE};
+use letmein_metrics::Metrics;
+use letmein_proto::{ResourceId, UserId};
 use letmein_systemd::{systemd_notify_ready, SystemdSocket};
 use std::{
     fs::{metadata, remove_file, OpenOptions},
@@ -21,6 +24,7 @@ use std::{
     os::unix::fs::MetadataExt as _,
     path::{Path, PathBuf},
     sync::{atomic::Ordering::Relaxed, Arc},
+    time::{Duration, SystemTime},
 };
 use tokio::{
     net::{unix::pid_t, UnixListener, UnixStream},
@@ -42,7 +46,7 @@ fn get_letmeind_pid(rundir: &Path) -> ah::Result<pid_t> {
 }
 
 /// Some basic address sanity checks.
-fn addr_check(addr: &IpAddr) -> bool {
+pub fn addr_check(addr: &IpAddr) -> bool {
     match addr {
         IpAddr::V4(addr) => {
             let addr = u32::from_be_bytes(addr.octets());
@@ -55,32 +59,122 @@ fn addr_check(addr: &IpAddr) -> bool {
     }
 }
 
+/// Remove the Unix socket at `sock_path`, if it exists.
+pub fn remove_socket_file(sock_path: &Path) -> ah::Result<()> {
+    if let Ok(meta) = metadata(sock_path) {
+        const S_IFMT: u32 = libc::S_IFMT as _;
+        const S_IFSOCK: u32 = libc::S_IFSOCK as _;
+        if (meta.mode() & S_IFMT) == S_IFSOCK {
+            remove_file(sock_path).context("Remove existing socket")?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the user and the resource of an open or close message
+/// against the configuration.
+///
+/// Returns the user and the resource from the message.
+fn check_user_resource(
+    conf: &Config,
+    msg: &FirewallMessage,
+    addr: IpAddr,
+    port_type: PortType,
+    port: u16,
+) -> ah::Result<(UserId, ResourceId)> {
+    let (Some(user), Some(resource_id)) = (msg.user(), msg.resource()) else {
+        return Err(err!("No user or resource."));
+    };
+
+    // Check if the resource is actually configured and matches the port.
+    let Some(resource) = conf.resource(resource_id) else {
+        return Err(err!(
+            "The resource {resource_id} is not configured in letmeind.conf."
+        ));
+    };
+    let Resource::Port {
+        port: res_port,
+        tcp,
+        udp,
+        ..
+    } = resource;
+    let res_port_type = match (tcp, udp) {
+        (true, true) => PortType::TcpUdp,
+        (false, true) => PortType::Udp,
+        _ => PortType::Tcp,
+    };
+    if *res_port != port || res_port_type != port_type {
+        return Err(err!(
+            "The port {port} does not belong to the resource {resource_id}."
+        ));
+    }
+
+    // Check if the user is known and allowed to use the resource.
+    if conf.valid_keys(user, SystemTime::now()).is_empty() {
+        return Err(err!("The user {user} has no valid key in letmeind.conf."));
+    }
+    if !resource.contains_user(user) {
+        return Err(err!(
+            "The user {user} is not allowed to use the resource {resource_id}."
+        ));
+    }
+
+    // Check the source address allow-lists again.
+    // letmeind already rejected knocks from addresses that are not allowed.
+    if !resource.allows_addr(addr) || !conf.user_allows_addr(user, addr) {
+        return Err(err!(
+            "The address {addr} is not allowed to use the resource {resource_id}."
+        ));
+    }
+
+    Ok((user, resource_id))
+}
+
 pub struct FirewallConnection {
     stream: UnixStream,
+    auth: Option<MsgAuth>,
 }
 
 impl FirewallConnection {
     fn new(stream: UnixStream) -> ah::Result<Self> {
-        Ok(Self { stream })
+        Ok(Self { stream, auth: None })
     }
 
     async fn recv_msg(&mut self) -> ah::Result<Option<FirewallMessage>> {
-        FirewallMessage::recv(&mut self.stream).await
+        match &mut self.auth {
+            Some(auth) => FirewallMessage::recv_auth(&mut self.stream, auth).await,
+            None => FirewallMessage::recv(&mut self.stream).await,
+        }
     }
 
     async fn send_msg(&mut self, msg: &FirewallMessage) -> ah::Result<()> {
-        msg.send(&mut self.stream).await
+        match &mut self.auth {
+            Some(auth) => msg.send_auth(&mut self.stream, auth).await,
+            None => msg.send(&mut self.stream).await,
+        }
     }
 
     /// Handle the firewall daemon unix socket communication.
     pub async fn handle_message(
         &mut self,
         conf: &Config,
-        fw: Arc<Mutex<impl FirewallOpen>>,
+        fw: Arc<Mutex<impl FirewallOpen + FirewallBan>>,
+        metrics: &Metrics,
     ) -> ah::Result<()> {
+        // Start the message authentication, if enabled.
+        if let Some(key) = conf.firewall_key() {
+            let mut auth = MsgAuth::new(key);
+            auth.send_challenge(&mut self.stream)
+                .await
+                .context("Send authentication challenge")?;
+            self.auth = Some(auth);
+        }
+
         let Some(msg) = self.recv_msg().await? else {
             return Err(err!("Disconnected."));
         };
+        let op_label = operation_label(msg.operation());
+        metrics.inc(REQUESTS, &[("operation", op_label)]);
         match msg.operation() {
             FirewallOperation::Open => {
                 // Get the address from the socket message.
@@ -101,22 +195,26 @@ impl FirewallConnection {
                     return Err(err!("No port."));
                 };
 
-                // Check if the port is actually configured.
-                if conf.resource_id_by_port(port, None).is_none() {
-                    // Whoops, letmeind should never send us a request for an
-                    // unconfigured port. Did some other process write to the unix socket?
-                    self.send_msg(&FirewallMessage::new_nack()).await?;
-                    return Err(err!("The port {port} is not configured in letmeind.conf."));
-                }
-
                 // Don't allow the user to manage the control port.
-                if port == conf.port().port {
+                if conf.is_control_port(port) {
                     // Whoops, letmeind should never send us a request for the
                     // control port. Did some other process write to the unix socket?
                     self.send_msg(&FirewallMessage::new_nack()).await?;
                     return Err(err!("The knocked port {port} is the letmein control port."));
                 }
 
+                // Check if the user and the resource are valid.
+                // letmeind should never send us a request that doesn't match
+                // its configuration. Did some other process write to the unix socket?
+                let ident = check_user_resource(conf, &msg, addr, port_type, port);
+                let (user, resource) = match ident {
+                    Ok(ident) => ident,
+                    Err(e) => {
+                        self.send_msg(&FirewallMessage::new_nack()).await?;
+                        return Err(e);
+                    }
+                };
+
                 // Convert from protocol port type to lease port type.
                 let lease_port = match port_type {
                     PortType::Tcp => LeasePort::Tcp(port),
@@ -127,12 +225,23 @@ impl FirewallConnection {
                 // Open the firewall.
                 let ok = {
                     let mut fw = fw.lock().await;
-                    fw.open_port(conf, addr, lease_port).await.is_ok()
+                    let max_timeout = msg.timeout().map(|t| Duration::from_secs(t.into()));
+                    fw.open_port(
+                        conf,
+                        addr,
+                        lease_port,
+                        Some(user),
+                        Some(resource),
+                        max_timeout,
+                    )
+                    .await
+                    .is_ok()
                 };
 
                 if ok {
                     self.send_msg(&FirewallMessage::new_ack()).await?;
                 } else {
+                    metrics.inc(FIREWALL_ERRORS, &[("operation", op_label)]);
                     self.send_msg(&FirewallMessage::new_nack()).await?;
                 }
             }
@@ -155,22 +264,20 @@ impl FirewallConnection {
                     return Err(err!("No port."));
                 };
 
-                // Check if the port is actually configured.
-                if conf.resource_id_by_port(port, None).is_none() {
-                    // Whoops, letmeind should never send us a request for an
-                    // unconfigured port. Did some other process write to the unix socket?
-                    self.send_msg(&FirewallMessage::new_nack()).await?;
-                    return Err(err!("The port {port} is not configured in letmeind.conf."));
-                }
-
                 // Don't allow the user to manage the control port.
-                if port == conf.port().port {
+                if conf.is_control_port(port) {
                     // Whoops, letmeind should never send us a request for the
                     // control port. Did some other process write to the unix socket?
                     self.send_msg(&FirewallMessage::new_nack()).await?;
                     return Err(err!("The port {port} is the letmein control port."));
                 }
 
+                // Check if the user and the resource are valid.
+                if let Err(e) = check_user_resource(conf, &msg, addr, port_type, port) {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(e);
+                }
+
                 // Convert from protocol port type to lease port type.
                 let lease_port = match port_type {
                     PortType::Tcp => LeasePort::Tcp(port),
@@ -187,10 +294,50 @@ impl FirewallConnection {
                 if ok {
                     self.send_msg(&FirewallMessage::new_ack()).await?;
                 } else {
+                    metrics.inc(FIREWALL_ERRORS, &[("operation", op_label)]);
                     self.send_msg(&FirewallMessage::new_nack()).await?;
                 }
             }
-            FirewallOperation::Ack | FirewallOperation::Nack => {
+            FirewallOperation::Ban => {
+                // Get the address from the socket message.
+                let Some(addr) = msg.addr() else {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(err!("No addr."));
+                };
+
+                // Check if addr is valid.
+                if !addr_check(&addr) {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(err!("Invalid addr."));
+                }
+
+                // Check if firewall bans are enabled.
+                if conf.ban_threshold() == 0 || !conf.ban_firewall() {
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                    return Err(err!("Firewall bans are disabled in letmeind.conf."));
+                }
+
+                // Add the ban to the firewall.
+                let ok = {
+                    let mut fw = fw.lock().await;
+                    fw.ban_addr(conf, addr).await.is_ok()
+                };
+
+                if ok {
+                    self.send_msg(&FirewallMessage::new_ack()).await?;
+                } else {
+                    metrics.inc(FIREWALL_ERRORS, &[("operation", op_label)]);
+                    self.send_msg(&FirewallMessage::new_nack()).await?;
+                }
+            }
+            FirewallOperation::Ack
+            | FirewallOperation::Nack
+            | FirewallOperation::ListLeases
+            | FirewallOperation::Lease
+            | FirewallOperation::Revoke
+            | FirewallOperation::RevokeAddr
+            | FirewallOperation::Challenge => {
+                // The lease administration is only available on the admin socket.
                 return Err(err!("Received invalid message"));
             }
         }
@@ -233,13 +380,7 @@ impl FirewallServer {
         // Remove the socket, if it exists.
         let runsubdir = opts.rundir.join("letmeinfwd");
         let sock_path = runsubdir.join(SOCK_FILE);
-        if let Ok(meta) = metadata(&sock_path) {
-            const S_IFMT: u32 = libc::S_IFMT as _;
-            const S_IFSOCK: u32 = libc::S_IFSOCK as _;
-            if (meta.mode() & S_IFMT) == S_IFSOCK {
-                remove_file(&sock_path).context("Remove existing socket")?;
-            }
-        }
+        remove_socket_file(&sock_path)?;
 
         // Bind to the Unix socket.
         let listener = UnixListener::bind(&sock_path).context("Bind socket")?;
@@ -6,81 +6,93 @@
 // or the MIT license, at your option.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-//! Rule verification functionality for testing
+//! Inspection of the letmein generated rules in the kernel ruleset.
 
-use anyhow::{self as ah};
-use letmein_conf::Config;
-use nftables::{
-    helper::get_current_ruleset_with_args_async,
-    schema::{NfListObject, NfObject},
+use crate::firewall::{
+    nftables::{list_generated_rules, GeneratedRule},
+    SingleLeasePort,
 };
+use anyhow as ah;
+use clap::ValueEnum;
+use letmein_conf::Config;
 use std::net::IpAddr;
 
-/// Verify if a rule exists or is missing in the nftables ruleset
-/// 
-/// This function is used in tests to verify if a rule exists or not.
-/// It returns true if the verification passed, false otherwise.
-#[allow(dead_code)]
-pub async fn verify_nft_rule(
-    _config: &Config,
-    addr_str: &str,
-    port: u16,
-    proto: &str,
-    should_exist: bool,
+/// Protocol filter for the rule inspection.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerifyProtocol {
+    /// TCP rules.
+    Tcp,
+    /// UDP rules.
+    Udp,
+}
+
+/// Filter for the rules to be inspected.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VerifyFilter {
+    /// Only rules for this source address.
+    pub addr: Option<IpAddr>,
+    /// Only rules for this port.
+    pub port: Option<u16>,
+    /// Only rules for this protocol.
+    pub protocol: Option<VerifyProtocol>,
+}
+
+impl VerifyFilter {
+    /// Check if the rule matches this filter.
+    fn matches(&self, rule: &GeneratedRule) -> bool {
+        let (port, protocol) = match rule.port {
+            SingleLeasePort::Tcp(p) => (p, VerifyProtocol::Tcp),
+            SingleLeasePort::Udp(p) => (p, VerifyProtocol::Udp),
+        };
+        (self.addr.is_none() || self.addr == rule.addr)
+            && (self.port.is_none() || self.port == Some(port))
+            && (self.protocol.is_none() || self.protocol == Some(protocol))
+    }
+}
+
+/// List all letmein generated rules matching the `filter`
+/// and optionally check whether a matching rule is present (`should_exist = Some(true)`)
+/// or absent (`should_exist = Some(false)`).
+///
+/// Returns `false`, if the presence check failed.
+pub async fn verify_nft_rules(
+    conf: &Config,
+    filter: &VerifyFilter,
+    should_exist: Option<bool>,
 ) -> ah::Result<bool> {
-    // Parse the IP address
-    let addr: IpAddr = match addr_str.parse() {
-        Ok(addr) => addr,
-        Err(e) => {
-            eprintln!("Error parsing IP address {}: {}", addr_str, e);
-            return Ok(false);
-        }
-    };
+    let rules: Vec<_> = list_generated_rules(conf)
+        .await?
+        .into_iter()
+        .filter(|rule| filter.matches(rule))
+        .collect();
 
-    // Generate the comment string that identifies the rule
-    // Format: "letmein_{addr}-{port}/{proto}"
-    let comment = format!("letmein_{}-{}/{}", addr, port, proto);
-    
-    // Get current ruleset using the nftables crate
-    let ruleset = get_current_ruleset_with_args_async(None::<&str>, None::<&str>).await?;
-    
-    // Print the ruleset for debugging
-    println!("Current nftables ruleset:");
-    for obj in ruleset.objects.to_vec().iter() {
-        println!("{:?}", obj);
+    for rule in &rules {
+        let addr = match rule.addr {
+            Some(addr) => addr.to_string(),
+            None => "any".to_string(),
+        };
+        let handle = match rule.handle {
+            Some(handle) => handle.to_string(),
+            None => "-".to_string(),
+        };
+        let counters = match rule.counters {
+            Some(counters) => counters.to_string(),
+            None => "no counters".to_string(),
+        };
+        println!("{addr} {} handle={handle} {counters}", rule.port);
     }
-    
-    // Check if rule exists by looking for our comment identifier
-    let rule_exists = ruleset.objects.to_vec().iter().any(|obj| {
-        match obj {
-            NfObject::ListObject(NfListObject::Rule(rule)) => {
-                // Use debug formatting since Rule doesn't implement Display
-                format!("{:?}", rule).contains(&comment)
-            },
-            _ => false
+
+    Ok(match should_exist {
+        Some(true) if rules.is_empty() => {
+            eprintln!("ERROR: No rule found, but a rule was expected.");
+            false
         }
-    });
-    
-    let result = match should_exist {
-        true => {
-            if rule_exists {
-                println!("✓ OK: Rule found for {} port {}/{}", addr, port, proto);
-                true
-            } else {
-                eprintln!("✗ ERROR: Rule not found for {} port {}/{}", addr, port, proto);
-                false
-            }
-        },
-        false => {
-            if !rule_exists {
-                println!("✓ OK: Rule successfully removed for {} port {}/{}", addr, port, proto);
-                true
-            } else {
-                eprintln!("✗ ERROR: Rule still present for {} port {}/{}", addr, port, proto);
-                false
-            }
+        Some(false) if !rules.is_empty() => {
+            eprintln!("ERROR: Rule found, but no rule was expected.");
+            false
         }
-    };
-    
-    Ok(result)
+        _ => true,
+    })
 }
+
+// vim: ts=4 sw=4 expandtab